@@ -0,0 +1,160 @@
+//! Statistics across many traces of the same query. A single trace can be
+//! thrown off by a cold cache or a busy database; aggregating the traces of
+//! several runs shows which fields are slow most of the time
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::anyhow;
+use regex::Regex;
+
+use super::{field_paths, load_trace, percentile, Trace, TraceSort};
+
+/// The traces that `pattern` stands for: all JSON files in it if it is a
+/// directory, the files whose names match it if its last part has `*` or
+/// `?` wildcards, and the file itself otherwise
+fn trace_files(pattern: &str) -> anyhow::Result<Vec<PathBuf>> {
+    let path = Path::new(pattern);
+    let (dir, name) = if path.is_dir() {
+        (path, "*.json")
+    } else {
+        match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) if name.contains(['*', '?']) => (
+                path.parent()
+                    .filter(|dir| !dir.as_os_str().is_empty())
+                    .unwrap_or(Path::new(".")),
+                name,
+            ),
+            _ => return Ok(vec![path.to_path_buf()]),
+        }
+    };
+    let regex = regex::escape(name).replace(r"\*", ".*").replace(r"\?", ".");
+    let regex = Regex::new(&format!("^{regex}$"))?;
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir).map_err(|e| anyhow!("Failed to read {}: {e}", dir.display()))? {
+        let path = entry?.path();
+        if path.is_file()
+            && path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| regex.is_match(name))
+        {
+            files.push(path);
+        }
+    }
+    if files.is_empty() {
+        return Err(anyhow!("There are no traces in {pattern}"));
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// The times and entity counts of a field across the traces that have it
+#[derive(Default)]
+pub struct FieldStats {
+    /// The time that the field took by itself in each trace, in
+    /// microseconds
+    pub times: Vec<u64>,
+    pub entities: Vec<u64>,
+}
+
+impl FieldStats {
+    fn add(&mut self, trace: &Trace) {
+        self.times.push(trace.elapsed().as_micros() as u64);
+        // The entities of the request are those of all its fields
+        let count = match trace {
+            Trace::Root { .. } => Some(trace.total_entities()),
+            Trace::Query { .. } => trace.entity_count(),
+        };
+        if let Some(count) = count {
+            self.entities.push(count as u64);
+        }
+    }
+
+    /// The mean and 95th percentile of `values`, if there are any
+    fn mean_p95(values: &[u64]) -> Option<(f64, u64)> {
+        let mut values = values.to_vec();
+        values.sort_unstable();
+        let p95 = percentile(&values, 95)?;
+        let mean = values.iter().sum::<u64>() as f64 / values.len() as f64;
+        Some((mean, p95))
+    }
+
+    /// The mean and 95th percentile of the time that the field took by
+    /// itself, in microseconds
+    pub fn time(&self) -> (f64, u64) {
+        Self::mean_p95(&self.times).unwrap_or_default()
+    }
+
+    /// The mean and 95th percentile of the entities that the field
+    /// returned, if the traces say
+    pub fn entities(&self) -> Option<(f64, u64)> {
+        Self::mean_p95(&self.entities)
+    }
+}
+
+/// The traces of queries with the same fingerprint
+pub struct Group {
+    pub fingerprint: String,
+    pub query: String,
+    pub traces: Vec<Trace>,
+    /// The stats of the whole requests
+    pub root: FieldStats,
+    /// The stats of each field by its path, in the order of the first
+    /// trace that has the field
+    pub fields: Vec<(String, FieldStats)>,
+}
+
+/// Traces grouped by the fingerprint of their query
+pub struct Aggregate {
+    /// The number of traces
+    pub files: usize,
+    /// The groups, the one with the most traces first
+    pub groups: Vec<Group>,
+}
+
+/// Load the traces that `patterns` stand for, group them by the fingerprint
+/// of their query, and collect the time and the entities of each field
+/// across the traces in each group. The fields of each trace are visited in
+/// the order that `sort` gives
+pub fn aggregate(patterns: &[String], sort: TraceSort) -> anyhow::Result<Aggregate> {
+    let mut groups: Vec<Group> = Vec::new();
+    let mut queries: HashMap<String, usize> = HashMap::new();
+    let mut files = 0;
+    for pattern in patterns {
+        for file in trace_files(pattern)? {
+            let trace = load_trace(&file.display().to_string())?;
+            let query = trace.request().map(|(query, _)| query).unwrap_or_default();
+            let fingerprint = trace.fingerprint().unwrap_or_default();
+            let index = *queries.entry(fingerprint.clone()).or_insert_with(|| {
+                groups.push(Group {
+                    fingerprint,
+                    query,
+                    traces: Vec::new(),
+                    root: FieldStats::default(),
+                    fields: Vec::new(),
+                });
+                groups.len() - 1
+            });
+            groups[index].traces.push(trace);
+            files += 1;
+        }
+    }
+    groups.sort_by_key(|group| std::cmp::Reverse(group.traces.len()));
+
+    for group in &mut groups {
+        let mut indices: HashMap<String, usize> = HashMap::new();
+        for trace in &group.traces {
+            group.root.add(trace);
+            for (path, field) in field_paths(trace, sort) {
+                let index = *indices.entry(path.clone()).or_insert_with(|| {
+                    group.fields.push((path, FieldStats::default()));
+                    group.fields.len() - 1
+                });
+                group.fields[index].1.add(field);
+            }
+        }
+    }
+    Ok(Aggregate { files, groups })
+}
@@ -0,0 +1,158 @@
+//! Benchmarks that replay a query over and over, possibly several runs at
+//! a time, and measure how long each run took
+
+use std::time::{Duration, Instant};
+
+use futures_util::future::join_all;
+use serde_json::{self as json, json};
+
+use super::{percentile, Config, LogEntry, Retry, ServerVersion};
+
+/// How often to replay the query of a benchmark
+#[derive(Debug, Clone)]
+pub struct ScheduleOpts {
+    /// Run the query this many times before measuring
+    pub warmup: usize,
+    /// Measure this many runs of the query, by default 10, or as many as
+    /// fit into `duration`
+    pub iterations: Option<usize>,
+    /// Run the query over and over for this long instead of a fixed number
+    /// of times
+    pub duration: Option<Duration>,
+    /// Keep this many runs of the query going at the same time
+    pub concurrency: usize,
+}
+
+impl Default for ScheduleOpts {
+    fn default() -> Self {
+        ScheduleOpts {
+            warmup: 1,
+            iterations: None,
+            duration: None,
+            concurrency: 1,
+        }
+    }
+}
+
+/// What a benchmark of one query measured
+#[derive(Debug)]
+pub struct BenchResult {
+    pub deployment: String,
+    pub query_id: String,
+    pub concurrency: usize,
+    /// How long each run that succeeded took in microseconds, from the
+    /// fastest to the slowest
+    pub times: Vec<u64>,
+    /// The number of runs that failed or returned errors
+    pub errors: usize,
+    /// How long the measured runs took altogether
+    pub elapsed: Duration,
+    /// The build of graph-node that answered the queries, if it is known
+    pub version: Option<ServerVersion>,
+}
+
+impl BenchResult {
+    /// The minimum, median, 95th and 99th percentile, and maximum time of
+    /// the runs that succeeded in milliseconds, or nothing if none did
+    pub fn stats(&self) -> Vec<(&'static str, f64)> {
+        let times = &self.times;
+        [
+            ("min", times.first().copied()),
+            ("median", percentile(times, 50)),
+            ("p95", percentile(times, 95)),
+            ("p99", percentile(times, 99)),
+            ("max", times.last().copied()),
+        ]
+        .into_iter()
+        .filter_map(|(name, micros)| Some((name, micros? as f64 / 1000.0)))
+        .collect()
+    }
+
+    /// The result as the JSON object that `--json` writes
+    pub fn to_json(&self) -> json::Value {
+        let mut result = json!({
+            "deployment": self.deployment,
+            "query_id": self.query_id,
+            "runs": self.times.len(),
+            "errors": self.errors,
+            "concurrency": self.concurrency,
+            "elapsed_ms": self.elapsed.as_millis() as u64,
+        });
+        ServerVersion::record(self.version.as_ref(), &mut result);
+        for (name, time) in self.stats() {
+            result[format!("{name}_ms")] = json!(time);
+        }
+        result
+    }
+}
+
+/// Replay `log_entry` against the graph-node of `config` as `schedule`
+/// says and measure each run. Runs are not retried so that failures show
+/// up as errors instead of slow runs
+pub async fn bench(
+    config: &Config,
+    schedule: &ScheduleOpts,
+    deployment: &str,
+    log_entry: &LogEntry,
+    out: &mut dyn std::io::Write,
+) -> anyhow::Result<BenchResult> {
+    let retry = Retry {
+        attempts: 1,
+        timeout: config.retry.timeout,
+        ..Retry::default()
+    };
+    let graph_node = config.graph_node();
+    let client = graph_node.http.client()?;
+    writeln!(out, "Warming up with {} runs", schedule.warmup)?;
+    for _ in 0..schedule.warmup {
+        graph_node
+            .query_with(&client, deployment, log_entry, &retry, out)
+            .await?;
+    }
+
+    let iterations = match (schedule.iterations, schedule.duration) {
+        (Some(iterations), _) => iterations,
+        (None, Some(_)) => usize::MAX,
+        (None, None) => 10,
+    };
+    let start = Instant::now();
+    let deadline = schedule.duration.map(|duration| start + duration);
+    // The number of runs that have been started by any of the workers
+    let started = std::cell::Cell::new(0);
+    let workers = (0..schedule.concurrency.max(1)).map(|_| async {
+        let mut times = Vec::new();
+        let mut errors = 0;
+        let mut log = Vec::new();
+        while started.get() < iterations && deadline.is_none_or(|end| Instant::now() < end) {
+            started.set(started.get() + 1);
+            let run = Instant::now();
+            match graph_node
+                .query_with(&client, deployment, log_entry, &retry, &mut log)
+                .await
+            {
+                Ok((resp, _)) if resp.get("errors").is_none() => times.push(run.elapsed()),
+                _ => errors += 1,
+            }
+        }
+        (times, errors, log)
+    });
+    let mut times = Vec::new();
+    let mut errors = 0;
+    for (worker_times, worker_errors, log) in join_all(workers).await {
+        times.extend(worker_times.iter().map(|time| time.as_micros() as u64));
+        errors += worker_errors;
+        out.write_all(&log)?;
+    }
+    let elapsed = start.elapsed();
+    times.sort_unstable();
+
+    Ok(BenchResult {
+        deployment: deployment.to_string(),
+        query_id: log_entry.query_id.clone(),
+        concurrency: schedule.concurrency,
+        times,
+        errors,
+        elapsed,
+        version: graph_node.version.clone(),
+    })
+}
@@ -0,0 +1,331 @@
+//! The brief output of traces: a tree of the fields of the query with the
+//! time that each of them took by itself and with the fields below it, how
+//! many entities it returned, and the totals for the whole request. Fields
+//! that are slow for the few entities they return are pointed out
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use regex::Regex;
+
+use super::{child_path, fit, or_dash, percent_of, sorted, DisplayOpts, Trace};
+
+/// Fields that take at least this percentage of the request and return fewer
+/// than `SLOW_THROUGHPUT` entities per millisecond are pointed out since
+/// their SQL is likely bad, rather than that they return a lot of data
+const SLOW_FIELD_PERCENT: f64 = 10.0;
+const SLOW_THROUGHPUT: f64 = 1.0;
+
+/// The lines that connect a field to its parent when it has siblings after
+/// it and when it is the last one, and what goes below a field's line in
+/// the same two cases
+const UNICODE_TREE: [&str; 4] = ["├── ", "└── ", "│   ", "    "];
+const ASCII_TREE: [&str; 4] = ["|-- ", "`-- ", "|   ", "    "];
+
+/// The width of the columns after the tree of fields in brief traces, not
+/// counting the optional waits and remarks
+const BRIEF_COLUMNS_WIDTH: usize = 59;
+
+/// The brief output for `trace`, which is called `name` and indented by
+/// `indent` spaces. For the root of a trace, this ends with the totals of
+/// the request
+pub fn brief_trace(name: &str, trace: &Trace, indent: usize, display: &DisplayOpts) -> String {
+    use Trace::*;
+
+    let mut out = String::new();
+    let prefix = format!("{space:indent$}", space = " ", indent = indent);
+    match trace {
+        Root {
+            elapsed, children, ..
+        } => {
+            let qt = trace.query_time();
+            let pt = *elapsed - qt;
+
+            if let Some(warning) = trace.cache_warning() {
+                out.push_str(&format!(
+                    "Note: {warning}, so the times are not those of running it\n\n"
+                ));
+            }
+            let arguments = if display.args {
+                trace.arguments()
+            } else {
+                HashMap::new()
+            };
+            let width = name_width(trace, prefix.len() + name.len(), &arguments, display.width);
+            out.push_str(&format!("{:width$} {:>9} {:>9}\n", "", "self", "total"));
+            out.push_str(&format!(
+                "{prefix}{name} {blank:9} {elapsed:>9}\n",
+                name = fit(name, width - prefix.len()),
+                blank = "",
+                elapsed = display.time(*elapsed),
+            ));
+            let mut tree = BriefTree {
+                display,
+                cutoff: display.top_nodes.map(|n| top_nodes_cutoff(trace, n)),
+                total: *elapsed,
+                width,
+                arguments,
+                out: String::new(),
+            };
+            tree.add_fields(children, "", &prefix, 1);
+            out.push_str(&tree.out);
+            out.push_str(&format!("\nquery:      {:>9}\n", display.time(qt)));
+            out.push_str(&format!("other:      {:>9}\n", display.time(pt)));
+            out.push_str(&format!("total:      {:>9}\n", display.time(*elapsed)));
+            if let Root {
+                cache_status: Some(status),
+                ..
+            } = trace
+            {
+                out.push_str(&format!("cache:      {status:>9}\n"));
+            }
+            if display.waits {
+                let (conn_wait, permit_wait) = trace.total_waits();
+                out.push_str(&format!("conn wait:  {:>9}\n", display.time(conn_wait)));
+                out.push_str(&format!("permit wait:{:>9}\n", display.time(permit_wait)));
+            }
+        }
+        Query { elapsed, .. } => {
+            let mut tree = BriefTree {
+                display,
+                cutoff: None,
+                total: *elapsed,
+                width: name_width(
+                    trace,
+                    prefix.len() + name.len(),
+                    &HashMap::new(),
+                    display.width,
+                ),
+                arguments: HashMap::new(),
+                out: String::new(),
+            };
+            tree.add_field(name, name, trace, &prefix, &prefix, 1);
+            out.push_str(&tree.out);
+        }
+    }
+    out
+}
+
+/// The width of the column with the tree of fields in brief traces. It is
+/// wide enough for the deepest and longest fields of `trace` with their
+/// `arguments`, and at least as wide as `root`, but with `columns` not wider
+/// than fits next to the other columns. Names that do not fit are cut short
+fn name_width(
+    trace: &Trace,
+    root: usize,
+    arguments: &HashMap<String, String>,
+    columns: Option<usize>,
+) -> usize {
+    fn widest(
+        trace: &Trace,
+        path: &str,
+        lead: usize,
+        arguments: &HashMap<String, String>,
+    ) -> usize {
+        trace
+            .children()
+            .iter()
+            .map(|(name, child)| {
+                let path = child_path(path, name);
+                let label = name.chars().count()
+                    + arguments
+                        .get(&path)
+                        .map_or(0, |arguments| arguments.chars().count() + 2);
+                (lead + label).max(widest(child, &path, lead + 4, arguments))
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
+    // Leave room for lines like `(12 more fields)`
+    let needed = (widest(trace, "", root + 4, arguments) + 4).max(50);
+    match columns {
+        Some(columns) => needed
+            .min(columns.saturating_sub(BRIEF_COLUMNS_WIDTH))
+            .max(20),
+        None => needed,
+    }
+}
+
+/// How `brief_trace` lays out the fields of a trace
+struct BriefTree<'a> {
+    display: &'a DisplayOpts,
+    /// Only fields that took at least this long themselves, and the fields
+    /// that lead to them, are shown
+    cutoff: Option<Duration>,
+    /// The time of the whole request
+    total: Duration,
+    /// The width of the column with the tree of fields
+    width: usize,
+    /// The arguments of the fields by their path, if they are shown
+    arguments: HashMap<String, String>,
+    /// The lines for the fields so far
+    out: String,
+}
+
+impl BriefTree<'_> {
+    /// Add the line for a field at `path`, starting with `lead`, and then
+    /// the fields below it, whose lines start with `prefix`
+    fn add_field(
+        &mut self,
+        name: &str,
+        path: &str,
+        trace: &Trace,
+        lead: &str,
+        prefix: &str,
+        depth: usize,
+    ) {
+        let display = self.display;
+        let elapsed = trace.elapsed();
+        let entity_count = trace.entity_count();
+        // Times are only recorded in whole milliseconds
+        let throughput = entity_count.map(|count| count as f64 / elapsed.as_millis().max(1) as f64);
+        let color = display.color(elapsed);
+        if let Some(color) = color {
+            self.out.push_str(color);
+        }
+        let name = match self.arguments.get(path) {
+            Some(arguments) => format!("{name}({arguments})"),
+            None => name.to_string(),
+        };
+        self.out.push_str(&format!(
+            "{lead}{name} {elapsed:>9} {cumulative:>9} {percent:5.1}% [{count:>7} entities, {throughput:>11}]",
+            name = fit(&name, self.width.saturating_sub(lead.chars().count())),
+            elapsed = display.time(elapsed),
+            cumulative = display.time(trace.query_time()),
+            percent = percent_of(elapsed, self.total),
+            count = or_dash(entity_count),
+            throughput = or_dash(throughput.map(|throughput| format!("{throughput:.2}/ms"))),
+        ));
+        if display.waits {
+            let (conn_wait, permit_wait) = trace.waits();
+            self.out.push_str(&format!(
+                " [conn {:>7}, permit {:>7}]",
+                or_dash(conn_wait.map(|wait| display.time(wait))),
+                or_dash(permit_wait.map(|wait| display.time(wait)))
+            ));
+        }
+        if percent_of(elapsed, self.total) >= SLOW_FIELD_PERCENT
+            && throughput.is_some_and(|throughput| throughput < SLOW_THROUGHPUT)
+        {
+            self.out.push_str(" <- few entities for its time");
+        }
+        if color.is_some() {
+            self.out.push_str("\x1b[0m");
+        }
+        self.out.push('\n');
+        self.add_fields(trace.children(), path, prefix, depth + 1)
+    }
+
+    /// Add `children`, which are `depth` levels deep, in the order given
+    /// by `display.sort`. With a `cutoff`, only the fields that took at
+    /// least that long themselves, and the fields that lead to them, are
+    /// shown. Fields that took less than `display.min_node_ms`, counting
+    /// the fields below them, or that are nested deeper than
+    /// `display.max_depth` are left out, too, as are those whose path does
+    /// not match `display.grep` and that do not lead to one that does. The
+    /// fields that are left out are summed up in one line
+    fn add_fields(&mut self, children: &[(String, Trace)], path: &str, prefix: &str, depth: usize) {
+        let display = self.display;
+        let mut shown = Vec::new();
+        let mut hidden = 0;
+        let mut hidden_time = Duration::ZERO;
+        let mut hidden_entities = 0;
+        let too_deep = display.max_depth.is_some_and(|max| depth > max);
+        for (name, trace) in sorted(children, display.sort) {
+            let path = child_path(path, name);
+            let too_fast = display
+                .min_node_ms
+                .is_some_and(|ms| trace.query_time() < Duration::from_millis(ms));
+            if too_deep
+                || too_fast
+                || self
+                    .cutoff
+                    .is_some_and(|cutoff| !has_slow_node(trace, cutoff))
+                || display
+                    .grep
+                    .as_ref()
+                    .is_some_and(|grep| !has_matching_node(&path, trace, grep))
+            {
+                hidden += 1;
+                hidden_time += trace.query_time();
+                hidden_entities += trace.total_entities();
+            } else {
+                shown.push((name, path, trace));
+            }
+        }
+
+        let [branch, last, pipe, blank] = if display.ascii {
+            ASCII_TREE
+        } else {
+            UNICODE_TREE
+        };
+        for (i, (name, path, trace)) in shown.iter().enumerate() {
+            let is_last = i + 1 == shown.len() && hidden == 0;
+            let (lead, below) = if is_last {
+                (last, blank)
+            } else {
+                (branch, pipe)
+            };
+            self.add_field(
+                name,
+                path,
+                trace,
+                &format!("{prefix}{lead}"),
+                &format!("{prefix}{below}"),
+                depth,
+            );
+        }
+        if hidden > 0 {
+            let lead = format!("{prefix}{last}");
+            let name = match hidden {
+                1 => "(1 more field)".to_string(),
+                n => format!("({n} more fields)"),
+            };
+            self.out.push_str(&format!(
+                "{lead}{name} {blank:9} {elapsed:>9} {percent:5.1}% [{count:7} entities]\n",
+                name = fit(&name, self.width.saturating_sub(lead.chars().count())),
+                blank = "",
+                percent = percent_of(hidden_time, self.total),
+                count = hidden_entities,
+                elapsed = display.time(hidden_time),
+            ));
+        }
+    }
+}
+
+/// The time that the `n`-th slowest field of `trace` took by itself, not
+/// counting the fields below it
+fn top_nodes_cutoff(trace: &Trace, n: usize) -> Duration {
+    fn collect(trace: &Trace, times: &mut Vec<Duration>) {
+        for (_, child) in trace.children() {
+            times.push(child.elapsed());
+            collect(child, times);
+        }
+    }
+    let mut times = Vec::new();
+    collect(trace, &mut times);
+    times.sort_by_key(|time| std::cmp::Reverse(*time));
+    match n {
+        0 => Duration::MAX,
+        n => times.get(n - 1).copied().unwrap_or_default(),
+    }
+}
+
+/// Whether `trace` or any field below it took at least `cutoff` by itself
+fn has_slow_node(trace: &Trace, cutoff: Duration) -> bool {
+    trace.elapsed() >= cutoff
+        || trace
+            .children()
+            .iter()
+            .any(|(_, child)| has_slow_node(child, cutoff))
+}
+
+/// Whether the path of `trace`, or of any field below it, matches `grep`
+fn has_matching_node(path: &str, trace: &Trace, grep: &Regex) -> bool {
+    grep.is_match(path)
+        || trace
+            .children()
+            .iter()
+            .any(|(name, child)| has_matching_node(&child_path(path, name), child, grep))
+}
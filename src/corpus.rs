@@ -0,0 +1,151 @@
+//! A corpus of recorded queries that can be replayed later to see whether
+//! changes to the query nodes made them slower or changed their results.
+//! Each query is stored in its own JSON file with the request, the response
+//! and the trace that `graph-node` returned when it was recorded
+
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::anyhow;
+use serde_json::{self as json, json};
+
+use super::fingerprint::fingerprint;
+use super::{response_diffs, Config, LogEntry, ServerVersion, Trace};
+
+/// A query from the corpus
+pub struct Recording {
+    /// The file that the recording is stored in
+    pub path: PathBuf,
+    pub deployment: String,
+    pub log_entry: LogEntry,
+    /// The response and trace that graph-node returned when the query was
+    /// recorded
+    pub response: json::Value,
+    pub trace: Trace,
+}
+
+impl Recording {
+    fn read(path: PathBuf) -> anyhow::Result<Self> {
+        let invalid =
+            |e: &dyn std::fmt::Display| anyhow!("Invalid recording {}: {e}", path.display());
+        let text = fs::read_to_string(&path).map_err(|e| invalid(&e))?;
+        let recording: json::Value = json::from_str(&text).map_err(|e| invalid(&e))?;
+        let field = |name: &str| {
+            recording[name]
+                .as_str()
+                .map(str::to_string)
+                .ok_or_else(|| invalid(&format!("{name} is missing")))
+        };
+        let log_entry = LogEntry {
+            timestamp: None,
+            query_id: field("query_id")?,
+            block: recording["block"].as_u64(),
+            query_time: 0,
+            query: recording["request"]["query"]
+                .as_str()
+                .ok_or_else(|| invalid(&"the request has no query"))?
+                .to_string(),
+            variables: recording["request"]["variables"].clone(),
+            unpinned: None,
+        };
+        let version = recording["graph_node_version"].as_str();
+        let trace = Trace::parse_from(&recording["trace"], version).map_err(|e| invalid(&e))?;
+        Ok(Recording {
+            deployment: field("deployment")?,
+            log_entry,
+            response: recording["response"].clone(),
+            trace,
+            path,
+        })
+    }
+}
+
+/// The recordings in `corpus`, sorted by file name
+pub fn read_corpus(corpus: &str) -> anyhow::Result<Vec<Recording>> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(corpus).map_err(|e| anyhow!("Failed to read {corpus}: {e}"))? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "json") {
+            paths.push(path);
+        }
+    }
+    if paths.is_empty() {
+        return Err(anyhow!("There are no recordings in {corpus}"));
+    }
+    paths.sort();
+    paths.into_iter().map(Recording::read).collect()
+}
+
+/// Replay `log_entry`, the `rank`-th query found for `deployment`, and
+/// save it in the `corpus` directory. Returns the
+/// file it was saved in and how long the query took
+pub async fn record(
+    config: &Config,
+    deployment: &str,
+    log_entry: &LogEntry,
+    rank: usize,
+    corpus: &str,
+    out: &mut dyn std::io::Write,
+) -> anyhow::Result<(PathBuf, Duration)> {
+    writeln!(out, "Querying graph-node for query trace")?;
+    let mut response = config
+        .graph_node()
+        .query(deployment, log_entry, &config.retry, out)
+        .await?;
+    let trace = response
+        .as_object_mut()
+        .and_then(|response| response.remove("trace"))
+        .unwrap_or_default();
+    let elapsed = config.graph_node().parse_trace(&trace)?.elapsed();
+    // Older versions of graph-node did not log the query id
+    let name = match log_entry.query_id.as_str() {
+        "none" => format!("{deployment}-{rank}.json"),
+        query_id => format!("{deployment}-{query_id}.json"),
+    };
+    let path = Path::new(corpus).join(name);
+    let mut recording = json!({
+        "deployment": deployment,
+        "query_id": log_entry.query_id,
+        "query_fingerprint": fingerprint(&log_entry.query, &log_entry.variables),
+        "block": log_entry.block,
+        "request": log_entry.request_body(),
+        "response": response,
+        "trace": trace,
+    });
+    ServerVersion::record(config.graph_node().version.as_ref(), &mut recording);
+    let mut f = File::create(&path)?;
+    writeln!(f, "{}", json::to_string_pretty(&recording)?)?;
+    Ok((path, elapsed))
+}
+
+/// What replaying a recording returned
+pub struct Rerun {
+    pub trace: Trace,
+    /// How the data and errors differ from those of the recording
+    pub diffs: Vec<String>,
+}
+
+/// Replay `recording` and compare the response with the recorded one
+pub async fn rerun(
+    config: &Config,
+    recording: &Recording,
+    out: &mut dyn std::io::Write,
+) -> anyhow::Result<Rerun> {
+    writeln!(out, "Querying graph-node for query trace")?;
+    let response = config
+        .graph_node()
+        .query(
+            &recording.deployment,
+            &recording.log_entry,
+            &config.retry,
+            out,
+        )
+        .await?;
+    let trace = config.graph_node().parse_trace(&response["trace"])?;
+    Ok(Rerun {
+        trace,
+        diffs: response_diffs(&recording.response, &response),
+    })
+}
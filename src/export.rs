@@ -0,0 +1,434 @@
+//! Export of traces as spans to tracing systems, so that they show up in
+//! the same place as the spans from the gateway and other services. Spans
+//! are sent to an OTLP endpoint, e.g., an OpenTelemetry collector or
+//! Grafana Tempo, or to Zipkin, or saved as JSON for Jaeger or Zipkin
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Write;
+
+use anyhow::anyhow;
+use chrono::Utc;
+use serde_derive::Deserialize;
+use serde_json::{self as json, json};
+use url::Url;
+
+use super::{spans, Http, LogEntry, Retry, Trace};
+
+/// Where to send spans. They are sent with OTLP over HTTP, encoded as JSON
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct Otlp {
+    /// The OTLP endpoint, e.g., `http://localhost:4318`. Spans are sent to
+    /// `/v1/traces` below it
+    url: String,
+    /// The `service.name` of the spans
+    #[serde(default = "Otlp::default_service_name")]
+    service_name: String,
+    /// Headers to send with every request, e.g., for authentication
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    #[serde(flatten)]
+    http: Http,
+}
+
+/// The value of an attribute of a span
+enum Value<'a> {
+    String(&'a str),
+    Int(usize),
+}
+
+/// A span for a node of a trace, with times in nanoseconds since the epoch
+struct SpanData<'a> {
+    id: String,
+    /// The index of the enclosing span
+    parent: Option<usize>,
+    name: &'a str,
+    start: i64,
+    end: i64,
+    attributes: Vec<(&'static str, Value<'a>)>,
+}
+
+/// The spans for `trace`: one for the whole query with one for each field
+/// below it. The spans end now
+fn span_data<'a>(
+    deployment: &'a str,
+    log_entry: &'a LogEntry,
+    trace: &'a Trace,
+) -> Vec<SpanData<'a>> {
+    let spans = spans(trace);
+    let end = Utc::now().timestamp_nanos_opt().unwrap_or_default();
+    let start = end - (spans[0].end * 1_000_000.0) as i64;
+    let nanos = |ms: f64| start + (ms * 1_000_000.0) as i64;
+    // The spans that enclose the current one, innermost last
+    let mut parents: Vec<usize> = Vec::new();
+    let mut data = Vec::new();
+    for (i, span) in spans.iter().enumerate() {
+        parents.truncate(span.depth);
+        let (name, attributes) = match span.trace {
+            Trace::Root { block, .. } => {
+                let mut attributes = vec![
+                    ("graphql.document", Value::String(&log_entry.query)),
+                    ("subgraph.deployment", Value::String(deployment)),
+                    ("graph_node.query_id", Value::String(&log_entry.query_id)),
+                    ("graph_node.block", Value::Int(*block)),
+                ];
+                let name = log_entry.operation_name();
+                if let Some(name) = name {
+                    attributes.push(("graphql.operation.name", Value::String(name)));
+                }
+                (name.unwrap_or("query"), attributes)
+            }
+            Trace::Query {
+                entity_count, sql, ..
+            } => {
+                let mut attributes = Vec::new();
+                if let Some(entity_count) = entity_count {
+                    attributes.push(("graph_node.entity_count", Value::Int(*entity_count)));
+                }
+                if let Some(sql) = sql {
+                    attributes.push(("db.query.text", Value::String(sql)));
+                }
+                (span.name, attributes)
+            }
+        };
+        data.push(SpanData {
+            id: format!("{:016x}", rand::random::<u64>()),
+            parent: parents.last().copied(),
+            name,
+            start: nanos(span.start),
+            end: nanos(span.end),
+            attributes,
+        });
+        parents.push(i);
+    }
+    data
+}
+
+impl Otlp {
+    fn default_service_name() -> String {
+        "qtrace".to_string()
+    }
+
+    fn traces_url(&self) -> anyhow::Result<Url> {
+        let url = match self.url.trim_end_matches('/') {
+            url if url.ends_with("/v1/traces") => url.to_string(),
+            url => format!("{url}/v1/traces"),
+        };
+        Url::parse(&url).map_err(|e| anyhow!("Invalid OTLP url {url}: {e}"))
+    }
+
+    /// Send `trace` as a tree of spans. They use `request_id`, which has
+    /// the same format, as their trace id so that they can be found by the
+    /// id that the request to graph-node was sent with
+    pub async fn export(
+        &self,
+        deployment: &str,
+        log_entry: &LogEntry,
+        trace: &Trace,
+        request_id: &str,
+        retry: &Retry,
+        out: &mut dyn std::io::Write,
+    ) -> anyhow::Result<()> {
+        let data = span_data(deployment, log_entry, trace);
+        let spans: Vec<_> = data
+            .iter()
+            .map(|span| {
+                let attributes: Vec<_> = span
+                    .attributes
+                    .iter()
+                    .map(|(key, value)| match value {
+                        Value::String(value) => {
+                            json!({ "key": key, "value": { "stringValue": value } })
+                        }
+                        // OTLP/JSON encodes 64-bit integers as strings
+                        Value::Int(value) => {
+                            json!({ "key": key, "value": { "intValue": value.to_string() } })
+                        }
+                    })
+                    .collect();
+                let mut otel_span = json!({
+                    "traceId": request_id,
+                    "spanId": span.id,
+                    "name": span.name,
+                    // Client for the request, internal for the fields
+                    "kind": if span.parent.is_none() { 3 } else { 1 },
+                    "startTimeUnixNano": span.start.to_string(),
+                    "endTimeUnixNano": span.end.to_string(),
+                    "attributes": attributes,
+                });
+                if let Some(parent) = span.parent {
+                    otel_span["parentSpanId"] = json!(data[parent].id);
+                }
+                otel_span
+            })
+            .collect();
+        let body = json!({
+            "resourceSpans": [{
+                "resource": {
+                    "attributes": [{
+                        "key": "service.name",
+                        "value": { "stringValue": self.service_name },
+                    }],
+                },
+                "scopeSpans": [{
+                    "scope": { "name": "qtrace", "version": env!("CARGO_PKG_VERSION") },
+                    "spans": spans,
+                }],
+            }],
+        })
+        .to_string();
+
+        let client = self.http.client()?;
+        let url = self.traces_url()?;
+        let request = || {
+            let mut request = client
+                .post(url.clone())
+                .header("Content-Type", "application/json")
+                .body(body.clone());
+            for (name, value) in &self.headers {
+                request = request.header(name, value);
+            }
+            request
+        };
+        retry
+            .send(request, None, out)
+            .await
+            .and_then(|resp| Ok(resp.error_for_status()?))
+            .map_err(|e| anyhow!("Failed to export the trace to {url}: {e}"))?;
+        writeln!(out, "Exported {} spans to {url}", data.len())?;
+        Ok(())
+    }
+}
+
+/// Save `trace` in `path` as a JSON file that the Jaeger UI can import,
+/// with the same spans that are sent with OTLP
+pub fn save_jaeger(
+    deployment: &str,
+    log_entry: &LogEntry,
+    trace: &Trace,
+    request_id: &str,
+    path: &str,
+) -> anyhow::Result<()> {
+    let data = span_data(deployment, log_entry, trace);
+    // Jaeger measures time in microseconds
+    let micros = |nanos: i64| nanos / 1000;
+    let spans: Vec<_> = data
+        .iter()
+        .map(|span| {
+            let tags: Vec<_> = span
+                .attributes
+                .iter()
+                .map(|(key, value)| match value {
+                    Value::String(value) => json!({ "key": key, "type": "string", "value": value }),
+                    Value::Int(value) => json!({ "key": key, "type": "int64", "value": value }),
+                })
+                .collect();
+            let references: Vec<_> = span
+                .parent
+                .iter()
+                .map(|parent| {
+                    json!({
+                        "refType": "CHILD_OF",
+                        "traceID": request_id,
+                        "spanID": data[*parent].id,
+                    })
+                })
+                .collect();
+            json!({
+                "traceID": request_id,
+                "spanID": span.id,
+                "operationName": span.name,
+                "references": references,
+                "startTime": micros(span.start),
+                "duration": micros(span.end) - micros(span.start),
+                "tags": tags,
+                "logs": [],
+                "processID": "p1",
+            })
+        })
+        .collect();
+    let jaeger = json!({
+        "data": [{
+            "traceID": request_id,
+            "spans": spans,
+            "processes": { "p1": { "serviceName": "qtrace", "tags": [] } },
+        }],
+    });
+    let mut f = File::create(path).map_err(|e| anyhow!("Failed to create {path}: {e}"))?;
+    writeln!(f, "{}", json::to_string_pretty(&jaeger)?)?;
+    Ok(())
+}
+
+/// A Zipkin collector to send spans to
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct Zipkin {
+    /// The Zipkin server, e.g., `http://localhost:9411`. Spans are sent to
+    /// `/api/v2/spans` below it
+    url: String,
+    /// The service name of the spans
+    #[serde(default = "Otlp::default_service_name")]
+    service_name: String,
+    #[serde(flatten)]
+    http: Http,
+}
+
+/// `trace` as spans in the Zipkin v2 JSON format
+fn zipkin_spans(
+    deployment: &str,
+    log_entry: &LogEntry,
+    trace: &Trace,
+    request_id: &str,
+    service_name: &str,
+) -> json::Value {
+    let data = span_data(deployment, log_entry, trace);
+    // Zipkin measures time in microseconds and only has string tags
+    let micros = |nanos: i64| nanos / 1000;
+    let spans: Vec<_> = data
+        .iter()
+        .map(|span| {
+            let tags: json::Map<_, _> = span
+                .attributes
+                .iter()
+                .map(|(key, value)| {
+                    let value = match value {
+                        Value::String(value) => value.to_string(),
+                        Value::Int(value) => value.to_string(),
+                    };
+                    (key.to_string(), json!(value))
+                })
+                .collect();
+            let mut zipkin_span = json!({
+                "traceId": request_id,
+                "id": span.id,
+                "name": span.name,
+                "timestamp": micros(span.start),
+                "duration": micros(span.end) - micros(span.start),
+                "localEndpoint": { "serviceName": service_name },
+                "tags": tags,
+            });
+            match span.parent {
+                Some(parent) => zipkin_span["parentId"] = json!(data[parent].id),
+                None => zipkin_span["kind"] = json!("CLIENT"),
+            }
+            zipkin_span
+        })
+        .collect();
+    json!(spans)
+}
+
+/// Save `trace` in `path` as a JSON file with Zipkin v2 spans
+pub fn save_zipkin(
+    deployment: &str,
+    log_entry: &LogEntry,
+    trace: &Trace,
+    request_id: &str,
+    path: &str,
+) -> anyhow::Result<()> {
+    let spans = zipkin_spans(deployment, log_entry, trace, request_id, "qtrace");
+    let mut f = File::create(path).map_err(|e| anyhow!("Failed to create {path}: {e}"))?;
+    writeln!(f, "{}", json::to_string_pretty(&spans)?)?;
+    Ok(())
+}
+
+impl Zipkin {
+    fn spans_url(&self) -> anyhow::Result<Url> {
+        let url = match self.url.trim_end_matches('/') {
+            url if url.ends_with("/api/v2/spans") => url.to_string(),
+            url => format!("{url}/api/v2/spans"),
+        };
+        Url::parse(&url).map_err(|e| anyhow!("Invalid Zipkin url {url}: {e}"))
+    }
+
+    /// Send `trace` to the collector, with `request_id` as the trace id
+    pub async fn export(
+        &self,
+        deployment: &str,
+        log_entry: &LogEntry,
+        trace: &Trace,
+        request_id: &str,
+        retry: &Retry,
+        out: &mut dyn std::io::Write,
+    ) -> anyhow::Result<()> {
+        let spans = zipkin_spans(deployment, log_entry, trace, request_id, &self.service_name);
+        let count = spans.as_array().map_or(0, Vec::len);
+        let body = spans.to_string();
+        let client = self.http.client()?;
+        let url = self.spans_url()?;
+        let request = || {
+            client
+                .post(url.clone())
+                .header("Content-Type", "application/json")
+                .body(body.clone())
+        };
+        retry
+            .send(request, None, out)
+            .await
+            .and_then(|resp| Ok(resp.error_for_status()?))
+            .map_err(|e| anyhow!("Failed to export the trace to {url}: {e}"))?;
+        writeln!(out, "Exported {count} spans to {url}")?;
+        Ok(())
+    }
+}
+
+/// Grafana Tempo, which spans are sent to with OTLP. With `grafana-url`,
+/// the link to the trace in Grafana is printed
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct Tempo {
+    #[serde(flatten)]
+    otlp: Otlp,
+    /// The Grafana that has Tempo as a data source
+    grafana_url: Option<String>,
+    /// The uid of the Tempo data source in Grafana
+    #[serde(default = "Tempo::default_datasource")]
+    datasource: String,
+}
+
+impl Tempo {
+    fn default_datasource() -> String {
+        "tempo".to_string()
+    }
+
+    /// The link to the trace with `trace_id` in Grafana's Explore view
+    fn trace_url(&self, grafana_url: &str, trace_id: &str) -> anyhow::Result<Url> {
+        let mut url = Url::parse(grafana_url)
+            .map_err(|e| anyhow!("Invalid Grafana url {grafana_url}: {e}"))?;
+        url.set_path(&format!("{}/explore", url.path().trim_end_matches('/')));
+        let left = json!({
+            "datasource": self.datasource,
+            "queries": [{
+                "refId": "A",
+                "datasource": { "type": "tempo", "uid": self.datasource },
+                "queryType": "traceql",
+                "query": trace_id,
+            }],
+            "range": { "from": "now-1h", "to": "now" },
+        });
+        url.query_pairs_mut()
+            .append_pair("orgId", "1")
+            .append_pair("left", &left.to_string());
+        Ok(url)
+    }
+
+    /// Push `trace` to Tempo and return where to find it: the link to it
+    /// in Grafana, or its id
+    pub async fn export(
+        &self,
+        deployment: &str,
+        log_entry: &LogEntry,
+        trace: &Trace,
+        request_id: &str,
+        retry: &Retry,
+        out: &mut dyn std::io::Write,
+    ) -> anyhow::Result<String> {
+        self.otlp
+            .export(deployment, log_entry, trace, request_id, retry, out)
+            .await?;
+        match &self.grafana_url {
+            Some(grafana_url) => Ok(self.trace_url(grafana_url, request_id)?.to_string()),
+            None => Ok(request_id.to_string()),
+        }
+    }
+}
@@ -0,0 +1,289 @@
+//! Findings about traces: patterns that commonly make queries slow, each
+//! of which a rule looks for. Every output format lists the findings of a
+//! trace, and with `--fail-on-findings`, `qtrace` exits with an error
+//! status when any trace has some, e.g., to fail a check in CI
+
+use std::time::Duration;
+
+use super::{child_path, percent_of, sql, DisplayOpts, Trace};
+
+/// Something that a rule found in a trace
+pub struct Finding {
+    /// The name of the rule that found it
+    pub rule: &'static str,
+    /// What kind of finding it is, e.g., `Repeated SQL`
+    pub title: &'static str,
+    pub message: String,
+}
+
+/// A check for one pattern in traces
+struct Rule {
+    /// The name of the rule in the JSON output
+    name: &'static str,
+    /// The title that its findings are listed with
+    title: &'static str,
+    /// Describe each occurrence of the pattern in a trace
+    check: fn(&Trace, &DisplayOpts) -> Vec<String>,
+}
+
+/// The rules that traces are checked against, in the order in which their
+/// findings are listed. A new check only needs an entry here
+const RULES: &[Rule] = &[
+    Rule {
+        name: "saturation",
+        title: "Database saturation",
+        check: saturation,
+    },
+    Rule {
+        name: "entities",
+        title: "Many entities",
+        check: many_entities,
+    },
+    Rule {
+        name: "nesting",
+        title: "Deep nesting",
+        check: deep_nesting,
+    },
+    Rule {
+        name: "n-plus-one",
+        title: "Likely N+1 queries",
+        check: n_plus_one,
+    },
+    Rule {
+        name: "repeated-sql",
+        title: "Repeated SQL",
+        check: repeated_sql,
+    },
+];
+
+/// Check `trace` against all rules
+pub fn analyze(trace: &Trace, display: &DisplayOpts) -> Vec<Finding> {
+    RULES
+        .iter()
+        .flat_map(|rule| {
+            (rule.check)(trace, display)
+                .into_iter()
+                .map(|message| Finding {
+                    rule: rule.name,
+                    title: rule.title,
+                    message,
+                })
+        })
+        .collect()
+}
+
+/// Point out when the fields of `trace` spent at least the fraction
+/// `display.saturation` of the time of the request waiting for a database
+/// connection or for a permit to run their query. The request is then slow
+/// because the connection pool or the permits are exhausted, which calls
+/// for more connections or less load rather than for faster SQL
+fn saturation(trace: &Trace, display: &DisplayOpts) -> Vec<String> {
+    let (conn_wait, permit_wait) = trace.total_waits();
+    let waits = conn_wait + permit_wait;
+    let elapsed = trace.elapsed();
+    if waits.is_zero() || waits.as_secs_f64() < display.saturation * elapsed.as_secs_f64() {
+        return Vec::new();
+    }
+    let contention = if conn_wait >= permit_wait {
+        "connections in the pool"
+    } else {
+        "permits to run queries"
+    };
+    vec![format!(
+        "the fields waited {} for a database connection and {} for a permit, \
+         {:.0}% of the {} of the request, so the bottleneck is contention for \
+         {contention} rather than the SQL itself",
+        display.time(conn_wait),
+        display.time(permit_wait),
+        percent_of(waits, elapsed),
+        display.time(elapsed),
+    )]
+}
+
+/// How many entities a field has to return to be pointed out
+const ENTITIES_MIN: usize = 10_000;
+
+/// Point out fields of `trace` that returned a lot of entities. Loading
+/// and serializing them takes time even when the SQL is fast, and clients
+/// rarely need that many at once
+fn many_entities(trace: &Trace, _display: &DisplayOpts) -> Vec<String> {
+    fn find(path: &str, trace: &Trace, found: &mut Vec<String>) {
+        for (name, child) in trace.children() {
+            let path = child_path(path, name);
+            if let Some(count) = child.entity_count().filter(|count| *count >= ENTITIES_MIN) {
+                found.push(format!(
+                    "{path} returned {count} entities; paging through them with \
+                     smaller `first` values is usually faster"
+                ));
+            }
+            find(&path, child, found);
+        }
+    }
+
+    let mut found = Vec::new();
+    find("", trace, &mut found);
+    found
+}
+
+/// How many levels fields can be nested before it is pointed out
+const NESTING_MAX: usize = 5;
+
+/// Point out when fields of `trace` are nested more than `NESTING_MAX`
+/// levels deep. Every level runs queries for the entities of the level
+/// above it, so deep queries multiply the work the database does
+fn deep_nesting(trace: &Trace, _display: &DisplayOpts) -> Vec<String> {
+    fn find(
+        path: &str,
+        depth: usize,
+        trace: &Trace,
+        deep: &mut usize,
+        deepest: &mut (usize, String),
+    ) {
+        for (name, child) in trace.children() {
+            let path = child_path(path, name);
+            if depth > NESTING_MAX {
+                *deep += 1;
+            }
+            if depth > deepest.0 {
+                *deepest = (depth, path.clone());
+            }
+            find(&path, depth + 1, child, deep, deepest);
+        }
+    }
+
+    let mut deep = 0;
+    let mut deepest = (0, String::new());
+    find("", 1, trace, &mut deep, &mut deepest);
+    if deep == 0 {
+        return Vec::new();
+    }
+    let (depth, path) = deepest;
+    vec![format!(
+        "{deep} fields are nested more than {NESTING_MAX} levels deep, down to \
+         {path} at {depth} levels; each level queries the database for the \
+         entities of the level above it"
+    )]
+}
+
+/// How many sibling fields with the same SQL it takes to point them out as
+/// a likely N+1 pattern
+const N_PLUS_ONE_MIN: usize = 5;
+
+/// Point out fields of `trace` with many siblings that ran the same SQL,
+/// or, for traces without SQL, that have the same response key apart from
+/// a number at its end. These usually come from resolving a list of
+/// entities one entity at a time, and are better fetched with one query
+fn n_plus_one(trace: &Trace, display: &DisplayOpts) -> Vec<String> {
+    fn find(path: &str, trace: &Trace, found: &mut Vec<(String, usize, Duration)>) {
+        let mut groups: Vec<(String, &str, usize, Duration)> = Vec::new();
+        for (name, child) in trace.children() {
+            let shape = match child {
+                Trace::Query { sql: Some(sql), .. } => sql::shape(sql),
+                _ => name
+                    .trim_end_matches(|c: char| c.is_ascii_digit())
+                    .to_string(),
+            };
+            match groups.iter_mut().find(|(s, ..)| *s == shape) {
+                Some((_, _, count, time)) => {
+                    *count += 1;
+                    *time += child.query_time();
+                }
+                None => groups.push((shape, name, 1, child.query_time())),
+            }
+        }
+        for (_, name, count, time) in groups {
+            if count >= N_PLUS_ONE_MIN {
+                let path = child_path(path, name);
+                found.push((path, count, time));
+            }
+        }
+        for (name, child) in trace.children() {
+            let path = child_path(path, name);
+            find(&path, child, found);
+        }
+    }
+
+    let mut found = Vec::new();
+    find("", trace, &mut found);
+    found
+        .into_iter()
+        .map(|(path, count, time)| {
+            format!(
+                "{path}: {count} fields with the same query, {} in total",
+                display.time(time)
+            )
+        })
+        .collect()
+}
+
+/// Point out SQL that several fields of `trace` ran, either exactly the
+/// same or with different values, and how long running it again took.
+/// Caching the result or restructuring the query avoids that
+fn repeated_sql(trace: &Trace, display: &DisplayOpts) -> Vec<String> {
+    struct Repeat<'a> {
+        shape: String,
+        paths: Vec<String>,
+        statements: Vec<(&'a str, &'a [String])>,
+        times: Vec<Duration>,
+    }
+
+    fn find<'a>(path: &str, trace: &'a Trace, repeats: &mut Vec<Repeat<'a>>) {
+        for (name, child) in trace.children() {
+            let path = child_path(path, name);
+            if let Trace::Query {
+                sql: Some(sql),
+                params,
+                elapsed,
+                ..
+            } = child
+            {
+                let shape = sql::shape(sql);
+                let index = match repeats.iter().position(|repeat| repeat.shape == shape) {
+                    Some(index) => index,
+                    None => {
+                        repeats.push(Repeat {
+                            shape,
+                            paths: Vec::new(),
+                            statements: Vec::new(),
+                            times: Vec::new(),
+                        });
+                        repeats.len() - 1
+                    }
+                };
+                let repeat = &mut repeats[index];
+                repeat.paths.push(path.clone());
+                repeat.statements.push((sql, params));
+                repeat.times.push(*elapsed);
+            }
+            find(&path, child, repeats);
+        }
+    }
+
+    let mut repeats = Vec::new();
+    find("", trace, &mut repeats);
+    repeats.retain(|repeat| repeat.paths.len() > 1);
+    repeats.sort_by_key(|repeat| std::cmp::Reverse(repeat.times.iter().sum::<Duration>()));
+    let mut found = Vec::new();
+    for repeat in repeats {
+        let total: Duration = repeat.times.iter().sum();
+        let again = total - repeat.times.iter().min().copied().unwrap_or_default();
+        let mut statements = repeat.statements;
+        statements.sort();
+        statements.dedup();
+        let mut paths = repeat.paths[..repeat.paths.len().min(3)].join(", ");
+        if repeat.paths.len() > 3 {
+            paths.push_str(&format!(" and {} more", repeat.paths.len() - 3));
+        }
+        let values = match statements.len() {
+            1 => "exactly the same SQL".to_string(),
+            distinct => format!("the same SQL with {distinct} different values"),
+        };
+        found.push(format!(
+            "{} fields ran {values}: {paths}; {} in total, {} of that running it again",
+            repeat.paths.len(),
+            display.time(total),
+            display.time(again)
+        ));
+    }
+    found
+}
@@ -0,0 +1,54 @@
+//! Fingerprints that identify a query independent of the values in it, so
+//! that traces of the same query can be grouped across runs and
+//! deployments. The fingerprint covers the shape of the document, which
+//! includes the declared types of its variables, and the JSON types of the
+//! variables that were sent, since leaving out an optional filter or
+//! passing `null` for it changes what the query does
+
+use serde_json as json;
+
+use super::query_shape;
+
+/// The parameters of the 64-bit FNV-1a hash, which unlike the hashers in
+/// `std` is guaranteed to stay the same across releases
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(FNV_OFFSET, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(FNV_PRIME)
+    })
+}
+
+fn value_type(value: &json::Value) -> &'static str {
+    match value {
+        json::Value::Null => "null",
+        json::Value::Bool(_) => "bool",
+        json::Value::Number(_) => "number",
+        json::Value::String(_) => "string",
+        json::Value::Array(_) => "list",
+        json::Value::Object(_) => "object",
+    }
+}
+
+/// The fingerprint of `query` run with `variables`, as 16 hex digits. The
+/// variables can either be an object or a string with the JSON for it,
+/// which is how `graph-node` puts them into traces
+pub fn fingerprint(query: &str, variables: &json::Value) -> String {
+    let variables = match variables {
+        json::Value::String(text) => json::from_str(text).unwrap_or_default(),
+        variables => variables.clone(),
+    };
+    let mut types: Vec<_> = variables
+        .as_object()
+        .map(|variables| {
+            variables
+                .iter()
+                .map(|(name, value)| format!("{name}:{}", value_type(value)))
+                .collect()
+        })
+        .unwrap_or_default();
+    types.sort();
+    let text = format!("{}\n{}", query_shape(query), types.join(","));
+    format!("{:016x}", fnv1a(text.as_bytes()))
+}
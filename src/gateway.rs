@@ -0,0 +1,71 @@
+//! The gateway of the decentralized network, which routes queries to one
+//! of the indexers that serve a deployment
+
+use anyhow::anyhow;
+use serde_derive::Deserialize;
+use serde_json as json;
+use url::Url;
+
+use super::{describe_request, send_query, Http, HttpTiming, LogEntry, Retry};
+
+/// Where to send queries to the network. The gateway does not return
+/// traces, only the data and how long the request took
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct Gateway {
+    #[serde(default = "Gateway::default_url")]
+    url: String,
+    /// The API key for the gateway. It is sent in a header so that it does
+    /// not end up in URLs or error messages
+    api_key: String,
+    #[serde(flatten)]
+    http: Http,
+}
+
+impl Gateway {
+    fn default_url() -> String {
+        "https://gateway.thegraph.com".to_string()
+    }
+
+    fn query_url(&self, deployment: &str) -> anyhow::Result<Url> {
+        let url = format!(
+            "{}/api/deployments/id/{deployment}",
+            self.url.trim_end_matches('/')
+        );
+        Url::parse(&url).map_err(|e| anyhow!("Invalid gateway url {url}: {e}"))
+    }
+
+    /// Send `log_entry` to the gateway and return its response and how
+    /// long the request took
+    pub(crate) async fn query(
+        &self,
+        deployment: &str,
+        log_entry: &LogEntry,
+        retry: &Retry,
+        out: &mut dyn std::io::Write,
+    ) -> anyhow::Result<(json::Value, HttpTiming)> {
+        let client = self.http.client()?;
+        let url = self.query_url(deployment)?;
+        let body = log_entry.request_body().to_string();
+        let request = || {
+            client
+                .post(url.clone())
+                .header("Content-Type", "application/json")
+                .bearer_auth(&self.api_key)
+                .body(body.clone())
+        };
+        send_query("gateway", request, retry, out).await
+    }
+
+    pub(crate) fn describe(
+        &self,
+        deployment: &str,
+        log_entry: Option<&LogEntry>,
+    ) -> anyhow::Result<String> {
+        describe_request(
+            &self.query_url(deployment)?,
+            &["Authorization".to_string()],
+            log_entry,
+        )
+    }
+}
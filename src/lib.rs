@@ -0,0 +1,3306 @@
+//! Find slow queries in the query log of `graph-node`, replay them to get
+//! their traces, and analyze the traces. The `qtrace` command is built on
+//! this library, which services can use instead of running the command:
+//! `Config::load` reads the same configuration file, `find_entries`
+//! searches the query log, `GraphNode::query` replays an entry, and
+//! `GraphNode::parse_trace` turns the trace in the response into a `Trace`.
+//! `brief::brief_trace` renders a trace like the command does,
+//! `findings::analyze` points out likely problems in it, and `diff_traces`
+//! and `response_diffs` compare two runs of the same query. The `bench`,
+//! `aggregate` and `corpus` modules return the results of the `bench`,
+//! `aggregate`, `record` and `rerun` commands as data. Their options, like
+//! `QueryFilter` and `DisplayOpts`, are plain structs with defaults
+
+pub mod aggregate;
+pub mod bench;
+pub mod brief;
+pub mod corpus;
+pub mod export;
+pub mod findings;
+pub mod fingerprint;
+pub mod gateway;
+pub mod local;
+pub mod persisted;
+pub mod report;
+pub mod source;
+pub mod sql;
+mod trace_format;
+pub mod tunnel;
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::File,
+    ops::Range,
+    sync::OnceLock,
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+use chrono::{DateTime, Utc};
+use regex::Regex;
+use serde_derive::Deserialize;
+use serde_json::{self as json, json};
+use url::Url;
+
+use export::{Otlp, Tempo, Zipkin};
+use gateway::Gateway;
+use local::LocalNode;
+use persisted::PersistedQueries;
+use report::ReportFormat;
+use source::{
+    CloudWatch, DockerLogs, Elasticsearch, Gcp, Journal, Kubernetes, LogFile, LogFormat, LogSource,
+    Loki, Pattern,
+};
+
+/// How traces are printed
+#[derive(Debug, Clone)]
+pub struct DisplayOpts {
+    /// Print the SQL that graph-node ran for each field, and its bind
+    /// parameters, below the trace
+    pub show_sql: bool,
+    pub format: TraceFormat,
+    /// The order of the fields below each field of a trace
+    pub sort: TraceSort,
+    /// Only show the N fields that took the longest by themselves, and the
+    /// fields that lead to them
+    pub top_nodes: Option<usize>,
+    /// Leave out fields that took less than this many milliseconds,
+    /// counting the fields below them
+    pub min_node_ms: Option<u64>,
+    /// Leave out fields that are nested more than this many levels deep
+    pub max_depth: Option<usize>,
+    /// Only show the fields whose path of response keys, e.g.,
+    /// `pools.swaps`, matches this, and the fields that lead to them
+    pub grep: Option<Regex>,
+    /// Print a report about each trace instead of the brief output
+    pub report: Option<ReportFormat>,
+    /// Show how long each field waited for a database connection and for a
+    /// permit to run its query
+    pub waits: bool,
+    /// Show the arguments of each field next to its name
+    pub args: bool,
+    /// The database counts as saturated when the fields waited for a
+    /// connection and a permit for at least this fraction of the time of
+    /// the request
+    pub saturation: f64,
+    /// Fields that took at least this many milliseconds by themselves are
+    /// shown in yellow, and those that took at least `red_ms` in red
+    pub yellow_ms: u64,
+    pub red_ms: u64,
+    /// Whether to color slow fields with ANSI escape codes
+    pub colors: bool,
+    /// Draw the tree of fields with ASCII characters instead of box-drawing
+    /// ones
+    pub ascii: bool,
+    pub unit: TimeUnit,
+    /// The number of decimals to show times with, by default 0, or 3 for
+    /// seconds
+    pub precision: Option<usize>,
+    /// The number of columns that the brief output has to fit into, e.g.,
+    /// those of the terminal. Names of fields that do not fit are cut short
+    pub width: Option<usize>,
+}
+
+impl Default for DisplayOpts {
+    fn default() -> Self {
+        DisplayOpts {
+            show_sql: false,
+            format: TraceFormat::default(),
+            sort: TraceSort::default(),
+            top_nodes: None,
+            min_node_ms: None,
+            max_depth: None,
+            grep: None,
+            report: None,
+            waits: false,
+            args: false,
+            saturation: 0.2,
+            yellow_ms: 100,
+            red_ms: 1000,
+            colors: false,
+            ascii: false,
+            unit: TimeUnit::default(),
+            precision: None,
+            width: None,
+        }
+    }
+}
+
+impl DisplayOpts {
+    /// The ANSI escape code for the color of a field that took `elapsed` by
+    /// itself, if it should have one
+    pub fn color(&self, elapsed: Duration) -> Option<&'static str> {
+        if !self.colors {
+            return None;
+        }
+        let ms = elapsed.as_millis() as u64;
+        if ms >= self.red_ms {
+            Some("\x1b[31m")
+        } else if ms >= self.yellow_ms {
+            Some("\x1b[33m")
+        } else {
+            None
+        }
+    }
+
+    /// `time` in the unit and with the precision that were asked for,
+    /// e.g., `12ms` or `0.012s`
+    pub fn time(&self, time: Duration) -> String {
+        let (value, unit, precision) = match self.unit {
+            TimeUnit::Us => (time.as_secs_f64() * 1e6, "µs", 0),
+            TimeUnit::Ms => (time.as_secs_f64() * 1e3, "ms", 0),
+            TimeUnit::S => (time.as_secs_f64(), "s", 3),
+        };
+        let precision = self.precision.unwrap_or(precision);
+        format!("{value:.precision$}{unit}")
+    }
+
+    /// How much longer, with a `+`, or shorter, with a `-`, `to` took than
+    /// `from`, like `time` shows it
+    pub fn time_delta(&self, from: Duration, to: Duration) -> String {
+        if to >= from {
+            format!("+{}", self.time(to - from))
+        } else {
+            format!("-{}", self.time(from - to))
+        }
+    }
+}
+
+/// The units that times can be shown in
+#[derive(clap::ValueEnum, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TimeUnit {
+    /// Microseconds
+    Us,
+    /// Milliseconds
+    #[default]
+    Ms,
+    /// Seconds
+    S,
+}
+
+/// The order in which the fields of a trace are printed
+#[derive(clap::ValueEnum, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TraceSort {
+    /// The slowest first, counting the fields below them
+    #[default]
+    Elapsed,
+    /// The ones that returned the most entities first
+    Entities,
+    /// By response key
+    Name,
+}
+
+/// How traces are printed
+#[derive(clap::ValueEnum, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFormat {
+    /// A tree of the fields with their times
+    #[default]
+    Brief,
+    /// A JSON profile for speedscope.app, on a single line
+    Speedscope,
+    /// Trace events for chrome://tracing and the Perfetto UI, on a single
+    /// line
+    Chrome,
+    /// A JSON summary with the timings of the request and a record for
+    /// each field, on a single line
+    Json,
+    /// A Graphviz graph of the fields, with thicker edges to the fields
+    /// that took more of the time
+    Dot,
+    /// A Mermaid flowchart of the fields in a Markdown code block, which
+    /// GitHub and GitLab render
+    Mermaid,
+}
+
+/// The conditions that entries from the query log must meet
+#[derive(Debug, Default, Clone)]
+pub struct QueryFilter {
+    /// The `query_id` to trace
+    pub qid: Option<String>,
+    /// Only consider queries that took longer than this many milliseconds
+    pub min_time: Option<usize>,
+    /// Only consider queries that took less than this many milliseconds
+    pub max_time: Option<usize>,
+    /// Only consider queries that ran against this block or a later one
+    pub block_from: Option<u64>,
+    /// Only consider queries that ran against this block or an earlier one
+    pub block_to: Option<u64>,
+    /// Only consider queries logged after this time
+    pub since: Option<DateTime<Utc>>,
+    /// Only consider queries logged before this time
+    pub until: Option<DateTime<Utc>>,
+    /// Only consider queries with this GraphQL operation name
+    pub operation: Option<String>,
+    /// Only consider queries whose text matches this regular expression
+    pub query_regex: Option<Regex>,
+    /// The IPFS hash of the deployment
+    pub deployment: String,
+}
+
+impl QueryFilter {
+    /// Check whether `entry` passes the filters. Sources that can not apply
+    /// all of them themselves use this to check the entries they found
+    pub fn matches(&self, entry: &LogEntry) -> bool {
+        if self.qid.as_ref().is_some_and(|qid| qid != &entry.query_id) {
+            return false;
+        }
+        if self
+            .min_time
+            .is_some_and(|min_time| entry.query_time <= min_time as u64)
+        {
+            return false;
+        }
+        if self
+            .max_time
+            .is_some_and(|max_time| entry.query_time >= max_time as u64)
+        {
+            return false;
+        }
+        if let Some(from) = self.block_from {
+            if entry.block.is_none_or(|block| block < from) {
+                return false;
+            }
+        }
+        if let Some(to) = self.block_to {
+            if entry.block.is_none_or(|block| block > to) {
+                return false;
+            }
+        }
+        if self.operation.is_some() && self.operation.as_deref() != entry.operation_name() {
+            return false;
+        }
+        if self
+            .query_regex
+            .as_ref()
+            .is_some_and(|regex| !regex.is_match(&entry.query))
+        {
+            return false;
+        }
+        // Entries without a timestamp can not be checked against the window
+        if let Some(timestamp) = entry.timestamp {
+            if self.since.is_some_and(|since| timestamp < since)
+                || self.until.is_some_and(|until| timestamp > until)
+            {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Logs on this machine to read query log entries from instead of the log
+/// aggregators in the configuration
+#[derive(Debug, Clone)]
+pub enum LocalLogs {
+    /// A file with `graph-node` log lines in the format that Loki ingests
+    File(String),
+    /// The journal of a systemd unit, read with `journalctl`
+    Journal(String),
+    /// The logs of a container, read with `docker logs`
+    Docker(String),
+}
+
+/// Changes to the `first` and `skip` arguments of the fields in a query
+#[derive(Clone, Debug, Default)]
+pub struct ArgumentOpts {
+    /// The new value of the `first` argument of all fields that have one
+    pub first: Option<u64>,
+    /// The new value of the `skip` argument of all fields that have one
+    pub skip: Option<u64>,
+    /// Only change the arguments of the fields with this name or alias, and
+    /// add them to these fields if they do not have them yet
+    pub field: Option<String>,
+}
+
+impl ArgumentOpts {
+    /// The arguments to change, and their new values
+    fn overrides(&self) -> Vec<(&'static str, u64)> {
+        [("first", self.first), ("skip", self.skip)]
+            .into_iter()
+            .filter_map(|(name, value)| Some((name, value?)))
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+pub struct LogEntry {
+    pub timestamp: Option<DateTime<Utc>>,
+    pub query_id: String,
+    pub block: Option<u64>,
+    pub query_time: u64,
+    pub query: String,
+    pub variables: json::Value,
+    /// The query as it was before `pin_block` added the block to it
+    pub unpinned: Option<String>,
+}
+
+impl LogEntry {
+    /// The name of the operation in the query, or `None` for anonymous
+    /// queries
+    fn operation_name(&self) -> Option<&str> {
+        static NAME: OnceLock<Regex> = OnceLock::new();
+        let name = NAME.get_or_init(|| {
+            Regex::new(r"\b(?:query|mutation|subscription)\s+([_A-Za-z][_0-9A-Za-z]*)").unwrap()
+        });
+        name.captures(&self.query)
+            .and_then(|caps| caps.get(1))
+            .map(|name| name.as_str())
+    }
+
+    /// Replace the variables that are in `overrides`, and add the ones
+    /// that the query was not logged with
+    pub fn override_variables(&mut self, overrides: &json::Map<String, json::Value>) {
+        if overrides.is_empty() {
+            return;
+        }
+        if !self.variables.is_object() {
+            self.variables = json!({});
+        }
+        if let Some(variables) = self.variables.as_object_mut() {
+            variables.extend(overrides.clone());
+        }
+    }
+
+    /// Change the `first` and `skip` arguments of the query as `arguments`
+    /// says. Arguments that are passed as variables are changed by changing
+    /// the variable
+    pub fn override_arguments(&mut self, arguments: &ArgumentOpts) -> anyhow::Result<()> {
+        let overrides = arguments.overrides();
+        if overrides.is_empty() {
+            return Ok(());
+        }
+        let field = arguments.field.as_deref();
+        let (query, variables) = with_arguments(&self.query, &overrides, field)?;
+        if let Some(field) = field {
+            if query == self.query && variables.is_empty() {
+                return Err(anyhow!("The query has no field named {field}"));
+            }
+        }
+        self.query = query;
+        let overrides = variables
+            .into_iter()
+            .map(|(name, value)| (name, json!(value)))
+            .collect();
+        self.override_variables(&overrides);
+        Ok(())
+    }
+
+    /// Make the query run against the block that it originally ran against
+    /// by adding a `block` argument to its top-level fields
+    pub fn pin_block(&mut self) -> anyhow::Result<()> {
+        let block = self
+            .block
+            .ok_or_else(|| anyhow!("Query {} was logged without a block", self.query_id))?;
+        let pinned = with_block(&self.query, block);
+        self.unpinned = Some(std::mem::replace(&mut self.query, pinned));
+        Ok(())
+    }
+
+    /// This entry with the query from before `pin_block`, or `None` if the
+    /// block was not pinned
+    pub fn unpin(&self) -> Option<LogEntry> {
+        Some(LogEntry {
+            timestamp: self.timestamp,
+            query_id: self.query_id.clone(),
+            block: self.block,
+            query_time: self.query_time,
+            query: self.unpinned.clone()?,
+            variables: self.variables.clone(),
+            unpinned: None,
+        })
+    }
+
+    /// The body of the request that replays the query
+    pub fn request_body(&self) -> json::Value {
+        json!({
+            "query": self.query,
+            "variables": self.variables,
+        })
+    }
+
+    pub fn to_json(&self) -> json::Value {
+        json!({
+            "timestamp": self.timestamp.map(|ts| ts.to_rfc3339()),
+            "query_id": self.query_id,
+            "block": self.block,
+            "query_time_ms": self.query_time,
+            "query": self.query,
+            "variables": self.variables,
+        })
+    }
+
+    /// Build a log entry from the fields of a query log line, named like the
+    /// labels that the LogQL pattern extracts, and the timestamp of the line
+    fn from_stream(
+        stream: &json::Map<String, json::Value>,
+        timestamp: Option<DateTime<Utc>>,
+    ) -> anyhow::Result<Self> {
+        let query = match stream.get("query") {
+            Some(json::Value::String(s)) => s.to_string(),
+            _ => return Err(anyhow!("Invalid query log entry: could not find query")),
+        };
+        // Older versions of graph-node did not log the variables or the
+        // query id
+        let variables = match stream.get("variables") {
+            Some(json::Value::String(s)) => json::from_str(s)
+                .map_err(|e| anyhow!("Invalid query log entry: unparseable variables: {e}"))?,
+            _ => json!({}),
+        };
+        let query_id = match stream.get("query_id") {
+            Some(json::Value::String(s)) => s.to_string(),
+            _ => "none".to_string(),
+        };
+        let query_time = stream
+            .get("query_time")
+            .and_then(|time| time.as_str())
+            .and_then(|time| time.parse().ok())
+            .ok_or_else(|| anyhow!("Invalid query log entry: could not find query_time"))?;
+        let block = stream
+            .get("block")
+            .and_then(|block| block.as_str())
+            .and_then(|block| block.parse().ok());
+        Ok(LogEntry {
+            timestamp,
+            query_id,
+            block,
+            query_time,
+            query,
+            variables,
+            unpinned: None,
+        })
+    }
+}
+
+/// TLS settings for endpoints that use a private CA or require client
+/// certificates. All files must be in PEM format
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+struct Tls {
+    /// An additional CA certificate to trust
+    ca_cert: Option<String>,
+    /// The client certificate to present to the server
+    client_cert: Option<String>,
+    /// The private key for `client_cert`, in PKCS#8 format
+    client_key: Option<String>,
+}
+
+impl Tls {
+    fn read(file: &str) -> anyhow::Result<Vec<u8>> {
+        std::fs::read(file).map_err(|e| anyhow!("Failed to read {file}: {e}"))
+    }
+
+    fn configure(
+        &self,
+        mut builder: reqwest::ClientBuilder,
+    ) -> anyhow::Result<reqwest::ClientBuilder> {
+        let read = Self::read;
+        if let Some(ca_cert) = &self.ca_cert {
+            let cert = reqwest::Certificate::from_pem(&read(ca_cert)?)
+                .map_err(|e| anyhow!("Invalid CA certificate {ca_cert}: {e}"))?;
+            builder = builder.add_root_certificate(cert);
+        }
+        match (&self.client_cert, &self.client_key) {
+            (Some(cert), Some(key)) => {
+                let identity = reqwest::Identity::from_pkcs8_pem(&read(cert)?, &read(key)?)
+                    .map_err(|e| anyhow!("Invalid client certificate {cert}: {e}"))?;
+                builder = builder.identity(identity);
+            }
+            (None, None) => {}
+            _ => {
+                return Err(anyhow!(
+                    "Both client-cert and client-key must be set to use a client certificate"
+                ))
+            }
+        }
+        Ok(builder)
+    }
+
+    /// A TLS connector with the same settings as the clients from
+    /// `configure`, for connections that are not made with such a client
+    fn native(&self) -> anyhow::Result<native_tls::TlsConnector> {
+        let read = Self::read;
+        let mut builder = native_tls::TlsConnector::builder();
+        if let Some(ca_cert) = &self.ca_cert {
+            let cert = native_tls::Certificate::from_pem(&read(ca_cert)?)
+                .map_err(|e| anyhow!("Invalid CA certificate {ca_cert}: {e}"))?;
+            builder.add_root_certificate(cert);
+        }
+        if let (Some(cert), Some(key)) = (&self.client_cert, &self.client_key) {
+            let identity = native_tls::Identity::from_pkcs8(&read(cert)?, &read(key)?)
+                .map_err(|e| anyhow!("Invalid client certificate {cert}: {e}"))?;
+            builder.identity(identity);
+        }
+        Ok(builder.build()?)
+    }
+}
+
+/// Settings for the HTTP client that talks to an endpoint. They are part of
+/// the config section for that endpoint
+#[derive(Deserialize, Debug, Default)]
+pub struct Http {
+    #[serde(default)]
+    tls: Tls,
+    /// The proxy to use for all requests to the endpoint. Without it, the
+    /// proxies from the `HTTPS_PROXY`, `HTTP_PROXY` and `ALL_PROXY`
+    /// environment variables are used
+    proxy: Option<String>,
+    /// How long a request to the endpoint may take before it is abandoned
+    #[serde(rename = "timeout-secs")]
+    timeout_secs: Option<u64>,
+}
+
+impl Http {
+    /// The timeout for endpoints that do not set one
+    const DEFAULT_TIMEOUT: Duration = Duration::from_secs(300);
+
+    pub fn client(&self) -> anyhow::Result<reqwest::Client> {
+        let timeout = self
+            .timeout_secs
+            .map_or(Self::DEFAULT_TIMEOUT, Duration::from_secs);
+        let mut builder = self
+            .tls
+            .configure(reqwest::Client::builder())?
+            .timeout(timeout);
+        if let Some(proxy) = &self.proxy {
+            // Setting a proxy explicitly disables the ones from the environment
+            let proxy =
+                reqwest::Proxy::all(proxy).map_err(|e| anyhow!("Invalid proxy {proxy}: {e}"))?;
+            builder = builder.proxy(proxy);
+        }
+        Ok(builder.build()?)
+    }
+}
+
+#[derive(Debug)]
+pub enum Trace {
+    Root {
+        query: String,
+        variables: String,
+        query_id: String,
+        block: usize,
+        elapsed: Duration,
+        conn_wait: Duration,
+        permit_wait: Duration,
+        /// How graph-node's caches answered the query, e.g., `hit` or
+        /// `miss`, which older versions of graph-node do not include
+        cache_status: Option<String>,
+        children: Vec<(String, Trace)>,
+    },
+    Query {
+        query: String,
+        /// The SQL statement that graph-node ran for this field, which
+        /// older versions of graph-node do not include
+        sql: Option<String>,
+        /// The values of the bind parameters of `sql`
+        params: Vec<String>,
+        elapsed: Duration,
+        /// How long the query waited for a connection and for a permit,
+        /// and how many entities it returned, which not all versions of
+        /// graph-node include
+        conn_wait: Option<Duration>,
+        permit_wait: Option<Duration>,
+        entity_count: Option<usize>,
+        children: Vec<(String, Trace)>,
+    },
+}
+
+impl Trace {
+    fn number_as_millis(entry: &json::Value, key: &str) -> anyhow::Result<Duration> {
+        Self::millis(&entry[key]).ok_or_else(|| anyhow!("Invalid trace: {key} is not a duration"))
+    }
+
+    /// A number of milliseconds, which can have a fraction for times below
+    /// a millisecond. Fractions are kept to the microsecond
+    fn millis(value: &json::Value) -> Option<Duration> {
+        match value.as_u64() {
+            Some(ms) => Some(Duration::from_millis(ms)),
+            None => value
+                .as_f64()
+                .filter(|ms| *ms >= 0.0)
+                .map(|ms| Duration::from_micros((ms * 1000.0).round() as u64)),
+        }
+    }
+
+    /// Parse a trace without knowing which version of `graph-node`
+    /// produced it; `GraphNode::parse_trace` uses the configured version
+    pub fn parse(root: &json::Value) -> anyhow::Result<Self> {
+        Self::parse_from(root, None)
+    }
+
+    /// Parse a trace that `graph-node` in `version` produced, in any of the
+    /// shapes that `trace_format` knows
+    pub fn parse_from(root: &json::Value, version: Option<&str>) -> anyhow::Result<Self> {
+        let root = trace_format::normalize(root, version)?;
+        let root = root.as_ref();
+        let mut children = Vec::new();
+        for (key, value) in root
+            .as_object()
+            .ok_or_else(|| anyhow!("Invalid trace: root is not an object"))?
+        {
+            if value.is_object() {
+                children.push(Self::parse_query(key, value)?);
+            }
+        }
+        Ok(Self::Root {
+            query: root["query"].to_string(),
+            variables: root["variables"].to_string(),
+            query_id: root["query_id"].to_string(),
+            block: root["block"]
+                .as_u64()
+                .ok_or_else(|| anyhow!("Invalid trace: block is not a number"))?
+                as usize,
+            elapsed: Self::number_as_millis(root, "elapsed_ms")?,
+            conn_wait: Self::number_as_millis(root, "conn_wait_ms")?,
+            permit_wait: Self::number_as_millis(root, "permit_wait_ms")?,
+            cache_status: ["cache_status", "cache"]
+                .iter()
+                .find_map(|key| root[key].as_str())
+                .map(str::to_string),
+            children,
+        })
+    }
+
+    /// The fingerprint of the query of the trace, which only the root has
+    pub fn fingerprint(&self) -> Option<String> {
+        let (query, variables) = self.request()?;
+        Some(fingerprint::fingerprint(&query, &variables))
+    }
+
+    /// The query and the variables of the trace, which only the root has
+    pub fn request(&self) -> Option<(String, json::Value)> {
+        match self {
+            Self::Root {
+                query, variables, ..
+            } => {
+                // The root keeps the query and variables as JSON text, and
+                // graph-node puts the variables in as a string with the
+                // JSON for them
+                let query = unquote(query);
+                let variables = match json::from_str(variables).unwrap_or_default() {
+                    json::Value::String(text) => json::from_str(&text).unwrap_or_default(),
+                    variables => variables,
+                };
+                Some((query, variables))
+            }
+            Self::Query { .. } => None,
+        }
+    }
+
+    /// The arguments of the fields of the trace by their path, as
+    /// `field_arguments` finds them in the query
+    pub fn arguments(&self) -> HashMap<String, String> {
+        self.request()
+            .map(|(query, variables)| field_arguments(&query, &variables))
+            .unwrap_or_default()
+    }
+
+    /// A warning when the times of this trace do not come from running the
+    /// query since graph-node answered it from one of its caches
+    pub fn cache_warning(&self) -> Option<&'static str> {
+        match self {
+            Self::Root {
+                cache_status: Some(status),
+                ..
+            } => match status.as_str() {
+                "hit" => Some("graph-node answered this query from its result cache"),
+                "shared" => Some(
+                    "graph-node answered this query with the result of the same query \
+                     that ran at the same time",
+                ),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn parse_query(name: &str, query: &json::Value) -> anyhow::Result<(String, Self)> {
+        let mut children = Vec::new();
+        for (key, value) in query
+            .as_object()
+            .ok_or_else(|| anyhow!("Invalid trace: {name} is not an object"))?
+        {
+            if value.is_object() {
+                children.push(Self::parse_query(key, value)?);
+            }
+        }
+        let elapsed = Self::number_as_millis(query, "elapsed_ms")?;
+        let conn_wait = Self::millis(&query["conn_wait_ms"]);
+        let permit_wait = Self::millis(&query["permit_wait_ms"]);
+        let entity_count = query["entity_count"].as_u64().map(|count| count as usize);
+        // Newer versions of graph-node put the SQL into `sql`, older ones
+        // into `query`
+        let sql = query["sql"]
+            .as_str()
+            .or_else(|| query["query"].as_str())
+            .map(str::to_string);
+        let params = query["params"]
+            .as_array()
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+            .iter()
+            .map(|param| match param.as_str() {
+                Some(param) => param.to_string(),
+                None => param.to_string(),
+            })
+            .collect();
+        let query = Self::Query {
+            query: query.to_string(),
+            sql,
+            params,
+            elapsed,
+            conn_wait,
+            permit_wait,
+            entity_count,
+            children,
+        };
+        Ok((name.to_string(), query))
+    }
+
+    pub fn query_id(&self) -> &str {
+        match self {
+            Self::Root { query_id, .. } => query_id,
+            Self::Query { .. } => "none",
+        }
+    }
+
+    /// The time spent running queries in this node and all its children
+    pub fn query_time(&self) -> Duration {
+        match self {
+            Self::Root { children, .. } => {
+                children.iter().map(|(_, trace)| trace.query_time()).sum()
+            }
+            Self::Query {
+                elapsed, children, ..
+            } => {
+                *elapsed
+                    + children
+                        .iter()
+                        .map(|(_, trace)| trace.query_time())
+                        .sum::<Duration>()
+            }
+        }
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        match self {
+            Self::Root { elapsed, .. } | Self::Query { elapsed, .. } => *elapsed,
+        }
+    }
+
+    /// How long this node waited for a database connection and for a
+    /// permit to run its query, if the trace says
+    pub fn waits(&self) -> (Option<Duration>, Option<Duration>) {
+        match self {
+            Self::Root {
+                conn_wait,
+                permit_wait,
+                ..
+            } => (Some(*conn_wait), Some(*permit_wait)),
+            Self::Query {
+                conn_wait,
+                permit_wait,
+                ..
+            } => (*conn_wait, *permit_wait),
+        }
+    }
+
+    /// The number of entities that this node returned, if the trace says.
+    /// The root does not return entities itself
+    pub fn entity_count(&self) -> Option<usize> {
+        match self {
+            Self::Root { .. } => Some(0),
+            Self::Query { entity_count, .. } => *entity_count,
+        }
+    }
+
+    /// The number of entities that this node and all its children returned
+    pub fn total_entities(&self) -> usize {
+        self.entity_count().unwrap_or_default()
+            + self
+                .children()
+                .iter()
+                .map(|(_, trace)| trace.total_entities())
+                .sum::<usize>()
+    }
+
+    /// Like `waits`, but for this node and all its children
+    pub fn total_waits(&self) -> (Duration, Duration) {
+        let (conn, permit) = self.waits();
+        self.children()
+            .iter()
+            .map(|(_, trace)| trace.total_waits())
+            .fold(
+                (conn.unwrap_or_default(), permit.unwrap_or_default()),
+                |(conn, permit), (c, p)| (conn + c, permit + p),
+            )
+    }
+
+    pub fn children(&self) -> &[(String, Trace)] {
+        match self {
+            Self::Root { children, .. } | Self::Query { children, .. } => children,
+        }
+    }
+
+    /// The child with the given name, i.e., the response key of a field
+    pub fn child(&self, name: &str) -> Option<&Trace> {
+        self.children()
+            .iter()
+            .find(|(child, _)| child == name)
+            .map(|(_, trace)| trace)
+    }
+}
+
+/// Traces serialize to the flat shape that `graph-node` uses, with the
+/// optional parts only when the trace has them, so that parsing the JSON
+/// gives back the same trace
+impl serde::Serialize for Trace {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeMap;
+
+        // `Root` keeps the query and variables as JSON text
+        fn as_json(text: &str) -> json::Value {
+            json::from_str(text).unwrap_or_else(|_| json::Value::String(text.to_string()))
+        }
+
+        let mut map = serializer.serialize_map(None)?;
+        match self {
+            Self::Root {
+                query,
+                variables,
+                query_id,
+                block,
+                elapsed,
+                conn_wait,
+                permit_wait,
+                cache_status,
+                children: _,
+            } => {
+                map.serialize_entry("query", &as_json(query))?;
+                map.serialize_entry("variables", &as_json(variables))?;
+                map.serialize_entry("query_id", &as_json(query_id))?;
+                map.serialize_entry("block", block)?;
+                map.serialize_entry("elapsed_ms", &millis_json(*elapsed))?;
+                map.serialize_entry("conn_wait_ms", &millis_json(*conn_wait))?;
+                map.serialize_entry("permit_wait_ms", &millis_json(*permit_wait))?;
+                if let Some(cache_status) = cache_status {
+                    map.serialize_entry("cache_status", cache_status)?;
+                }
+            }
+            Self::Query {
+                query: _,
+                sql,
+                params,
+                elapsed,
+                conn_wait,
+                permit_wait,
+                entity_count,
+                children: _,
+            } => {
+                if let Some(sql) = sql {
+                    map.serialize_entry("sql", sql)?;
+                }
+                if !params.is_empty() {
+                    map.serialize_entry("params", params)?;
+                }
+                map.serialize_entry("elapsed_ms", &millis_json(*elapsed))?;
+                if let Some(conn_wait) = conn_wait {
+                    map.serialize_entry("conn_wait_ms", &millis_json(*conn_wait))?;
+                }
+                if let Some(permit_wait) = permit_wait {
+                    map.serialize_entry("permit_wait_ms", &millis_json(*permit_wait))?;
+                }
+                if let Some(entity_count) = entity_count {
+                    map.serialize_entry("entity_count", entity_count)?;
+                }
+            }
+        }
+        for (name, child) in self.children() {
+            map.serialize_entry(name, child)?;
+        }
+        map.end()
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct GraphNode {
+    /// The name that `--targets` refers to this endpoint by
+    pub name: Option<String>,
+    url: String,
+    #[serde(rename = "trace-token")]
+    trace_token: String,
+    /// The GraphQL endpoint of the index node API, used to look up the
+    /// current version of a subgraph by name. Without it, the version is
+    /// looked up with a `_meta` query against the subgraph
+    #[serde(rename = "index-node-url")]
+    index_node_url: Option<String>,
+    /// The GraphQL endpoint of the network subgraph, used to find the
+    /// subgraphs for `--all-matching`
+    #[serde(rename = "network-subgraph-url")]
+    network_subgraph_url: Option<String>,
+    /// A token to send in an `Authorization: Bearer` header, e.g., for a
+    /// node behind an authenticating proxy
+    token: Option<String>,
+    /// Further headers to send with every request
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    /// A unix socket that graph-node serves queries on, which is used
+    /// instead of the host and port in `url`
+    socket: Option<String>,
+    /// A command like `kubectl port-forward` that makes graph-node
+    /// reachable at the host and port in `url`. It is started before the
+    /// first request and stopped at the end
+    #[serde(rename = "port-forward")]
+    port_forward: Option<Vec<String>>,
+    /// A response header that carries the version of graph-node, e.g., one
+    /// that a proxy in front of it adds. It is only used without an
+    /// `index-node-url`
+    #[serde(rename = "version-header")]
+    version_header: Option<String>,
+    /// How to address the subgraph that a query is for
+    #[serde(default)]
+    pub routing: Routing,
+    /// The names of the subgraphs whose deployments were looked up by name,
+    /// for routing by name
+    #[serde(skip)]
+    pub names: HashMap<String, String>,
+    /// The version of graph-node, which is recorded in saved outputs
+    #[serde(skip)]
+    pub version: Option<ServerVersion>,
+    #[serde(flatten)]
+    pub http: Http,
+}
+
+/// The build of graph-node that answered the queries
+#[derive(Debug, Clone)]
+pub struct ServerVersion {
+    pub version: String,
+    commit: Option<String>,
+}
+
+impl ServerVersion {
+    /// Add the version to `output`, a JSON object that is saved. The
+    /// values are strings so that traces with them can still be parsed
+    pub fn record(version: Option<&ServerVersion>, output: &mut json::Value) {
+        let (Some(version), Some(output)) = (version, output.as_object_mut()) else {
+            return;
+        };
+        output.insert("graph_node_version".to_string(), json!(version.version));
+        if let Some(commit) = &version.commit {
+            output.insert("graph_node_commit".to_string(), json!(commit));
+        }
+    }
+}
+
+impl std::fmt::Display for ServerVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.commit {
+            Some(commit) => write!(f, "{} ({commit})", self.version),
+            None => write!(f, "{}", self.version),
+        }
+    }
+}
+
+/// How queries are sent to a subgraph
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Routing {
+    /// To `/subgraphs/id/<deployment>`
+    #[default]
+    Id,
+    /// To `/subgraphs/name/<name>`, for query nodes that do not serve
+    /// queries by deployment
+    Name,
+}
+
+/// Whether `deployment` is the IPFS hash of a deployment rather than the
+/// name of a subgraph
+fn is_hash(deployment: &str) -> bool {
+    deployment.len() == 46
+        && deployment.starts_with("Qm")
+        && deployment.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+impl GraphNode {
+    fn query_url(&self, deployment: &str) -> anyhow::Result<Url> {
+        let mut url = Url::parse(&self.url)?;
+        match self.routing {
+            Routing::Id => url.set_path(&format!("/subgraphs/id/{deployment}")),
+            Routing::Name => {
+                let name = match self.names.get(deployment) {
+                    Some(name) => name,
+                    None if !is_hash(deployment) => deployment,
+                    None => {
+                        return Err(anyhow!(
+                            "Queries for {deployment} can not be routed by name since it was \
+                             not given as a subgraph name"
+                        ))
+                    }
+                };
+                url.set_path(&format!("/subgraphs/name/{name}"));
+            }
+        }
+        Ok(url)
+    }
+
+    /// Turn `deployment` into the IPFS hash of a deployment. If it already
+    /// is one, it is returned unchanged; otherwise, it is taken to be the
+    /// name of a subgraph and the hash of its current version is looked up
+    pub async fn resolve(
+        &self,
+        deployment: &str,
+        retry: &Retry,
+        out: &mut dyn std::io::Write,
+    ) -> anyhow::Result<String> {
+        if is_hash(deployment) {
+            return Ok(deployment.to_string());
+        }
+
+        let (url, body, pointer) = match &self.index_node_url {
+            Some(url) => {
+                let body = json!({
+                    "query": "query($name: String!) { indexingStatusForCurrentVersion(subgraphName: $name) { subgraph } }",
+                    "variables": { "name": deployment },
+                });
+                (
+                    Url::parse(url)?,
+                    body,
+                    "/data/indexingStatusForCurrentVersion/subgraph",
+                )
+            }
+            None => {
+                let mut url = Url::parse(&self.url)?;
+                url.set_path(&format!("/subgraphs/name/{deployment}"));
+                let body = json!({ "query": "{ _meta { deployment } }" });
+                (url, body, "/data/_meta/deployment")
+            }
+        };
+        let resp = self
+            .lookup(&url, &body, true, retry, out)
+            .await
+            .map_err(|e| anyhow!("Failed to look up subgraph {deployment}: {e}"))?;
+        let hash = resp
+            .pointer(pointer)
+            .and_then(|hash| hash.as_str())
+            .ok_or_else(|| match resp.get("errors") {
+                Some(errors) => anyhow!("Failed to look up subgraph {deployment}: {errors}"),
+                None => anyhow!("Subgraph {deployment} not found"),
+            })?;
+        writeln!(out, "Subgraph {deployment} is deployment {hash}")?;
+        Ok(hash.to_string())
+    }
+
+    /// The deployments of all versions of the subgraphs whose display name
+    /// matches `glob`, in which `*` matches any text and `?` any single
+    /// character. The subgraphs are looked up in the network subgraph
+    pub async fn matching(
+        &self,
+        glob: &str,
+        retry: &Retry,
+        out: &mut dyn std::io::Write,
+    ) -> anyhow::Result<Vec<String>> {
+        const PAGE_SIZE: usize = 1000;
+        const QUERY: &str = r#"query($last: String!, $text: String!) {
+  subgraphs(first: 1000, orderBy: id, where: { id_gt: $last, metadata_: { displayName_contains_nocase: $text } }) {
+    id
+    metadata { displayName }
+    versions(first: 1000) { subgraphDeployment { ipfsHash } }
+  }
+}"#;
+
+        let url = self.network_subgraph_url.as_ref().ok_or_else(|| {
+            anyhow!("--all-matching needs network-subgraph-url in the [graph-node] section")
+        })?;
+        let url = Url::parse(url)?;
+        let regex = regex::escape(glob).replace(r"\*", ".*").replace(r"\?", ".");
+        let regex = Regex::new(&format!("(?i)^{regex}$"))?;
+        // Let the network subgraph narrow down the candidates with the
+        // longest part of the glob that has no wildcards
+        let text = glob
+            .split(['*', '?'])
+            .max_by_key(|part| part.len())
+            .unwrap_or_default();
+        let mut deployments = Vec::new();
+        let mut last = String::new();
+        loop {
+            let body = json!({
+                "query": QUERY,
+                "variables": { "last": last, "text": text },
+            });
+            let resp = self
+                .lookup(&url, &body, false, retry, out)
+                .await
+                .map_err(|e| anyhow!("Failed to query the network subgraph: {e}"))?;
+            let subgraphs = resp
+                .pointer("/data/subgraphs")
+                .and_then(|subgraphs| subgraphs.as_array())
+                .ok_or_else(|| anyhow!("Network subgraph query failed: {}", resp["errors"]))?;
+            for subgraph in subgraphs {
+                let name = subgraph["metadata"]["displayName"].as_str();
+                if !name.is_some_and(|name| regex.is_match(name)) {
+                    continue;
+                }
+                let versions = subgraph["versions"].as_array().map(Vec::as_slice);
+                deployments.extend(versions.unwrap_or_default().iter().filter_map(|version| {
+                    version["subgraphDeployment"]["ipfsHash"]
+                        .as_str()
+                        .map(str::to_string)
+                }));
+            }
+            match subgraphs
+                .last()
+                .and_then(|subgraph| subgraph["id"].as_str())
+            {
+                Some(id) if subgraphs.len() >= PAGE_SIZE => last = id.to_string(),
+                _ => break,
+            }
+        }
+        writeln!(
+            out,
+            "Found {} deployments matching {glob}",
+            deployments.len()
+        )?;
+        Ok(deployments)
+    }
+
+    /// Parse `trace`, which this graph-node returned, in the shape that its
+    /// version produces
+    pub fn parse_trace(&self, trace: &json::Value) -> anyhow::Result<Trace> {
+        let version = self
+            .version
+            .as_ref()
+            .map(|version| version.version.as_str());
+        Trace::parse_from(trace, version)
+    }
+
+    /// Look up the version of graph-node with the index node API, or read
+    /// it from `version-header` in the response to a request for `url`
+    pub async fn lookup_version(
+        &self,
+        retry: &Retry,
+        out: &mut dyn std::io::Write,
+    ) -> anyhow::Result<Option<ServerVersion>> {
+        if let Some(url) = &self.index_node_url {
+            let body = json!({ "query": "{ version { version commit } }" });
+            let resp = self
+                .lookup(&Url::parse(url)?, &body, true, retry, out)
+                .await?;
+            let version = &resp["data"]["version"];
+            let Some(number) = version["version"].as_str() else {
+                return Err(anyhow!("the index node returned {}", resp["errors"]));
+            };
+            return Ok(Some(ServerVersion {
+                version: number.to_string(),
+                commit: version["commit"].as_str().map(str::to_string),
+            }));
+        }
+        let Some(header) = &self.version_header else {
+            return Ok(None);
+        };
+        let client = self.http.client()?;
+        let url = Url::parse(&self.url)?;
+        let resp = retry
+            .send(|| self.authorize(client.get(url.clone())), None, out)
+            .await?;
+        Ok(resp
+            .headers()
+            .get(header)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| ServerVersion {
+                version: value.to_string(),
+                commit: None,
+            }))
+    }
+
+    /// Start a POST request of a JSON body to `url`. Requests to this
+    /// graph-node, as opposed to the network subgraph, get its token and
+    /// headers
+    fn post(&self, client: &reqwest::Client, url: &Url, own: bool) -> reqwest::RequestBuilder {
+        let request = client
+            .post(url.clone())
+            .header("Content-Type", "application/json");
+        if own {
+            self.authorize(request)
+        } else {
+            request
+        }
+    }
+
+    /// Add the token and headers for this graph-node to `request`
+    fn authorize(&self, mut request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+        for (name, value) in &self.headers {
+            request = request.header(name, value);
+        }
+        request
+    }
+
+    /// Describe the request that replays `log_entry` for `--dry-run`, with
+    /// the values of all headers that might be secret left out. Without a
+    /// log entry, the query and variables are left out, too
+    pub fn describe(
+        &self,
+        deployment: &str,
+        log_entry: Option<&LogEntry>,
+    ) -> anyhow::Result<String> {
+        let mut headers = vec!["X-GraphTraceQuery".to_string()];
+        if self.token.is_some() {
+            headers.push("Authorization".to_string());
+        }
+        headers.extend(self.headers.keys().cloned());
+        describe_request(&self.query_url(deployment)?, &headers, log_entry)
+    }
+
+    /// Send the GraphQL request `body` to `url`, which is not a subgraph
+    /// query that should be traced, and return the response. `own` says
+    /// whether `url` belongs to this graph-node
+    async fn lookup(
+        &self,
+        url: &Url,
+        body: &json::Value,
+        own: bool,
+        retry: &Retry,
+        out: &mut dyn std::io::Write,
+    ) -> anyhow::Result<json::Value> {
+        let client = self.http.client()?;
+        let body = body.to_string();
+        let resp = retry
+            .send(
+                || self.post(&client, url, own).body(body.clone()),
+                None,
+                out,
+            )
+            .await?
+            .text()
+            .await?;
+        Ok(json::from_str(&resp)?)
+    }
+
+    pub async fn query(
+        &self,
+        deployment: &str,
+        log_entry: &LogEntry,
+        retry: &Retry,
+        out: &mut dyn std::io::Write,
+    ) -> anyhow::Result<json::Value> {
+        let client = self.http.client()?;
+        self.query_with(&client, deployment, log_entry, retry, out)
+            .await
+            .map(|(resp, _)| resp)
+    }
+
+    /// Like `query`, but also measure how long each phase of the request
+    /// took
+    pub async fn query_timed(
+        &self,
+        deployment: &str,
+        log_entry: &LogEntry,
+        retry: &Retry,
+        out: &mut dyn std::io::Write,
+    ) -> anyhow::Result<(json::Value, HttpTiming)> {
+        let url = self.query_url(deployment)?;
+        let mut connection = HttpTiming::default();
+        // Through a proxy, the probe would measure the wrong connection
+        if self.http.proxy.is_none() {
+            if let Err(e) = connection.probe(&url, &self.http.tls).await {
+                writeln!(out, "Failed to measure the connection to graph-node: {e}")?;
+            }
+        }
+        let client = self.http.client()?;
+        let (resp, timing) = self
+            .query_with(&client, deployment, log_entry, retry, out)
+            .await?;
+        let timing = HttpTiming {
+            first_byte: timing.first_byte,
+            transfer: timing.transfer,
+            request_id: timing.request_id,
+            ..connection
+        };
+        Ok((resp, timing))
+    }
+
+    /// Like `query`, but with a client that can be reused so that repeated
+    /// queries do not have to open a new connection each time. Also returns
+    /// how long it took until the response started and until it was
+    /// complete
+    pub async fn query_with(
+        &self,
+        client: &reqwest::Client,
+        deployment: &str,
+        log_entry: &LogEntry,
+        retry: &Retry,
+        out: &mut dyn std::io::Write,
+    ) -> anyhow::Result<(json::Value, HttpTiming)> {
+        let url = self.query_url(deployment)?;
+        let body = log_entry.request_body().to_string();
+        let request = || {
+            self.post(client, &url, true)
+                .header("X-GraphTraceQuery", &self.trace_token)
+                .body(body.clone())
+        };
+        send_query("graph-node", request, retry, out).await
+    }
+}
+
+/// What a target returned for a replayed query
+pub struct Replay {
+    /// The response, with the trace if the target returned one
+    pub output: json::Value,
+    pub trace: Option<Trace>,
+    pub timing: HttpTiming,
+}
+
+/// Where `compare --targets` replays a query
+pub enum Target<'a> {
+    GraphNode(&'a GraphNode),
+    Gateway(&'a Gateway),
+}
+
+impl Target<'_> {
+    /// Replay `log_entry`. Only graph-node returns a trace
+    pub async fn query(
+        &self,
+        deployment: &str,
+        log_entry: &LogEntry,
+        retry: &Retry,
+        out: &mut dyn std::io::Write,
+    ) -> anyhow::Result<(json::Value, HttpTiming)> {
+        match self {
+            Target::GraphNode(graph_node) => {
+                let client = graph_node.http.client()?;
+                graph_node
+                    .query_with(&client, deployment, log_entry, retry, out)
+                    .await
+            }
+            Target::Gateway(gateway) => gateway.query(deployment, log_entry, retry, out).await,
+        }
+    }
+
+    /// Replay `log_entry` and parse the trace that graph-node returns
+    pub async fn replay(
+        &self,
+        deployment: &str,
+        log_entry: &LogEntry,
+        retry: &Retry,
+        out: &mut dyn std::io::Write,
+    ) -> anyhow::Result<Replay> {
+        let (output, timing) = self.query(deployment, log_entry, retry, out).await?;
+        let trace = match self {
+            Target::GraphNode(graph_node) => Some(graph_node.parse_trace(&output["trace"])?),
+            Target::Gateway(_) => None,
+        };
+        Ok(Replay {
+            output,
+            trace,
+            timing,
+        })
+    }
+
+    pub fn describe(
+        &self,
+        deployment: &str,
+        log_entry: Option<&LogEntry>,
+    ) -> anyhow::Result<String> {
+        match self {
+            Target::GraphNode(graph_node) => graph_node.describe(deployment, log_entry),
+            Target::Gateway(gateway) => gateway.describe(deployment, log_entry),
+        }
+    }
+}
+
+/// Describe the request that replays `log_entry` by POSTing it to `url`
+/// with the values of `headers` redacted. Without a log entry, the query
+/// and variables are left out
+fn describe_request(
+    url: &Url,
+    headers: &[String],
+    log_entry: Option<&LogEntry>,
+) -> anyhow::Result<String> {
+    let mut lines = vec![
+        format!("POST {url}"),
+        "Content-Type: application/json".to_string(),
+        "X-Request-Id: <random id for each replay>".to_string(),
+    ];
+    lines.extend(headers.iter().map(|name| format!("{name}: <redacted>")));
+    let body = match log_entry {
+        Some(log_entry) => log_entry.request_body(),
+        None => json!({
+            "query": "<query from the query log>",
+            "variables": "<variables from the query log>",
+        }),
+    };
+    lines.push(String::new());
+    lines.push(json::to_string_pretty(&body)?);
+    Ok(lines.join("\n"))
+}
+
+/// How much of a response to receive between progress messages
+const PROGRESS_BYTES: usize = 10_000_000;
+
+/// Send the GraphQL request that `request` builds to `endpoint` and return
+/// the response and how long it took. Responses are compressed if the
+/// endpoint supports that
+async fn send_query(
+    endpoint: &str,
+    request: impl Fn() -> reqwest::RequestBuilder,
+    retry: &Retry,
+    out: &mut dyn std::io::Write,
+) -> anyhow::Result<(json::Value, HttpTiming)> {
+    // Retries are sent with the same id since they are for the same replay
+    let request_id = format!("{:032x}", rand::random::<u128>());
+    writeln!(out, "Sending {endpoint} request {request_id}")?;
+    let request = || request().header("X-Request-Id", &request_id);
+    let mut attempt = 1;
+    loop {
+        match send_query_once(endpoint, &request, &request_id, retry, out).await {
+            Err(e) if attempt < retry.attempts && GraphQlErrors::is_store_error(&e) => {
+                let backoff = retry.backoff(attempt);
+                writeln!(
+                    out,
+                    "Attempt {attempt} failed: {e}; retrying in {}ms",
+                    backoff.as_millis()
+                )?;
+                tokio::time::sleep(backoff).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Send the query once. Store errors are returned as `GraphQlErrors` so
+/// that `send_query` can retry them
+async fn send_query_once(
+    endpoint: &str,
+    request: impl Fn() -> reqwest::RequestBuilder,
+    request_id: &str,
+    retry: &Retry,
+    out: &mut dyn std::io::Write,
+) -> anyhow::Result<(json::Value, HttpTiming)> {
+    let start = Instant::now();
+    let mut resp = retry
+        .send(request, None, out)
+        .await
+        .map_err(|e| anyhow!("Failed to send {endpoint} query: {}", e))?;
+    let first_byte = start.elapsed();
+    // Responses for queries with lots of entities can be many megabytes
+    // large; read them piece by piece to show progress
+    let mut body = Vec::new();
+    let mut reported = 0;
+    while let Some(chunk) = resp
+        .chunk()
+        .await
+        .map_err(|e| anyhow!("Failed to get {endpoint} response: {}", e))?
+    {
+        body.extend_from_slice(&chunk);
+        if body.len() - reported >= PROGRESS_BYTES {
+            reported = body.len();
+            writeln!(out, "Received {} MB", reported / 1_000_000)?;
+        }
+    }
+    let timing = HttpTiming {
+        first_byte,
+        transfer: start.elapsed() - first_byte,
+        request_id: request_id.to_string(),
+        ..HttpTiming::default()
+    };
+    let resp: json::Value = json::from_slice(&body)
+        .map_err(|e| anyhow!("Failed to parse {endpoint} response: {}", e))?;
+    if resp["data"].is_null() {
+        if let Some(errors) = resp["errors"].as_array() {
+            return Err(GraphQlErrors::new(endpoint, request_id, errors).into());
+        }
+    }
+    Ok((resp, timing))
+}
+
+/// The `errors` that an endpoint returned instead of any data, e.g.,
+/// because the query timed out or was too expensive
+#[derive(Debug)]
+pub struct GraphQlErrors {
+    endpoint: String,
+    request_id: String,
+    messages: Vec<String>,
+}
+
+impl GraphQlErrors {
+    fn new(endpoint: &str, request_id: &str, errors: &[json::Value]) -> Self {
+        let messages = errors
+            .iter()
+            .map(|error| match error["message"].as_str() {
+                Some(message) => message.to_string(),
+                None => error.to_string(),
+            })
+            .collect();
+        Self {
+            endpoint: endpoint.to_string(),
+            request_id: request_id.to_string(),
+            messages,
+        }
+    }
+
+    fn any_message(e: &anyhow::Error, patterns: &[&str]) -> bool {
+        e.downcast_ref::<GraphQlErrors>().is_some_and(|errors| {
+            errors.messages.iter().any(|message| {
+                let message = message.to_lowercase();
+                patterns.iter().any(|pattern| message.contains(pattern))
+            })
+        })
+    }
+
+    /// Whether `e` is a store error, which `graph-node` returns when its
+    /// database had a problem, and which often goes away when the query
+    /// is retried
+    fn is_store_error(e: &anyhow::Error) -> bool {
+        Self::any_message(e, &["store error"])
+    }
+
+    /// Whether `e` says that the block the query asked for is not
+    /// available anymore because the deployment was pruned
+    pub fn is_block_unavailable(e: &anyhow::Error) -> bool {
+        Self::any_message(e, &["block not found", "pruned"])
+    }
+}
+
+impl std::fmt::Display for GraphQlErrors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} returned errors for request {}",
+            self.endpoint, self.request_id
+        )?;
+        for message in &self.messages {
+            write!(f, "\n  {message}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for GraphQlErrors {}
+
+/// How long the phases of a request to graph-node took. The HTTP client
+/// does not report how long it took to set up the connection; that is
+/// measured by opening a separate connection to the same server
+#[derive(Debug, Default)]
+pub struct HttpTiming {
+    /// How long it took to look up the host, connect to it, and do the TLS
+    /// handshake, if that could be measured
+    pub dns: Option<Duration>,
+    pub connect: Option<Duration>,
+    pub tls: Option<Duration>,
+    /// From sending the request until the response started, including
+    /// setting up the connection and retries
+    pub first_byte: Duration,
+    /// From the first byte until the end of the response
+    pub transfer: Duration,
+    /// The `X-Request-Id` that the request was sent with, so that it can be
+    /// found in the logs of the server
+    pub request_id: String,
+}
+
+impl HttpTiming {
+    /// Measure how long it takes to look up the host of `url`, connect to
+    /// it, and, for HTTPS, to do the TLS handshake
+    async fn probe(&mut self, url: &Url, tls: &Tls) -> anyhow::Result<()> {
+        let host = url.host_str().ok_or_else(|| anyhow!("{url} has no host"))?;
+        let port = url
+            .port_or_known_default()
+            .ok_or_else(|| anyhow!("{url} has no port"))?;
+
+        let start = Instant::now();
+        let addr = tokio::net::lookup_host((host, port))
+            .await?
+            .next()
+            .ok_or_else(|| anyhow!("{host} has no address"))?;
+        self.dns = Some(start.elapsed());
+
+        let start = Instant::now();
+        let stream = tokio::net::TcpStream::connect(addr).await?;
+        self.connect = Some(start.elapsed());
+
+        if url.scheme() == "https" {
+            let connector = tokio_native_tls::TlsConnector::from(tls.native()?);
+            let start = Instant::now();
+            connector.connect(host, stream).await?;
+            self.tls = Some(start.elapsed());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Output {
+    pub trace: Option<String>,
+    pub data: Option<String>,
+    pub query: Option<String>,
+    pub variables: Option<String>,
+}
+
+/// A config section that can either be given once as a table or several
+/// times as an array of tables
+#[derive(Debug)]
+pub enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+impl<'de, T: serde::de::DeserializeOwned> serde::Deserialize<'de> for OneOrMany<T> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        // Decide based on the shape of the value so that errors in the
+        // section itself are reported as such
+        let value = toml::Value::deserialize(deserializer)?;
+        let result = match value {
+            toml::Value::Array(_) => Vec::<T>::deserialize(value).map(OneOrMany::Many),
+            _ => T::deserialize(value).map(OneOrMany::One),
+        };
+        result.map_err(serde::de::Error::custom)
+    }
+}
+
+impl<T> OneOrMany<T> {
+    pub fn as_slice(&self) -> &[T] {
+        match self {
+            OneOrMany::One(one) => std::slice::from_ref(one),
+            OneOrMany::Many(many) => many,
+        }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [T] {
+        match self {
+            OneOrMany::One(one) => std::slice::from_mut(one),
+            OneOrMany::Many(many) => many,
+        }
+    }
+}
+
+/// Spaces out requests so that there are at most a given number of them per
+/// second. Deserializes from that number
+#[derive(Debug, Default)]
+struct RateLimit {
+    interval: Option<Duration>,
+    /// The earliest time at which the next request may be sent
+    next: std::sync::Mutex<Option<Instant>>,
+}
+
+impl<'de> serde::Deserialize<'de> for RateLimit {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let per_second = f64::deserialize(deserializer)?;
+        if !per_second.is_finite() || per_second <= 0.0 {
+            return Err(serde::de::Error::custom(
+                "the rate limit must be a positive number of requests per second",
+            ));
+        }
+        Ok(RateLimit {
+            interval: Some(Duration::from_secs_f64(1.0 / per_second)),
+            next: Default::default(),
+        })
+    }
+}
+
+impl RateLimit {
+    async fn wait(&self) {
+        let Some(interval) = self.interval else {
+            return;
+        };
+        let wait = {
+            let mut next = self.next.lock().unwrap();
+            let now = Instant::now();
+            let start = next.map_or(now, |next| next.max(now));
+            *next = Some(start + interval);
+            start - now
+        };
+        tokio::time::sleep(wait).await;
+    }
+}
+
+/// How to retry HTTP requests that fail because of a transient problem like
+/// a connection error or a 502 from a gateway
+#[derive(Deserialize, Debug)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct Retry {
+    /// How often to try a request in total
+    pub attempts: usize,
+    /// How long to wait before the first retry; the wait doubles for each
+    /// further retry
+    pub backoff_ms: u64,
+    /// The longest time to wait between two attempts
+    pub max_backoff_ms: u64,
+    /// Wait a random time between half and all of the backoff so that
+    /// concurrent retries do not all hit the server at the same time
+    pub jitter: bool,
+    /// Replay a query whose block was pinned with `--pin-block` against
+    /// the latest block if the deployment no longer has the pinned block
+    pub unpin_pruned: bool,
+    /// The timeout from `--timeout`, which overrides the ones of the
+    /// endpoints
+    #[serde(skip)]
+    pub timeout: Option<Duration>,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Retry {
+            attempts: 3,
+            backoff_ms: 500,
+            max_backoff_ms: 10_000,
+            jitter: true,
+            unpin_pruned: false,
+            timeout: None,
+        }
+    }
+}
+
+impl Retry {
+    fn backoff(&self, attempt: usize) -> Duration {
+        let factor = 1u64 << (attempt - 1).min(32);
+        let backoff = self
+            .backoff_ms
+            .saturating_mul(factor)
+            .min(self.max_backoff_ms);
+        let backoff = if self.jitter {
+            rand::random_range(backoff / 2..=backoff)
+        } else {
+            backoff
+        };
+        Duration::from_millis(backoff)
+    }
+
+    /// Send the request that `request` builds, and build and send it again
+    /// if it fails in a way that might go away by itself
+    async fn send(
+        &self,
+        request: impl Fn() -> reqwest::RequestBuilder,
+        rate_limit: Option<&RateLimit>,
+        out: &mut dyn std::io::Write,
+    ) -> anyhow::Result<reqwest::Response> {
+        let mut attempt = 1;
+        loop {
+            if let Some(rate_limit) = rate_limit {
+                rate_limit.wait().await;
+            }
+            let mut request = request();
+            if let Some(timeout) = self.timeout {
+                request = request.timeout(timeout);
+            }
+            let result = request.send().await;
+            let problem = match &result {
+                Ok(resp) if resp.status().is_server_error() => Some(resp.status().to_string()),
+                Ok(resp) if resp.status() == reqwest::StatusCode::TOO_MANY_REQUESTS => {
+                    Some(resp.status().to_string())
+                }
+                Ok(_) => None,
+                Err(e) if e.is_connect() || e.is_timeout() || e.is_request() => Some(e.to_string()),
+                Err(_) => None,
+            };
+            match problem {
+                Some(problem) if attempt < self.attempts => {
+                    let backoff = self.backoff(attempt);
+                    writeln!(
+                        out,
+                        "Attempt {attempt} failed: {problem}; retrying in {}ms",
+                        backoff.as_millis()
+                    )?;
+                    tokio::time::sleep(backoff).await;
+                    attempt += 1;
+                }
+                _ => return Ok(result?),
+            }
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Config {
+    pub loki: Option<OneOrMany<Loki>>,
+    pub elasticsearch: Option<Elasticsearch>,
+    pub cloudwatch: Option<CloudWatch>,
+    pub gcp: Option<Gcp>,
+    pub k8s: Option<Kubernetes>,
+    /// The first one is used unless `--targets` selects others
+    #[serde(rename = "graph-node")]
+    pub graph_nodes: OneOrMany<GraphNode>,
+    #[serde(rename = "local-node")]
+    pub local_node: Option<LocalNode>,
+    pub gateway: Option<Gateway>,
+    #[serde(rename = "persisted-queries")]
+    pub persisted_queries: Option<PersistedQueries>,
+    pub otlp: Option<Otlp>,
+    pub tempo: Option<Tempo>,
+    pub zipkin: Option<Zipkin>,
+    pub output: Option<Output>,
+    #[serde(default)]
+    pub retry: Retry,
+    /// How to print traces, from the command line
+    #[serde(skip)]
+    pub display: DisplayOpts,
+}
+
+impl Config {
+    pub fn load(file: &str) -> anyhow::Result<Config> {
+        let config = std::fs::read_to_string(file)?;
+        let config: Config = toml::from_str(&config)?;
+        if config.graph_nodes.as_slice().is_empty() {
+            return Err(anyhow!("The configuration has no [graph-node] section"));
+        }
+        Ok(config)
+    }
+
+    /// The graph-node endpoint that queries are replayed against
+    pub fn graph_node(&self) -> &GraphNode {
+        &self.graph_nodes.as_slice()[0]
+    }
+
+    /// Make the graph-node endpoint with the given name the one that
+    /// queries are replayed against
+    pub fn select(&mut self, name: &str) -> anyhow::Result<()> {
+        let graph_nodes = self.graph_nodes.as_mut_slice();
+        match graph_nodes
+            .iter()
+            .position(|graph_node| graph_node.name.as_deref() == Some(name))
+        {
+            Some(index) => {
+                graph_nodes[..=index].rotate_right(1);
+                Ok(())
+            }
+            None => self.target(name).and_then(|_| {
+                Err(anyhow!(
+                    "Queries can only be traced with a graph-node endpoint, not with {name}"
+                ))
+            }),
+        }
+    }
+
+    /// The graph-node endpoint with the given name, or the gateway for
+    /// `gateway` unless a graph-node endpoint has that name
+    pub fn target(&self, name: &str) -> anyhow::Result<Target<'_>> {
+        let graph_nodes = self.graph_nodes.as_slice();
+        let graph_node = graph_nodes
+            .iter()
+            .find(|graph_node| graph_node.name.as_deref() == Some(name));
+        match (graph_node, &self.gateway) {
+            (Some(graph_node), _) => Ok(Target::GraphNode(graph_node)),
+            (None, Some(gateway)) if name == "gateway" => Ok(Target::Gateway(gateway)),
+            (None, _) => {
+                let mut names: Vec<_> = graph_nodes
+                    .iter()
+                    .filter_map(|graph_node| graph_node.name.as_deref())
+                    .collect();
+                if self.gateway.is_some() {
+                    names.push("gateway");
+                }
+                Err(anyhow!(
+                    "There is no endpoint named {name}; the configured ones are: {}",
+                    names.join(", ")
+                ))
+            }
+        }
+    }
+
+    /// All configured Loki clusters, in the order in which they should be
+    /// queried
+    pub fn lokis(&self) -> &[Loki] {
+        self.loki
+            .as_ref()
+            .map(OneOrMany::as_slice)
+            .unwrap_or_default()
+    }
+
+    /// All configured log sources, in the order in which they should be
+    /// queried: the Loki clusters first, then Elasticsearch, CloudWatch,
+    /// Google Cloud Logging, and the pods in Kubernetes
+    pub fn sources(&self) -> Vec<&dyn LogSource> {
+        let mut sources: Vec<&dyn LogSource> = Vec::new();
+        sources.extend(self.lokis().iter().map(|loki| loki as &dyn LogSource));
+        if let Some(es) = &self.elasticsearch {
+            sources.push(es);
+        }
+        if let Some(cloudwatch) = &self.cloudwatch {
+            sources.push(cloudwatch);
+        }
+        if let Some(gcp) = &self.gcp {
+            sources.push(gcp);
+        }
+        if let Some(k8s) = &self.k8s {
+            sources.push(k8s);
+        }
+        sources
+    }
+}
+
+/// Add the fingerprint of its query to `trace`, a trace as `graph-node`
+/// returns it that is about to be saved
+pub fn record_fingerprint(trace: &mut json::Value) {
+    let query = trace["query"].as_str().unwrap_or_default();
+    let fingerprint = fingerprint::fingerprint(query, &trace["variables"]);
+    if let Some(trace) = trace.as_object_mut() {
+        trace.insert("query_fingerprint".to_string(), json!(fingerprint));
+    }
+}
+
+/// Render `trace` as a flame graph in which the width of each field is the
+/// time spent in its query and those of the fields below it, and save it
+/// as an SVG in `path`
+pub fn save_flamegraph(trace: &Trace, path: &str) -> anyhow::Result<()> {
+    fn fold(stack: &str, trace: &Trace, lines: &mut Vec<String>) {
+        for (name, child) in trace.children() {
+            let stack = format!("{stack};{name}");
+            lines.push(format!("{stack} {}", child.elapsed().as_millis()));
+            fold(&stack, child, lines);
+        }
+    }
+
+    if trace.elapsed().is_zero() {
+        return Err(anyhow!(
+            "The query took no time and can not be shown as a flame graph"
+        ));
+    }
+    // The root gets the time that was not spent in queries
+    let other = trace.elapsed().saturating_sub(trace.query_time());
+    let mut lines = vec![format!("root {}", other.as_millis())];
+    fold("root", trace, &mut lines);
+    let mut options = inferno::flamegraph::Options::default();
+    options.title = format!("Query {}", trace.query_id().trim_matches('"'));
+    options.count_name = "ms".to_string();
+    let f = File::create(path).map_err(|e| anyhow!("Failed to create {path}: {e}"))?;
+    inferno::flamegraph::from_lines(&mut options, lines.iter().map(String::as_str), f)
+        .map_err(|e| anyhow!("Failed to write flame graph to {path}: {e}"))
+}
+
+/// A node of a trace placed on a timeline in milliseconds
+struct Span<'a> {
+    name: &'a str,
+    trace: &'a Trace,
+    depth: usize,
+    start: f64,
+    end: f64,
+}
+
+/// Lay out the nodes of `trace` on a timeline, in the order in which they
+/// start. The trace only says how long each field took, not when it
+/// started, so the fields are laid out one after the other, each followed
+/// by the fields below it
+fn spans(trace: &Trace) -> Vec<Span<'_>> {
+    fn lay_out<'a>(
+        name: &'a str,
+        trace: &'a Trace,
+        depth: usize,
+        start: f64,
+        spans: &mut Vec<Span<'a>>,
+    ) -> f64 {
+        let index = spans.len();
+        spans.push(Span {
+            name,
+            trace,
+            depth,
+            start,
+            end: start,
+        });
+        let mut end = match trace {
+            Trace::Root { .. } => start,
+            Trace::Query { elapsed, .. } => start + elapsed.as_secs_f64() * 1000.0,
+        };
+        for (name, child) in trace.children() {
+            end = lay_out(name, child, depth + 1, end, spans);
+        }
+        // The root also includes the time that was not spent in queries
+        if let Trace::Root { elapsed, .. } = trace {
+            end = end.max(elapsed.as_secs_f64() * 1000.0);
+        }
+        spans[index].end = end;
+        end
+    }
+
+    let mut spans = Vec::new();
+    lay_out("root", trace, 0, 0.0, &mut spans);
+    spans
+}
+
+/// Turn `trace` into a profile in the file format of speedscope
+pub fn speedscope_profile(trace: &Trace) -> json::Value {
+    let spans = spans(trace);
+    let mut events = Vec::new();
+    // The spans that are open, innermost last
+    let mut open: Vec<(usize, &Span)> = Vec::new();
+    for (frame, span) in spans.iter().enumerate() {
+        while let Some((frame, outer)) = open.pop_if(|(_, outer)| outer.depth >= span.depth) {
+            events.push(json!({ "type": "C", "frame": frame, "at": outer.end }));
+        }
+        events.push(json!({ "type": "O", "frame": frame, "at": span.start }));
+        open.push((frame, span));
+    }
+    while let Some((frame, outer)) = open.pop() {
+        events.push(json!({ "type": "C", "frame": frame, "at": outer.end }));
+    }
+    let frames: Vec<_> = spans
+        .iter()
+        .map(|span| json!({ "name": span.name }))
+        .collect();
+    let name = format!("Query {}", trace.query_id().trim_matches('"'));
+    json!({
+        "$schema": "https://www.speedscope.app/file-format-schema.json",
+        "name": name,
+        "exporter": format!("qtrace {}", env!("CARGO_PKG_VERSION")),
+        "shared": { "frames": frames },
+        "profiles": [{
+            "type": "evented",
+            "name": name,
+            "unit": "milliseconds",
+            "startValue": 0.0,
+            "endValue": spans[0].end,
+            "events": events,
+        }],
+    })
+}
+
+/// Summarize `trace` for scripts: the timings of the whole request, a
+/// record for each field with the path of response keys that leads to it,
+/// and the findings about it
+pub fn trace_summary(trace: &Trace, display: &DisplayOpts) -> json::Value {
+    fn add_fields(
+        path: &str,
+        trace: &Trace,
+        arguments: &HashMap<String, String>,
+        fields: &mut Vec<json::Value>,
+    ) {
+        for (name, child) in trace.children() {
+            let path = child_path(path, name);
+            let (conn_wait, permit_wait) = child.waits();
+            fields.push(json!({
+                "path": path,
+                "elapsed_ms": millis_json(child.elapsed()),
+                "total_ms": millis_json(child.query_time()),
+                "conn_wait_ms": conn_wait.map(millis_json),
+                "permit_wait_ms": permit_wait.map(millis_json),
+                "entity_count": child.entity_count(),
+                "arguments": arguments.get(&path),
+            }));
+            add_fields(&path, child, arguments, fields);
+        }
+    }
+
+    let mut fields = Vec::new();
+    add_fields("", trace, &trace.arguments(), &mut fields);
+    let findings: Vec<_> = findings::analyze(trace, display)
+        .into_iter()
+        .map(|finding| json!({ "rule": finding.rule, "message": finding.message }))
+        .collect();
+    let elapsed = trace.elapsed();
+    let query = trace.query_time();
+    let (conn_wait, permit_wait) = trace.total_waits();
+    let (block, cache_status) = match trace {
+        Trace::Root {
+            block,
+            cache_status,
+            ..
+        } => (Some(*block), cache_status.as_deref()),
+        Trace::Query { .. } => (None, None),
+    };
+    json!({
+        "query_id": trace.query_id().trim_matches('"'),
+        "fingerprint": trace.fingerprint(),
+        "block": block,
+        "cache_status": cache_status,
+        "elapsed_ms": millis_json(elapsed),
+        "query_ms": millis_json(query),
+        "other_ms": millis_json(elapsed.saturating_sub(query)),
+        "conn_wait_ms": millis_json(conn_wait),
+        "permit_wait_ms": millis_json(permit_wait),
+        "entity_count": trace.total_entities(),
+        "fields": fields,
+        "findings": findings,
+    })
+}
+
+/// `time` as a number of milliseconds for JSON output, which only has a
+/// fraction if the time is not a whole number of milliseconds
+fn millis_json(time: Duration) -> json::Value {
+    if time.subsec_nanos().is_multiple_of(1_000_000) {
+        json!(time.as_millis() as u64)
+    } else {
+        json!(time.as_secs_f64() * 1000.0)
+    }
+}
+
+/// Turn `trace` into a Graphviz graph with a node for each field that has
+/// its times and entities, and edges whose width and label give the share
+/// of the request that the field and the fields below it took. The
+/// findings go into a note next to the graph
+pub fn dot_graph(trace: &Trace, display: &DisplayOpts) -> String {
+    fn add_nodes(
+        id: usize,
+        trace: &Trace,
+        display: &DisplayOpts,
+        total: Duration,
+        dot: &mut String,
+        next: &mut usize,
+    ) {
+        for (name, child) in trace.children() {
+            *next += 1;
+            let child_id = *next;
+            let label = format!(
+                "{name}\\n{} self, {} total\\n{} entities",
+                display.time(child.elapsed()),
+                display.time(child.query_time()),
+                or_dash(child.entity_count())
+            );
+            let share = percent_of(child.query_time(), total);
+            dot.push_str(&format!(
+                "  n{child_id} [label=\"{}\"];\n",
+                label.replace('"', "\\\"")
+            ));
+            dot.push_str(&format!(
+                "  n{id} -> n{child_id} [label=\"{share:.1}%\", penwidth={:.1}];\n",
+                1.0 + share / 10.0
+            ));
+            add_nodes(child_id, child, display, total, dot, next);
+        }
+    }
+
+    let elapsed = trace.elapsed();
+    let mut dot = String::from("digraph trace {\n  node [shape=box];\n");
+    dot.push_str(&format!(
+        "  n0 [label=\"root\\n{}\"];\n",
+        display.time(elapsed)
+    ));
+    add_nodes(0, trace, display, elapsed, &mut dot, &mut 0);
+    let findings = findings::analyze(trace, display);
+    if !findings.is_empty() {
+        let mut label = String::from("Findings\\l");
+        for finding in &findings {
+            label.push_str(&format!("{}: {}\\l", finding.title, finding.message));
+        }
+        dot.push_str(&format!(
+            "  findings [shape=note, label=\"{}\"];\n",
+            label.replace('"', "\\\"")
+        ));
+    }
+    dot.push_str("}\n");
+    dot
+}
+
+/// Turn `trace` into a Mermaid flowchart like `dot_graph`, wrapped in a
+/// Markdown code block so that it can be pasted into issues as it is. The
+/// findings follow the code block as a Markdown list
+pub fn mermaid_flowchart(trace: &Trace, display: &DisplayOpts) -> String {
+    fn add_nodes(
+        id: usize,
+        trace: &Trace,
+        display: &DisplayOpts,
+        total: Duration,
+        chart: &mut String,
+        next: &mut usize,
+    ) {
+        for (name, child) in trace.children() {
+            *next += 1;
+            let child_id = *next;
+            let label = format!(
+                "{}<br/>{} self, {} total<br/>{} entities",
+                name.replace('"', "#quot;"),
+                display.time(child.elapsed()),
+                display.time(child.query_time()),
+                or_dash(child.entity_count())
+            );
+            chart.push_str(&format!("  n{child_id}[\"{label}\"]\n"));
+            chart.push_str(&format!(
+                "  n{id} -->|{:.1}%| n{child_id}\n",
+                percent_of(child.query_time(), total)
+            ));
+            add_nodes(child_id, child, display, total, chart, next);
+        }
+    }
+
+    let elapsed = trace.elapsed();
+    let mut chart = String::from("```mermaid\nflowchart TD\n");
+    chart.push_str(&format!("  n0[\"root<br/>{}\"]\n", display.time(elapsed)));
+    add_nodes(0, trace, display, elapsed, &mut chart, &mut 0);
+    chart.push_str("```\n");
+    let findings = findings::analyze(trace, display);
+    if !findings.is_empty() {
+        chart.push_str("\n**Findings**\n\n");
+        for finding in &findings {
+            chart.push_str(&format!("- {}: {}\n", finding.title, finding.message));
+        }
+    }
+    chart
+}
+
+/// Turn `trace` into the trace event format that `chrome://tracing` and
+/// the Perfetto UI load, with a complete event for each node
+pub fn chrome_trace(trace: &Trace) -> json::Value {
+    let events: Vec<_> = spans(trace)
+        .iter()
+        .map(|span| {
+            let args = match span.trace {
+                Trace::Root { block, .. } => json!({
+                    "query_id": trace.query_id().trim_matches('"'),
+                    "block": block,
+                }),
+                Trace::Query {
+                    entity_count, sql, ..
+                } => json!({ "entity_count": entity_count, "sql": sql }),
+            };
+            // Timestamps are in microseconds
+            json!({
+                "name": span.name,
+                "cat": if span.depth == 0 { "request" } else { "query" },
+                "ph": "X",
+                "ts": span.start * 1000.0,
+                "dur": (span.end - span.start) * 1000.0,
+                "pid": 1,
+                "tid": 1,
+                "args": args,
+            })
+        })
+        .collect();
+    json!({ "traceEvents": events, "displayTimeUnit": "ms" })
+}
+
+/// The path of response keys to the field `name` below the field at
+/// `parent`, which is empty for the root
+pub fn child_path(parent: &str, name: &str) -> String {
+    match parent {
+        "" => name.to_string(),
+        parent => format!("{parent}.{name}"),
+    }
+}
+
+/// The text of a string that the trace stores as JSON
+pub(crate) fn unquote(text: &str) -> String {
+    json::from_str::<String>(text).unwrap_or_else(|_| text.to_string())
+}
+
+/// `text` padded to `width` characters, or cut short with an ellipsis if
+/// it is longer than that
+pub fn fit(text: &str, width: usize) -> String {
+    if text.chars().count() <= width {
+        format!("{text:width$}")
+    } else {
+        let mut text: String = text.chars().take(width.saturating_sub(1)).collect();
+        text.push('…');
+        text
+    }
+}
+
+/// `value`, or `-` if the trace does not have it
+pub fn or_dash<T: std::fmt::Display>(value: Option<T>) -> String {
+    value.map_or_else(|| "-".to_string(), |value| value.to_string())
+}
+
+/// `part` as a percentage of `total`
+pub fn percent_of(part: Duration, total: Duration) -> f64 {
+    if total.is_zero() {
+        0.0
+    } else {
+        100.0 * part.as_secs_f64() / total.as_secs_f64()
+    }
+}
+
+/// The `children` of a node of a trace in the order that `sort` says
+pub fn sorted(children: &[(String, Trace)], sort: TraceSort) -> Vec<&(String, Trace)> {
+    let mut children: Vec<_> = children.iter().collect();
+    match sort {
+        TraceSort::Elapsed => {
+            children.sort_by_key(|(_, trace)| std::cmp::Reverse(trace.query_time()))
+        }
+        TraceSort::Entities => {
+            children.sort_by_key(|(_, trace)| std::cmp::Reverse(trace.entity_count()))
+        }
+        TraceSort::Name => children.sort_by(|(a, _), (b, _)| a.cmp(b)),
+    }
+    children
+}
+
+/// The path of response keys that leads to each field of `trace`, in the
+/// order that `sort` says, together with the field
+pub fn field_paths(trace: &Trace, sort: TraceSort) -> Vec<(String, &Trace)> {
+    fn add<'a>(
+        path: &str,
+        trace: &'a Trace,
+        sort: TraceSort,
+        fields: &mut Vec<(String, &'a Trace)>,
+    ) {
+        for (name, child) in sorted(trace.children(), sort) {
+            let path = child_path(path, name);
+            fields.push((path.clone(), child));
+            add(&path, child, sort, fields);
+        }
+    }
+
+    let mut fields = Vec::new();
+    add("", trace, sort, &mut fields);
+    fields
+}
+
+/// The value below which `p` percent of the sorted `values` fall, using the
+/// nearest rank, or nothing if there are no values
+pub fn percentile(values: &[u64], p: usize) -> Option<u64> {
+    let rank = (values.len() * p).div_ceil(100).max(1);
+    values.get(rank - 1).copied()
+}
+
+/// A summary of the query times of log entries, in milliseconds
+pub struct QueryTimes {
+    pub count: usize,
+    pub min: u64,
+    pub mean: u64,
+    /// The 50th, 90th and 99th percentile
+    pub percentiles: Vec<(usize, u64)>,
+    pub max: u64,
+    /// A histogram with buckets that grow in steps of 1, 2.5 and 5, each
+    /// with its upper bound and the number of entries below it. The last
+    /// bucket includes `max`
+    pub histogram: Vec<(u64, usize)>,
+}
+
+/// Summarize the query times of `log_entries`, if there are any
+pub fn query_times(log_entries: &[LogEntry]) -> Option<QueryTimes> {
+    let mut times: Vec<_> = log_entries.iter().map(|entry| entry.query_time).collect();
+    times.sort_unstable();
+    let &max = times.last()?;
+    let total: u64 = times.iter().sum();
+
+    // The upper bounds of the buckets, the last one includes `max`
+    let mut bounds = Vec::new();
+    let mut scale = 10;
+    while bounds.last().is_none_or(|bound| *bound <= max) {
+        bounds.extend([scale, scale * 5 / 2, scale * 5]);
+        scale *= 10;
+    }
+    while bounds.len() > 1 && bounds[bounds.len() - 2] > max {
+        bounds.pop();
+    }
+    let mut counts = vec![0; bounds.len()];
+    for time in &times {
+        let bucket = bounds.partition_point(|bound| bound <= time);
+        counts[bucket] += 1;
+    }
+    Some(QueryTimes {
+        count: times.len(),
+        min: times[0],
+        mean: total / times.len() as u64,
+        percentiles: [50, 90, 99]
+            .into_iter()
+            .filter_map(|p| Some((p, percentile(&times, p)?)))
+            .collect(),
+        max,
+        histogram: bounds.into_iter().zip(counts).collect(),
+    })
+}
+
+/// The fields of two traces matched up by their path
+pub struct TraceDiff {
+    /// The path and the times of the root and of each field that both
+    /// traces have, in the order of the first trace
+    pub both: Vec<(String, Duration, Duration)>,
+    /// The fields that only the first trace has and those that only the
+    /// second one has, with their time
+    pub only: [Vec<(String, Duration)>; 2],
+}
+
+/// Match up the fields of `first` and `second` by their path. The fields
+/// of each trace are in the order that `sort` gives
+pub fn diff_traces(first: &Trace, second: &Trace, sort: TraceSort) -> TraceDiff {
+    let first_fields = field_paths(first, sort);
+    let second_fields = field_paths(second, sort);
+    let by_path = |fields: &[(String, &Trace)]| -> HashMap<String, Duration> {
+        fields
+            .iter()
+            .map(|(path, field)| (path.clone(), field.elapsed()))
+            .collect()
+    };
+    let (first_times, second_times) = (by_path(&first_fields), by_path(&second_fields));
+
+    let mut both = vec![("root".to_string(), first.elapsed(), second.elapsed())];
+    for (path, field) in &first_fields {
+        if let Some(other) = second_times.get(path) {
+            both.push((path.clone(), field.elapsed(), *other));
+        }
+    }
+    let only = |fields: &[(String, &Trace)], others: &HashMap<String, Duration>| {
+        fields
+            .iter()
+            .filter(|(path, _)| !others.contains_key(path))
+            .map(|(path, field)| (path.clone(), field.elapsed()))
+            .collect()
+    };
+    TraceDiff {
+        both,
+        only: [
+            only(&first_fields, &second_times),
+            only(&second_fields, &first_times),
+        ],
+    }
+}
+
+/// The places where the data and errors in the response `second` differ
+/// from those in the response `first` to the same query, each described by
+/// its path, e.g., `/data/pools[id=0x1]/volume: "2" instead of "1"`
+pub fn response_diffs(first: &json::Value, second: &json::Value) -> Vec<String> {
+    let mut diffs = Vec::new();
+    for key in ["data", "errors"] {
+        diff_data(&format!("/{key}"), &first[key], &second[key], &mut diffs);
+    }
+    diffs
+}
+
+/// Add the places where `second` differs from `first` to `diffs`, each
+/// described by its path. Lists of entities are matched up by their `id`
+/// so that a missing entity does not make all the ones after it differ
+fn diff_data(path: &str, first: &json::Value, second: &json::Value, diffs: &mut Vec<String>) {
+    use json::Value::{Array, Object};
+
+    fn ids(list: &[json::Value]) -> Option<Vec<&str>> {
+        list.iter()
+            .map(|value| value.get("id").and_then(|id| id.as_str()))
+            .collect()
+    }
+
+    match (first, second) {
+        (Object(first), Object(second)) => {
+            for (key, value) in first {
+                match second.get(key) {
+                    Some(other) => diff_data(&format!("{path}/{key}"), value, other, diffs),
+                    None => diffs.push(format!("{path}/{key}: missing")),
+                }
+            }
+            for key in second.keys().filter(|key| !first.contains_key(*key)) {
+                diffs.push(format!("{path}/{key}: extra"));
+            }
+        }
+        (Array(first), Array(second)) => match (ids(first), ids(second)) {
+            (Some(first_ids), Some(second_ids)) => {
+                let by_id: HashMap<_, _> = second_ids.iter().zip(second).collect();
+                for (id, value) in first_ids.iter().zip(first) {
+                    match by_id.get(id) {
+                        Some(other) => diff_data(&format!("{path}[id={id}]"), value, other, diffs),
+                        None => diffs.push(format!("{path}[id={id}]: missing")),
+                    }
+                }
+                let known: HashSet<_> = first_ids.iter().collect();
+                for id in second_ids.iter().filter(|id| !known.contains(id)) {
+                    diffs.push(format!("{path}[id={id}]: extra"));
+                }
+                if first_ids.len() == second_ids.len()
+                    && first_ids != second_ids
+                    && second_ids.iter().all(|id| known.contains(id))
+                {
+                    diffs.push(format!("{path}: different order"));
+                }
+            }
+            _ => {
+                for (i, (value, other)) in first.iter().zip(second).enumerate() {
+                    diff_data(&format!("{path}[{i}]"), value, other, diffs);
+                }
+                if first.len() != second.len() {
+                    diffs.push(format!(
+                        "{path}: {} entries instead of {}",
+                        second.len(),
+                        first.len()
+                    ));
+                }
+            }
+        },
+        _ if first != second => diffs.push(format!("{path}: {second} instead of {first}")),
+        _ => {}
+    }
+}
+
+/// Normalize `query` so that queries that only differ in literal values,
+/// whitespace, commas, or comments have the same shape. Enum values are
+/// kept since they usually change what the query does
+pub fn query_shape(query: &str) -> String {
+    fn is_name(c: char) -> bool {
+        c.is_alphanumeric() || c == '_' || c == '?'
+    }
+
+    let mut shape = String::with_capacity(query.len());
+    for range in tokenize(query) {
+        let token = &query[range];
+        // Strings and numbers are literals
+        let token = if token.starts_with(|c: char| c == '"' || c == '-' || c.is_ascii_digit()) {
+            "?"
+        } else {
+            token
+        };
+        // Only consecutive names need to be separated
+        if shape.ends_with(is_name) && token.starts_with(is_name) {
+            shape.push(' ');
+        }
+        shape.push_str(token);
+    }
+    shape
+}
+
+/// Split `query` into GraphQL tokens, returned as the byte ranges they
+/// occupy. Whitespace, commas, and comments are skipped
+fn tokenize(query: &str) -> Vec<Range<usize>> {
+    let mut tokens = Vec::new();
+    let mut chars = query.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        match c {
+            c if c.is_whitespace() || c == ',' => continue,
+            '#' => {
+                while chars.next_if(|(_, c)| *c != '\n').is_some() {}
+                continue;
+            }
+            '"' => {
+                if chars.next_if(|(_, c)| *c == '"').is_none() {
+                    let mut escaped = false;
+                    for (_, c) in chars.by_ref() {
+                        match c {
+                            _ if escaped => escaped = false,
+                            '\\' => escaped = true,
+                            '"' => break,
+                            _ => {}
+                        }
+                    }
+                } else if chars.next_if(|(_, c)| *c == '"').is_some() {
+                    // A block string, which ends with three quotes
+                    let mut quotes = 0;
+                    for (_, c) in chars.by_ref() {
+                        quotes = if c == '"' { quotes + 1 } else { 0 };
+                        if quotes == 3 {
+                            break;
+                        }
+                    }
+                }
+            }
+            '.' => while chars.next_if(|(_, c)| *c == '.').is_some() {},
+            c if c.is_alphanumeric() || c == '_' || c == '-' => {
+                while chars
+                    .next_if(|(_, c)| c.is_alphanumeric() || matches!(c, '_' | '.' | '-' | '+'))
+                    .is_some()
+                {}
+            }
+            _ => {}
+        }
+        let end = chars.peek().map_or(query.len(), |(end, _)| *end);
+        tokens.push(start..end);
+    }
+    tokens
+}
+
+/// The tokens of a GraphQL document, with helpers for walking over them
+struct Tokens<'a> {
+    query: &'a str,
+    ranges: Vec<Range<usize>>,
+}
+
+impl<'a> Tokens<'a> {
+    fn new(query: &'a str) -> Self {
+        Tokens {
+            query,
+            ranges: tokenize(query),
+        }
+    }
+
+    fn text(&self, i: usize) -> Option<&'a str> {
+        self.ranges.get(i).map(|range| &self.query[range.clone()])
+    }
+
+    /// The text of the tokens from `start` up to, but not including, `end`,
+    /// or `None` if there are no such tokens
+    fn span(&self, start: usize, end: usize) -> Option<&'a str> {
+        let first = self.ranges.get(start)?;
+        let last = self.ranges.get(end.checked_sub(1)?)?;
+        self.query.get(first.start..last.end)
+    }
+
+    /// The index of the token after the brackets that open at `i`
+    fn skip_group(&self, mut i: usize) -> usize {
+        let mut depth = 0;
+        while let Some(token) = self.text(i) {
+            i += 1;
+            match token {
+                "{" | "(" | "[" => depth += 1,
+                "}" | ")" | "]" => {
+                    depth -= 1;
+                    if depth == 0 {
+                        break;
+                    }
+                }
+                _ => {}
+            }
+        }
+        i
+    }
+
+    fn skip_directives(&self, mut i: usize) -> usize {
+        while self.text(i) == Some("@") {
+            i += 2;
+            if self.text(i) == Some("(") {
+                i = self.skip_group(i);
+            }
+        }
+        i
+    }
+
+    /// The index of the token after the fragment spread or inline fragment
+    /// that starts with the `...` at `i`
+    fn skip_fragment(&self, mut i: usize) -> usize {
+        i += 1;
+        match self.text(i) {
+            Some("on") => i += 2,
+            Some("@" | "{") => {}
+            _ => i += 1,
+        }
+        i = self.skip_directives(i);
+        if self.text(i) == Some("{") {
+            i = self.skip_group(i);
+        }
+        i
+    }
+
+    /// The names that follow `prefix`, e.g., `$` for variables, in the
+    /// tokens from `start` up to `end`
+    fn names_after(&self, prefix: &str, start: usize, end: usize) -> Vec<&'a str> {
+        (start..end)
+            .filter(|&i| self.text(i) == Some(prefix))
+            .filter_map(|i| self.text(i + 1))
+            .filter(|name| !matches!(*name, "on" | "{" | "@"))
+            .collect()
+    }
+}
+
+/// Add a `block: { number: <block> }` argument to the top-level fields of
+/// all operations in `query` so that they run against `block`. Fields that
+/// already have a `block` argument, introspection fields, and fragments are
+/// left alone
+fn with_block(query: &str, block: u64) -> String {
+    let tokens = Tokens::new(query);
+    let text = |i: usize| tokens.text(i);
+
+    // The places where the argument needs to be inserted, and how
+    let mut inserts = Vec::new();
+    let mut i = 0;
+    while let Some(token) = text(i) {
+        match token {
+            "fragment" => {
+                while text(i).is_some_and(|token| token != "{") {
+                    i += 1;
+                }
+                i = tokens.skip_group(i);
+            }
+            // Variable definitions, whose defaults can contain braces
+            "(" => i = tokens.skip_group(i),
+            "{" => {
+                i += 1;
+                while let Some(token) = text(i) {
+                    if token == "}" {
+                        i += 1;
+                        break;
+                    }
+                    if token == "..." {
+                        i = tokens.skip_fragment(i);
+                        continue;
+                    }
+                    if text(i + 1) == Some(":") {
+                        // Skip the alias
+                        i += 2;
+                    }
+                    let pin = text(i).is_some_and(|name| !name.starts_with("__"));
+                    let name_end = tokens.ranges.get(i).map_or(query.len(), |range| range.end);
+                    i += 1;
+                    if text(i) == Some("(") {
+                        let end = tokens.skip_group(i);
+                        let mut depth = 0;
+                        let mut has_block = false;
+                        for j in i + 1..end {
+                            match text(j) {
+                                Some("{" | "(" | "[") => depth += 1,
+                                Some("}" | ")" | "]") => depth -= 1,
+                                Some("block") if depth == 0 && text(j + 1) == Some(":") => {
+                                    has_block = true
+                                }
+                                _ => {}
+                            }
+                        }
+                        if pin && !has_block {
+                            let args = format!("block: {{ number: {block} }}, ");
+                            inserts.push((tokens.ranges[i].end, args));
+                        }
+                        i = end;
+                    } else if pin {
+                        inserts.push((name_end, format!("(block: {{ number: {block} }})")));
+                    }
+                    i = tokens.skip_directives(i);
+                    if text(i) == Some("{") {
+                        i = tokens.skip_group(i);
+                    }
+                }
+            }
+            _ => i += 1,
+        }
+    }
+
+    let mut query = query.to_string();
+    for (pos, text) in inserts.into_iter().rev() {
+        query.insert_str(pos, &text);
+    }
+    query
+}
+
+/// The arguments of the fields in `query` by the path of response keys that
+/// leads to each field, e.g., `first: 1000, orderBy: timestamp` for
+/// `pools.swaps`, with the values of `variables` filled in for variables
+/// that are passed as arguments. Fields in fragments get the arguments for
+/// each place where the fragment is spread, and fields without arguments
+/// are left out
+fn field_arguments(query: &str, variables: &json::Value) -> HashMap<String, String> {
+    /// How deeply fragments can be spread into each other, which guards
+    /// against fragments that spread themselves
+    const MAX_FRAGMENT_DEPTH: usize = 32;
+
+    struct Walk<'a> {
+        tokens: Tokens<'a>,
+        variables: &'a json::Value,
+        /// The index of the `{` of each fragment definition, by name
+        fragments: HashMap<&'a str, usize>,
+        arguments: HashMap<String, String>,
+    }
+
+    impl Walk<'_> {
+        /// The text of the arguments between the tokens from `start` up to
+        /// `end`, with variables replaced by their values, or
+        /// nothing if the query ends before `start`
+        fn arguments(&self, start: usize, end: usize) -> String {
+            let tokens = &self.tokens;
+            let end = end.min(tokens.ranges.len());
+            let Some(first) = tokens.ranges.get(start).filter(|_| start < end) else {
+                return String::new();
+            };
+            let mut text = String::new();
+            let mut previous = first.start;
+            let mut i = start;
+            while let Some(range) = tokens.ranges.get(i).filter(|_| i < end) {
+                text.push_str(&tokens.query[previous..range.start]);
+                let value = match (tokens.text(i), tokens.text(i + 1)) {
+                    (Some("$"), Some(name)) if i + 1 < end => self.variables.get(name),
+                    _ => None,
+                };
+                match value {
+                    Some(value) => {
+                        text.push_str(&value.to_string());
+                        previous = tokens
+                            .ranges
+                            .get(i + 1)
+                            .map_or(range.end, |range| range.end);
+                        i += 2;
+                    }
+                    None => {
+                        text.push_str(&tokens.query[range.clone()]);
+                        previous = range.end;
+                        i += 1;
+                    }
+                }
+            }
+            text.split_whitespace().collect::<Vec<_>>().join(" ")
+        }
+
+        /// Record the arguments of the fields in the selection set that
+        /// starts with the `{` at `i`, which is at `path`, and return the
+        /// index of the token after it
+        fn selections(&mut self, mut i: usize, path: &str, depth: usize) -> usize {
+            i += 1;
+            while let Some(token) = self.tokens.text(i) {
+                match token {
+                    "}" => return i + 1,
+                    "..." => match self.tokens.text(i + 1) {
+                        // An inline fragment, whose fields are those of the
+                        // enclosing field
+                        Some("on" | "@" | "{") => {
+                            i += 1;
+                            if self.tokens.text(i) == Some("on") {
+                                i += 2;
+                            }
+                            i = self.tokens.skip_directives(i);
+                            if self.tokens.text(i) == Some("{") {
+                                i = self.selections(i, path, depth);
+                            }
+                        }
+                        name => {
+                            let start = name.and_then(|name| self.fragments.get(name).copied());
+                            i = self.tokens.skip_directives(i + 2);
+                            if let Some(start) = start.filter(|_| depth < MAX_FRAGMENT_DEPTH) {
+                                self.selections(start, path, depth + 1);
+                            }
+                        }
+                    },
+                    key => {
+                        let path = child_path(path, key);
+                        if self.tokens.text(i + 1) == Some(":") {
+                            // Skip the name after the alias
+                            i += 2;
+                        }
+                        i += 1;
+                        if self.tokens.text(i) == Some("(") {
+                            let end = self.tokens.skip_group(i);
+                            let arguments = self.arguments(i + 1, end - 1);
+                            if !arguments.is_empty() {
+                                self.arguments.insert(path.clone(), arguments);
+                            }
+                            i = end;
+                        }
+                        i = self.tokens.skip_directives(i);
+                        if self.tokens.text(i) == Some("{") {
+                            i = self.selections(i, &path, depth);
+                        }
+                    }
+                }
+            }
+            i
+        }
+    }
+
+    let tokens = Tokens::new(query);
+    let mut fragments = HashMap::new();
+    let mut i = 0;
+    while let Some(token) = tokens.text(i) {
+        match token {
+            "fragment" => {
+                let name = tokens.text(i + 1);
+                while tokens.text(i).is_some_and(|token| token != "{") {
+                    i += 1;
+                }
+                if let Some(name) = name {
+                    fragments.insert(name, i);
+                }
+                i = tokens.skip_group(i);
+            }
+            "{" | "(" => i = tokens.skip_group(i),
+            _ => i += 1,
+        }
+    }
+
+    let mut walk = Walk {
+        tokens,
+        variables,
+        fragments,
+        arguments: HashMap::new(),
+    };
+    let mut i = 0;
+    while let Some(token) = walk.tokens.text(i) {
+        i = match token {
+            // Variable definitions, whose defaults can contain braces
+            "(" => walk.tokens.skip_group(i),
+            "fragment" => {
+                while walk.tokens.text(i).is_some_and(|token| token != "{") {
+                    i += 1;
+                }
+                walk.tokens.skip_group(i)
+            }
+            "{" => walk.selections(i, "", 0),
+            _ => i + 1,
+        };
+    }
+    walk.arguments
+}
+
+/// Set the arguments in `overrides` to their values in all fields of
+/// `query` that have them, or only in the fields whose name or alias is
+/// `field`, which also get the arguments if they do not have them. Returns
+/// the changed query and the variables that need to be set instead for
+/// arguments that are passed as variables. It is an error if the arguments
+/// of a field that would change are cut off
+fn with_arguments(
+    query: &str,
+    overrides: &[(&str, u64)],
+    field: Option<&str>,
+) -> anyhow::Result<(String, Vec<(String, u64)>)> {
+    let tokens = Tokens::new(query);
+    let text = |i: usize| tokens.text(i);
+    let is_name = |token: &str| {
+        token
+            .chars()
+            .next()
+            .is_some_and(|c| c.is_alphabetic() || c == '_')
+    };
+
+    let mut edits = Vec::new();
+    let mut variables = Vec::new();
+    let mut depth = 0;
+    let mut i = 0;
+    while let Some(token) = text(i) {
+        match token {
+            "{" => depth += 1,
+            "}" => depth -= 1,
+            // Variable definitions and directive arguments
+            "(" => {
+                i = tokens.skip_group(i);
+                continue;
+            }
+            _ if depth > 0
+                && is_name(token)
+                && text(i + 1) != Some(":")
+                && !matches!(text(i - 1), Some("@" | "on" | "...")) =>
+            {
+                let alias = match text(i - 1) {
+                    Some(":") => text(i - 2),
+                    _ => None,
+                };
+                let selected = field.is_none_or(|field| field == token || alias == Some(field));
+                if text(i + 1) != Some("(") {
+                    if selected && field.is_some() {
+                        let args: Vec<_> = overrides
+                            .iter()
+                            .map(|(name, value)| format!("{name}: {value}"))
+                            .collect();
+                        edits.push((
+                            tokens.ranges[i].end..tokens.ranges[i].end,
+                            format!("({})", args.join(", ")),
+                        ));
+                    }
+                    i += 1;
+                    continue;
+                }
+                let end = tokens.skip_group(i + 1);
+                if selected {
+                    let incomplete =
+                        || anyhow!("Invalid query: the arguments of {token} are incomplete");
+                    if text(end - 1) != Some(")") {
+                        return Err(incomplete());
+                    }
+                    // The arguments of the field and the tokens of their values
+                    let mut args = HashMap::new();
+                    let mut j = i + 2;
+                    while j + 1 < end {
+                        let value = j + 2;
+                        let next = match text(value) {
+                            Some("{" | "[") => tokens.skip_group(value),
+                            Some("$") => value + 2,
+                            _ => value + 1,
+                        };
+                        // The value has to end before the closing `)`
+                        if next > end - 1 {
+                            return Err(incomplete());
+                        }
+                        if let Some(name) = text(j) {
+                            args.insert(name, value..next);
+                        }
+                        j = next;
+                    }
+                    for (name, value) in overrides {
+                        match args.get(name) {
+                            Some(range) if text(range.start) == Some("$") => {
+                                if let Some(variable) = text(range.start + 1) {
+                                    variables.push((variable.to_string(), *value));
+                                }
+                            }
+                            Some(range) => {
+                                let start = tokens.ranges[range.start].start;
+                                let end = tokens.ranges[range.end - 1].end;
+                                edits.push((start..end, value.to_string()));
+                            }
+                            None if field.is_some() => {
+                                let pos = tokens.ranges[i + 1].end;
+                                edits.push((pos..pos, format!("{name}: {value}, ")));
+                            }
+                            None => {}
+                        }
+                    }
+                }
+                i = end;
+                continue;
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+
+    let mut query = query.to_string();
+    edits.sort_by_key(|(range, _)| range.start);
+    for (range, text) in edits.into_iter().rev() {
+        query.replace_range(range, &text);
+    }
+    Ok((query, variables))
+}
+
+/// Split the first operation in `query` into one query for each of its
+/// top-level fields, returned with the name under which the field appears
+/// in the response. Each query only keeps the variable definitions and
+/// fragments that its field uses
+pub fn split_fields(query: &str) -> anyhow::Result<Vec<(String, String)>> {
+    let tokens = Tokens::new(query);
+    let text = |i: usize| tokens.text(i);
+
+    // Each fragment's name and the tokens it spans
+    let mut fragments = Vec::new();
+    // Where the operation starts, and where its selection set starts
+    let mut operation = None;
+    let mut keyword = None;
+    let mut i = 0;
+    while let Some(token) = text(i) {
+        match token {
+            "fragment" => {
+                let start = i;
+                while text(i).is_some_and(|token| token != "{") {
+                    i += 1;
+                }
+                i = tokens.skip_group(i);
+                if let Some(name) = text(start + 1) {
+                    fragments.push((name, start, i));
+                }
+            }
+            "query" | "mutation" | "subscription" if keyword.is_none() => {
+                keyword = Some(i);
+                i += 1;
+            }
+            "(" => i = tokens.skip_group(i),
+            "{" => {
+                if operation.is_none() {
+                    operation = Some((keyword.unwrap_or(i), i));
+                }
+                keyword = None;
+                i = tokens.skip_group(i);
+            }
+            _ => i += 1,
+        }
+    }
+    let (start, selections) = operation.ok_or_else(|| anyhow!("The query has no operation"))?;
+    let span = |start: usize, end: usize| {
+        tokens
+            .span(start, end)
+            .ok_or_else(|| anyhow!("Invalid query: the document ends unexpectedly"))
+    };
+
+    // The variable definitions of the operation, each with its name and
+    // its text, and the parts of the operation's header around them
+    let mut definitions = Vec::new();
+    let (mut before, mut after) = ("", "");
+    if start < selections {
+        before = span(start, selections)?;
+    }
+    if let Some(open) = (start..selections).find(|&i| text(i) == Some("(")) {
+        let close = tokens.skip_group(open);
+        before = span(start, open)?;
+        if close < selections {
+            after = span(close, selections)?;
+        }
+        let dollars: Vec<_> = (open + 1..close - 1)
+            .filter(|&i| text(i) == Some("$"))
+            .collect();
+        for (k, &dollar) in dollars.iter().enumerate() {
+            let end = dollars.get(k + 1).copied().unwrap_or(close - 1);
+            if let Some(name) = text(dollar + 1) {
+                definitions.push((name, span(dollar, end)?));
+            }
+        }
+    }
+
+    let mut queries = Vec::new();
+    let mut i = selections + 1;
+    while let Some(token) = text(i) {
+        if token == "}" {
+            break;
+        }
+        let field = i;
+        let name = if token == "..." {
+            i = tokens.skip_fragment(i);
+            match text(field + 1) {
+                Some("on") => span(field, field + 3)?.to_string(),
+                Some(name) if name != "@" && name != "{" => format!("...{name}"),
+                _ => "...".to_string(),
+            }
+        } else {
+            let name = token.to_string();
+            if text(i + 1) == Some(":") {
+                i += 2;
+            }
+            i += 1;
+            if text(i) == Some("(") {
+                i = tokens.skip_group(i);
+            }
+            i = tokens.skip_directives(i);
+            if text(i) == Some("{") {
+                i = tokens.skip_group(i);
+            }
+            name
+        };
+
+        // The fragments that the field uses, directly or through other
+        // fragments
+        let mut used = tokens.names_after("...", field, i);
+        let mut k = 0;
+        while let Some(spread) = used.get(k).copied() {
+            if let Some((_, start, end)) = fragments.iter().find(|(name, ..)| *name == spread) {
+                for name in tokens.names_after("...", *start, *end) {
+                    if !used.contains(&name) {
+                        used.push(name);
+                    }
+                }
+            }
+            k += 1;
+        }
+        let fragments: Vec<_> = fragments
+            .iter()
+            .filter(|(name, ..)| used.contains(name))
+            .collect();
+        let mut variables = tokens.names_after("$", field, i);
+        for (_, start, end) in &fragments {
+            variables.extend(tokens.names_after("$", *start, *end));
+        }
+        let definitions: Vec<_> = definitions
+            .iter()
+            .filter(|(name, _)| variables.contains(name))
+            .map(|(_, definition)| *definition)
+            .collect();
+
+        let mut split = match (before, definitions.is_empty()) {
+            ("", _) => String::new(),
+            (before, true) => format!("{before} {after}"),
+            (before, false) => format!("{before}({}) {after}", definitions.join(", ")),
+        };
+        split = format!("{} {{ {} }}", split.trim(), span(field, i)?);
+        for (_, start, end) in fragments {
+            split.push_str("\n\n");
+            split.push_str(span(*start, *end)?);
+        }
+        queries.push((name, split.trim().to_string()));
+    }
+    Ok(queries)
+}
+
+/// The source for the `logs` on this machine, with log lines in `format`
+/// that `pattern` takes apart
+pub fn local_source<'a>(
+    logs: &'a LocalLogs,
+    format: Option<LogFormat>,
+    pattern: &'a Pattern,
+) -> Box<dyn LogSource + 'a> {
+    match logs {
+        LocalLogs::File(path) => Box::new(LogFile {
+            path,
+            pattern,
+            format,
+        }),
+        LocalLogs::Journal(unit) => Box::new(Journal {
+            unit,
+            pattern,
+            format,
+        }),
+        LocalLogs::Docker(container) => Box::new(DockerLogs {
+            container,
+            pattern,
+            format,
+        }),
+    }
+}
+
+/// Find up to `limit` query log entries matching `query`, either in the
+/// `local` logs, whose lines are in `format` or that of the first Loki
+/// cluster, or in the first configured log source that has matching
+/// entries
+pub async fn find_entries(
+    query: &QueryFilter,
+    local: Option<&LocalLogs>,
+    format: Option<LogFormat>,
+    config: &Config,
+    limit: usize,
+    out: &mut dyn std::io::Write,
+) -> anyhow::Result<Vec<LogEntry>> {
+    let default = Pattern::default();
+    let pattern = config
+        .lokis()
+        .first()
+        .map(|loki| &loki.pattern)
+        .unwrap_or(&default);
+    let local = local.map(|logs| local_source(logs, format, pattern));
+    let sources = match &local {
+        Some(local) => vec![local.as_ref()],
+        None => config.sources(),
+    };
+
+    let mut error = anyhow!(
+        "The configuration has no [loki], [elasticsearch], [cloudwatch], [gcp] or [k8s] section"
+    );
+    let mut entries = None;
+    for source in sources {
+        writeln!(out, "Searching {} for query log entries", source.name())?;
+        match source.find(query, limit, &config.retry, out).await {
+            Ok(found) => {
+                entries = Some(found);
+                break;
+            }
+            Err(e) => {
+                writeln!(out, "{} failed: {e}", source.name())?;
+                error = e;
+            }
+        }
+    }
+    entries.ok_or(error)
+}
+
+/// Load a trace from a file. The file can either contain just the trace,
+/// as saved with `--trace`, or the complete response from `graph-node`
+pub fn load_trace(file: &str) -> anyhow::Result<Trace> {
+    let json = std::fs::read_to_string(file).map_err(|e| anyhow!("Failed to read {file}: {e}"))?;
+    let json: json::Value =
+        json::from_str(&json).map_err(|e| anyhow!("Failed to parse {file}: {e}"))?;
+    let version = json["graph_node_version"].as_str();
+    if json.get("elapsed_ms").is_some() {
+        Trace::parse_from(&json, version)
+    } else {
+        Trace::parse_from(&json["trace"], version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn with_arguments_overrides_values_and_variables() {
+        let (query, variables) = with_arguments(
+            "query($n: Int) { a(first: 10, skip: $n) { id } }",
+            &[("first", 5), ("skip", 2)],
+            None,
+        )
+        .unwrap();
+        assert_eq!(query, "query($n: Int) { a(first: 5, skip: $n) { id } }");
+        assert_eq!(variables, vec![("n".to_string(), 2)]);
+    }
+
+    #[test]
+    fn with_arguments_adds_arguments_to_the_field() {
+        let (query, _) = with_arguments(
+            "{ a { id } b: a(skip: 1) { id } c { id } }",
+            &[("first", 5)],
+            Some("a"),
+        )
+        .unwrap();
+        assert_eq!(
+            query,
+            "{ a(first: 5) { id } b: a(first: 5, skip: 1) { id } c { id } }"
+        );
+    }
+
+    #[test]
+    fn with_arguments_rejects_cut_off_arguments() {
+        for query in ["{ a(first }", "{ a(first: }", "{ a(first: $ }"] {
+            assert!(
+                with_arguments(query, &[("first", 5)], None).is_err(),
+                "{query}"
+            );
+        }
+    }
+
+    #[test]
+    fn percentile_uses_the_nearest_rank() {
+        assert_eq!(percentile(&[], 50), None);
+        assert_eq!(percentile(&[1, 2, 3, 4], 50), Some(2));
+        assert_eq!(percentile(&[1, 2, 3, 4], 99), Some(4));
+    }
+
+    #[test]
+    fn colors_only_when_asked_for() {
+        let slow = Duration::from_millis(500);
+        assert_eq!(DisplayOpts::default().color(slow), None);
+        let display = DisplayOpts {
+            colors: true,
+            ..DisplayOpts::default()
+        };
+        assert_eq!(display.color(slow), Some("\x1b[33m"));
+        assert_eq!(display.color(Duration::from_millis(10)), None);
+    }
+}
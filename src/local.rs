@@ -0,0 +1,179 @@
+//! A throwaway `graph-node` that queries can be replayed against instead of
+//! the production query nodes
+
+use std::time::{Duration, Instant};
+
+use anyhow::anyhow;
+use serde_derive::Deserialize;
+
+use super::{GraphNode, Http};
+
+/// How to start a `graph-node` with Docker. It serves queries from the
+/// database at `postgres-url`, e.g., a replica or a restored dump, but has
+/// no deployments assigned and therefore does not index anything
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct LocalNode {
+    /// The `graph-node` image to run
+    #[serde(default = "LocalNode::default_image")]
+    image: String,
+    /// The database with the deployments. `graph-node` runs its migrations
+    /// at startup and therefore needs to be able to write to it
+    postgres_url: String,
+    /// The Ethereum RPC that `graph-node` insists on, as `network:url`
+    ethereum_rpc: String,
+    /// The IPFS node for `graph-node`
+    #[serde(default = "LocalNode::default_ipfs")]
+    ipfs: String,
+    /// The Docker network to run the container in, e.g., `host` to reach a
+    /// database on the same machine
+    network: Option<String>,
+    /// Further arguments for `graph-node`
+    #[serde(default)]
+    args: Vec<String>,
+    /// How long to wait for `graph-node` to accept queries
+    #[serde(default = "LocalNode::default_startup_timeout_secs")]
+    startup_timeout_secs: u64,
+}
+
+/// A running container that is stopped, and thereby removed, when this is
+/// dropped
+pub struct Container(String);
+
+impl Drop for Container {
+    fn drop(&mut self) {
+        let _ = docker(&["stop".to_string(), self.0.clone()]);
+    }
+}
+
+/// Run `docker` with `args` and return what it prints
+fn docker(args: &[String]) -> anyhow::Result<String> {
+    let output = std::process::Command::new("docker")
+        .args(args)
+        .output()
+        .map_err(|e| anyhow!("Failed to run docker: {e}"))?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "docker {} failed with {}: {}",
+            args[0],
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+impl LocalNode {
+    /// The port that `graph-node` serves queries on
+    const PORT: u16 = 8000;
+
+    fn default_image() -> String {
+        "graphprotocol/graph-node:latest".to_string()
+    }
+
+    fn default_ipfs() -> String {
+        "https://api.thegraph.com/ipfs/".to_string()
+    }
+
+    fn default_startup_timeout_secs() -> u64 {
+        120
+    }
+
+    /// Start `graph-node` and wait until it accepts queries. The returned
+    /// `GraphNode` sends queries to it with a trace token that is made up
+    /// for this run
+    pub async fn start(
+        &self,
+        out: &mut dyn std::io::Write,
+    ) -> anyhow::Result<(Container, GraphNode)> {
+        let token = format!("{:016x}", rand::random::<u64>());
+        let host_network = self.network.as_deref() == Some("host");
+        let mut args = vec![
+            "run".to_string(),
+            "--detach".to_string(),
+            "--rm".to_string(),
+            "--name".to_string(),
+            format!("qtrace-replay-{token}"),
+            "--entrypoint".to_string(),
+            "graph-node".to_string(),
+            "--env".to_string(),
+            format!("GRAPH_GRAPHQL_TRACE_TOKEN={token}"),
+        ];
+        if let Some(network) = &self.network {
+            args.extend(["--network".to_string(), network.clone()]);
+        }
+        if !host_network {
+            // Let Docker pick a free port on the host
+            args.extend([
+                "--publish".to_string(),
+                format!("127.0.0.1::{}", Self::PORT),
+            ]);
+        }
+        args.push(self.image.clone());
+        args.extend([
+            "--node-id".to_string(),
+            "qtrace_replay".to_string(),
+            "--postgres-url".to_string(),
+            self.postgres_url.clone(),
+            "--ethereum-rpc".to_string(),
+            self.ethereum_rpc.clone(),
+            "--ipfs".to_string(),
+            self.ipfs.clone(),
+        ]);
+        args.extend(self.args.iter().cloned());
+
+        writeln!(out, "Starting graph-node from {}", self.image)?;
+        let container = Container(docker(&args)?);
+        let url = if host_network {
+            format!("http://127.0.0.1:{}", Self::PORT)
+        } else {
+            let port = docker(&[
+                "port".to_string(),
+                container.0.clone(),
+                format!("{}/tcp", Self::PORT),
+            ])?;
+            let address = port
+                .lines()
+                .next()
+                .ok_or_else(|| anyhow!("Docker did not publish the graph-node port"))?;
+            format!("http://{address}")
+        };
+
+        let client = reqwest::Client::new();
+        let deadline = Instant::now() + Duration::from_secs(self.startup_timeout_secs);
+        while client.get(&url).send().await.is_err() {
+            if Instant::now() > deadline {
+                // `docker logs` replays what graph-node wrote to stderr there
+                let logs = std::process::Command::new("docker")
+                    .args(["logs", "--tail", "20", &container.0])
+                    .output()
+                    .map(|output| String::from_utf8_lossy(&output.stderr).to_string())
+                    .unwrap_or_default();
+                return Err(anyhow!(
+                    "graph-node did not start within {}s:\n{logs}",
+                    self.startup_timeout_secs
+                ));
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+        writeln!(out, "graph-node is serving queries at {url}")?;
+
+        let graph_node = GraphNode {
+            name: None,
+            url,
+            trace_token: token,
+            index_node_url: None,
+            network_subgraph_url: None,
+            token: None,
+            headers: Default::default(),
+            socket: None,
+            port_forward: None,
+            version_header: None,
+            routing: Default::default(),
+            names: Default::default(),
+            version: None,
+            http: Http::default(),
+        };
+        Ok((container, graph_node))
+    }
+}
@@ -1,10 +1,33 @@
-use std::{fs::File, io::Write as _, time::Duration};
+use std::{
+    collections::{hash_map::Entry, HashMap, HashSet},
+    fs::File,
+    io::Write as _,
+    process::ExitCode,
+    time::Duration,
+};
 
 use anyhow::anyhow;
-use clap::Parser;
-use serde_derive::Deserialize;
+use chrono::{DateTime, Utc};
+use clap::{Args, Parser, Subcommand};
+use futures_util::future::join_all;
+use rand::seq::SliceRandom;
+use regex::Regex;
 use serde_json::{self as json, json};
-use url::Url;
+
+use qtrace::aggregate::{aggregate, Aggregate, FieldStats};
+use qtrace::bench::{bench, ScheduleOpts};
+use qtrace::brief::brief_trace;
+use qtrace::corpus::{read_corpus, record, rerun};
+use qtrace::report::ReportFormat;
+use qtrace::source::{LogFormat, Pattern, TimeRange};
+use qtrace::{
+    child_path, chrome_trace, diff_traces, dot_graph, export, find_entries, findings, fit,
+    load_trace, local_source, mermaid_flowchart, or_dash, percent_of, query_shape, query_times,
+    record_fingerprint, report, response_diffs, save_flamegraph, speedscope_profile, split_fields,
+    sql, trace_summary, tunnel, ArgumentOpts, Config, DisplayOpts, GraphNode, GraphQlErrors,
+    HttpTiming, LocalLogs, LogEntry, OneOrMany, QueryFilter, Retry, Routing, ServerVersion, Target,
+    TimeUnit, Trace, TraceFormat, TraceSort,
+};
 
 #[derive(Debug, Parser)]
 #[clap(
@@ -14,391 +37,2511 @@ use url::Url;
     about = "Obtain slow query traces from the hosted service"
 )]
 struct Opts {
+    #[clap(subcommand)]
+    cmd: Command,
     /// The config file to use
-    #[clap(short, long, default_value = "config.toml", env = "QTRACE_CONFIG")]
+    #[clap(
+        short,
+        long,
+        default_value = "config.toml",
+        env = "QTRACE_CONFIG",
+        global = true
+    )]
     config: String,
-    /// The `query_id` to trace
-    #[clap(short, long)]
-    qid: Option<String>,
-    /// Only consider queries that took longer than this many milliseconds
-    #[clap(short, long)]
-    min_time: Option<usize>,
     /// Print some more information
-    #[clap(short, long)]
+    #[clap(short, long, global = true)]
     verbose: bool,
+    /// Abandon requests to Loki, graph-node, and other endpoints that take
+    /// longer than this, e.g., `30s`. Overrides the `timeout-secs` of the
+    /// endpoints in the config file
+    #[clap(long, global = true, value_parser = humantime::parse_duration)]
+    timeout: Option<Duration>,
+    /// Send queries to graph-node by the name of the subgraph instead of
+    /// the deployment hash, like `routing = "name"` in the config file
+    #[clap(long, global = true)]
+    route_by_name: bool,
+    /// Replay queries against the graph-node endpoint with this name from
+    /// the `[[graph-node]]` sections instead of the first one
+    #[clap(long, global = true, env = "QTRACE_TARGET", value_name = "NAME")]
+    target: Option<String>,
+    /// Print how the query log would be searched and the requests that
+    /// would be sent to graph-node without sending anything
+    #[clap(long, global = true)]
+    dry_run: bool,
+    /// Exit with status 3 when any of the traces that were printed has
+    /// findings, like likely N+1 queries or repeated SQL
+    #[clap(long, global = true)]
+    fail_on_findings: bool,
+    #[clap(flatten)]
+    display: DisplayArgs,
+}
+
+#[derive(Debug, Args)]
+struct FetchOpts {
+    /// Trace the N slowest matching queries and print a ranking of them
+    #[clap(long, value_name = "N", value_parser = parse_count)]
+    top: Option<usize>,
+    /// With `--top`, only trace the slowest query of each shape, i.e., of
+    /// queries that only differ in literal values
+    #[clap(long, requires = "top")]
+    dedup: bool,
+    /// Trace N randomly chosen matching queries instead of the most recent
+    /// one
+    #[clap(long, value_name = "N", conflicts_with = "top", value_parser = parse_count)]
+    sample: Option<usize>,
+    /// Run the queries against the block that they originally ran against
+    /// instead of the latest one
+    #[clap(long)]
+    pin_block: bool,
+    #[clap(flatten)]
+    variables: VariableOpts,
+    /// Replay each query a second time right after the first and show the
+    /// timings of both runs to tell work that caching speeds up from slow
+    /// SQL
+    #[clap(long)]
+    cache_compare: bool,
+    /// Also trace each top-level field of the query on its own and show
+    /// how long each of them took, to find the one that makes it slow
+    #[clap(long)]
+    split_fields: bool,
+    /// Replay the queries against a throwaway graph-node started with
+    /// Docker as set up in the `[local-node]` section
+    #[clap(long)]
+    local: bool,
+    #[clap(flatten)]
+    filter: FilterOpts,
+    #[clap(flatten)]
+    save: SaveOpts,
+}
+
+/// How to replay a query for `bench`
+#[derive(Debug, Args)]
+struct BenchOpts {
+    /// Run the query this many times before measuring
+    #[clap(long, default_value = "1", value_name = "N")]
+    warmup: usize,
+    /// Measure this many runs of the query [default: 10]
+    #[clap(long, value_name = "N", conflicts_with = "duration")]
+    iterations: Option<usize>,
+    /// Run the query over and over for this long instead of a fixed number
+    /// of times
+    #[clap(long, value_parser = humantime::parse_duration)]
+    duration: Option<Duration>,
+    /// Keep this many runs of the query going at the same time
+    #[clap(long, default_value = "1", value_name = "N")]
+    concurrency: usize,
+    #[clap(flatten)]
+    query: BenchQueryOpts,
+}
+
+/// How to put load on graph-node for `load`
+#[derive(Debug, Args)]
+struct LoadOpts {
+    /// The number of workers that send the query, each one as soon as its
+    /// previous run finished
+    #[clap(long, default_value = "10", value_name = "N")]
+    concurrency: usize,
+    /// How long to keep sending the query
+    #[clap(long, default_value = "60s", value_parser = humantime::parse_duration)]
+    duration: Duration,
+    #[clap(flatten)]
+    query: BenchQueryOpts,
+}
+
+impl LoadOpts {
+    /// A benchmark that runs for the whole duration without a warmup
+    fn bench(&self) -> BenchOpts {
+        BenchOpts {
+            warmup: 0,
+            iterations: None,
+            duration: Some(self.duration),
+            concurrency: self.concurrency,
+            query: self.query.clone(),
+        }
+    }
+}
+
+impl BenchOpts {
+    /// How often to replay the query
+    fn schedule(&self) -> ScheduleOpts {
+        ScheduleOpts {
+            warmup: self.warmup,
+            iterations: self.iterations,
+            duration: self.duration,
+            concurrency: self.concurrency,
+        }
+    }
+}
+
+/// Which query `bench` and `load` replay, and where the results go
+#[derive(Clone, Debug, Args)]
+struct BenchQueryOpts {
+    /// Append the results to this file as one JSON object per deployment
+    #[clap(long, value_name = "FILE")]
+    json: Option<String>,
+    /// Replay the query from this file instead of one from the query log
+    #[clap(long, value_name = "FILE")]
+    query_file: Option<String>,
+    #[clap(flatten)]
+    variables: VariableOpts,
+    /// Run the query against the block that it originally ran against
+    /// instead of the latest one
+    #[clap(long, conflicts_with = "query_file")]
+    pin_block: bool,
+    /// Replay the query against a throwaway graph-node started with
+    /// Docker as set up in the `[local-node]` section
+    #[clap(long)]
+    local: bool,
+}
+
+/// Options that say where to save the results of running a query
+#[derive(Debug, Args)]
+struct SaveOpts {
     /// Save the output in this file
     #[clap(short, long)]
     data: Option<String>,
     /// Save the query trace in this file
     #[clap(short, long)]
     trace: Option<String>,
-    /// The IPFS hash of the deployment
-    #[clap(required = true)]
-    deployment: String,
+    /// Render the trace as a flame graph and save it in this SVG file
+    #[clap(long, value_name = "FILE")]
+    flamegraph: Option<String>,
+    /// Save the trace in this file as JSON that the Jaeger UI can import
+    #[clap(long, value_name = "FILE")]
+    jaeger: Option<String>,
+    /// Save the trace in this file as Zipkin v2 JSON
+    #[clap(long, value_name = "FILE")]
+    zipkin: Option<String>,
 }
 
-#[derive(Debug)]
-struct LogEntry {
-    query: String,
-    variables: json::Value,
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Find a query in the query log and trace it
+    Fetch(FetchOpts),
+    /// List matching queries from the query log without tracing them
+    List {
+        /// The number of queries to list
+        #[clap(short, long, default_value = "20", value_parser = parse_count)]
+        limit: usize,
+        #[clap(flatten)]
+        filter: FilterOpts,
+    },
+    /// Replay the most recent matching query over and over and report how
+    /// long it took
+    Bench {
+        #[clap(flatten)]
+        bench: BenchOpts,
+        #[clap(flatten)]
+        filter: FilterOpts,
+    },
+    /// Send the most recent matching query from many workers at once for a
+    /// while and report throughput, errors, and latency
+    Load {
+        #[clap(flatten)]
+        load: LoadOpts,
+        #[clap(flatten)]
+        filter: FilterOpts,
+    },
+    /// Summarize how long matching queries took according to the query log
+    /// without tracing any of them
+    Stats {
+        /// The maximum number of queries to look at
+        #[clap(short, long, default_value = "10000", value_parser = parse_count)]
+        limit: usize,
+        #[clap(flatten)]
+        filter: FilterOpts,
+    },
+    /// Print matching queries from Loki as they are logged
+    Tail {
+        /// How often to poll Loki for new entries
+        #[clap(long, default_value = "2s", value_parser = humantime::parse_duration)]
+        interval: Duration,
+        #[clap(flatten)]
+        filter: FilterOpts,
+    },
+    /// Trace a query from a file instead of looking it up in the query log
+    Replay {
+        /// The file with the GraphQL query. Read from stdin if omitted
+        #[clap(long, value_name = "FILE")]
+        query_file: Option<String>,
+        #[clap(flatten)]
+        variables: VariableOpts,
+        /// Run the query against this block instead of the latest one
+        #[clap(long)]
+        block: Option<u64>,
+        /// Replay the query a second time right after the first and show
+        /// the timings of both runs to tell work that caching speeds up
+        /// from slow SQL
+        #[clap(long)]
+        cache_compare: bool,
+        /// Also trace each top-level field of the query on its own and show
+        /// how long each of them took, to find the one that makes it slow
+        #[clap(long)]
+        split_fields: bool,
+        /// Replay the query against a throwaway graph-node started with
+        /// Docker as set up in the `[local-node]` section
+        #[clap(long)]
+        local: bool,
+        #[clap(flatten)]
+        save: SaveOpts,
+        /// The IPFS hash of the deployment, or the name of a subgraph whose
+        /// current version to use
+        deployment: String,
+    },
+    /// Print a trace that was saved with `--trace`
+    Analyze {
+        /// The file with the trace
+        file: String,
+        /// Render the trace as a flame graph and save it in this SVG file
+        #[clap(long, value_name = "FILE")]
+        flamegraph: Option<String>,
+    },
+    /// Group saved traces by the shape of their query and print the mean
+    /// and 95th percentile of the time and entities of each field
+    Aggregate {
+        /// Directories with traces, files with a trace, or patterns like
+        /// `traces/pools-*.json` for the files to use
+        #[clap(required = true)]
+        traces: Vec<String>,
+    },
+    /// Compare the timings of two saved traces, or replay the most recent
+    /// matching query against several graph-node endpoints and compare the
+    /// traces from each of them
+    #[clap(override_usage = "qtrace compare <FIRST> <SECOND>\n       \
+                             qtrace compare --targets <NAMES> [OPTIONS] <DEPLOYMENTS>...")]
+    Compare {
+        /// The names of the graph-node endpoints from the `[[graph-node]]`
+        /// sections to replay the query against. Without it, the arguments
+        /// are the files with the two traces to compare
+        #[clap(long, value_name = "NAMES", value_delimiter = ',')]
+        targets: Vec<String>,
+        #[clap(flatten)]
+        filter: FilterOpts,
+    },
+    /// Diff two saved traces field by field, or replay the most recent
+    /// matching query against two graph-node endpoints and diff their
+    /// traces
+    #[clap(override_usage = "qtrace diff <FIRST> <SECOND>\n       \
+                             qtrace diff --targets <FIRST>,<SECOND> [OPTIONS] <DEPLOYMENTS>...")]
+    Diff {
+        /// The names of the two graph-node endpoints from the
+        /// `[[graph-node]]` sections to replay the query against. Without
+        /// it, the arguments are the files with the two traces to diff
+        #[clap(long, value_name = "NAMES", value_delimiter = ',')]
+        targets: Vec<String>,
+        #[clap(flatten)]
+        filter: FilterOpts,
+    },
+    /// Trace matching queries and save the requests, responses and traces
+    /// in a corpus directory for `rerun`
+    Record {
+        /// The directory to save the queries in
+        #[clap(long, value_name = "DIR")]
+        corpus: String,
+        /// The number of queries to record for each deployment
+        #[clap(short, long, default_value = "10", value_parser = parse_count)]
+        limit: usize,
+        /// Run the queries against the block that they originally ran
+        /// against so that their results do not change when they are rerun
+        #[clap(long)]
+        pin_block: bool,
+        #[clap(flatten)]
+        filter: FilterOpts,
+    },
+    /// Replay all queries in a corpus directory and report how their
+    /// timings and results changed since they were recorded
+    Rerun {
+        /// The directory with the queries from `record`
+        corpus: String,
+    },
 }
 
-#[derive(Deserialize, Debug)]
-struct Loki {
-    cluster: String,
-    url: String,
-    username: String,
-    password: String,
+/// Parse a number of queries, which must be at least one
+fn parse_count(s: &str) -> anyhow::Result<usize> {
+    match s.parse::<usize>() {
+        Ok(count) if count > 0 => Ok(count),
+        _ => Err(anyhow!("Invalid number {s}: expected a positive number")),
+    }
 }
 
-impl Loki {
-    fn query_url(&self) -> anyhow::Result<Url> {
-        let mut url = Url::parse(&self.url)?;
-        url.set_username(&self.username)
-            .map_err(|_| anyhow!("Failed to set Loki username"))?;
-        url.set_password(Some(&self.password))
-            .map_err(|_| anyhow!("Failed to set Loki password"))?;
-        url.set_path("/loki/api/v1/query");
-        Ok(url)
-    }
+/// Options that say how traces are printed
+#[derive(Debug, Args)]
+struct DisplayArgs {
+    /// Print the SQL that graph-node ran for each field, and its bind
+    /// parameters, below the trace
+    #[clap(long, global = true)]
+    show_sql: bool,
+    /// How to print traces
+    #[clap(long, global = true, value_enum, default_value_t)]
+    format: TraceFormat,
+    /// The order of the fields below each field of a trace
+    #[clap(long, global = true, value_enum, default_value_t)]
+    sort: TraceSort,
+    /// Only print the N fields that took the longest by themselves, and the
+    /// fields that lead to them
+    #[clap(long, global = true, value_name = "N")]
+    top_nodes: Option<usize>,
+    /// Leave out fields that took less than MS milliseconds, counting the
+    /// fields below them
+    #[clap(long, global = true, value_name = "MS")]
+    min_node_ms: Option<u64>,
+    /// Do not print fields that are nested more than N levels deep
+    #[clap(long, global = true, value_name = "N")]
+    max_depth: Option<usize>,
+    /// Only print the fields whose path of response keys, e.g.,
+    /// `pools.swaps`, matches this regular expression, and the fields that
+    /// lead to them
+    #[clap(long, global = true, value_name = "REGEX", value_parser = parse_regex)]
+    grep: Option<Regex>,
+    /// Print a report about each trace that can be shared, e.g., in a
+    /// GitHub issue, instead of the brief output
+    #[clap(long, global = true, value_enum, value_name = "FORMAT")]
+    report: Option<ReportFormat>,
+    /// Show how long each field waited for a database connection and for a
+    /// permit to run its query
+    #[clap(long, global = true)]
+    waits: bool,
+    /// Show the arguments of each field, e.g., `first` and `orderBy`, next
+    /// to its name, as they are in the query
+    #[clap(long, global = true)]
+    args: bool,
+    /// Warn that the database is saturated when the fields waited for a
+    /// connection and a permit for at least this fraction of the time of
+    /// the request [default: 0.2]
+    #[clap(long, global = true, value_name = "FRACTION", value_parser = parse_fraction)]
+    saturation: Option<f64>,
+    /// Print fields that took at least MS milliseconds by themselves in
+    /// yellow [default: 100]
+    #[clap(long, global = true, value_name = "MS")]
+    yellow_ms: Option<u64>,
+    /// Print fields that took at least MS milliseconds by themselves in red
+    /// [default: 1000]
+    #[clap(long, global = true, value_name = "MS")]
+    red_ms: Option<u64>,
+    /// Do not use colors, which are also turned off by setting `NO_COLOR`
+    /// or when the output is not a terminal
+    #[clap(long, global = true)]
+    no_color: bool,
+    /// Draw the tree of fields with ASCII characters instead of box-drawing
+    /// ones
+    #[clap(long, global = true)]
+    ascii: bool,
+    /// The unit to show times in
+    #[clap(long, global = true, value_enum, default_value_t)]
+    unit: TimeUnit,
+    /// The number of decimals to show times with [default: 0, or 3 for
+    /// seconds]
+    #[clap(long, global = true, value_name = "N")]
+    precision: Option<usize>,
+}
 
-    fn query(
-        &self,
-        deployment: &str,
-        qid: Option<&str>,
-        min_time: Option<usize>,
-        out: &mut dyn std::io::Write,
-    ) -> anyhow::Result<LogEntry> {
-        let query = {
-            // This will need to be adjusted if the query log format changes
-            const PATTERN: &str = r#"pattern "<_>INFO Query timing (GraphQL), block: <block>, query_time_ms: <query_time>, variables: <variables>, query: <query> , query_id: <query_id>,""#;
-            let mut query = format!(
-                r#"{{cluster="{cluster}",app=~"query-node.*",deployment="{deployment}",container="query-node"}} | {PATTERN}"#,
-                cluster = self.cluster
-            );
-            if let Some(qid) = qid {
-                query.push_str(&format!(r#" | query_id="{qid}""#));
-            }
-            if let Some(min_time) = min_time {
-                query.push_str(&format!(r#" | query_time > {min_time}"#));
-            }
-            query
-        };
+impl DisplayArgs {
+    /// How to print traces to stdout. Colors are only used on a terminal,
+    /// and the brief output is made to fit its width
+    fn opts(&self) -> DisplayOpts {
+        use std::io::IsTerminal;
 
-        let url = self.query_url()?;
-        let client = reqwest::blocking::Client::new();
-        let resp = client
-            .get(url)
-            .query(&[("query", query.as_str()), ("limit", "1")])
-            .send()
-            .map_err(|e| anyhow!("Failed to send Loki query: {}", e))?
-            .text()
-            .map_err(|e| anyhow!("Failed to get Loki response: {}", e))?;
-        let resp: json::Value =
-            json::from_str(&resp).map_err(|e| anyhow!("Failed to parse Loki response: {}", e))?;
-        let stream = match &resp["data"]["result"][0]["stream"] {
-            json::Value::Object(o) => o,
-            _ => {
-                writeln!(out, "Loki query: {query}")?;
-                writeln!(out, "Loki response status: {}", resp["status"])?;
-                return Err(anyhow!("Invalid Loki response: no result"));
-            }
-        };
-        let query = match &stream["query"] {
-            json::Value::String(s) => s.to_string(),
-            _ => return Err(anyhow!("Invalid Loki response: could not find query")),
-        };
-        let variables = match &stream["variables"] {
-            json::Value::String(s) => json::from_str(s)?,
-            _ => return Err(anyhow!("Invalid Loki response: could not find variables")),
-        };
-        let entry = LogEntry { query, variables };
-        Ok(entry)
-    }
-}
-
-#[derive(Debug)]
-pub enum Trace {
-    Root {
-        query: String,
-        variables: String,
-        query_id: String,
-        block: usize,
-        elapsed: Duration,
-        conn_wait: Duration,
-        permit_wait: Duration,
-        children: Vec<(String, Trace)>,
-    },
-    Query {
-        query: String,
-        elapsed: Duration,
-        conn_wait: Duration,
-        permit_wait: Duration,
-        entity_count: usize,
-        children: Vec<(String, Trace)>,
-    },
+        let default = DisplayOpts::default();
+        let colors = !self.no_color
+            && std::env::var_os("NO_COLOR").is_none_or(|value| value.is_empty())
+            && std::io::stdout().is_terminal();
+        let width = terminal_size::terminal_size_of(std::io::stdout())
+            .map(|(terminal_size::Width(columns), _)| columns as usize);
+        DisplayOpts {
+            show_sql: self.show_sql,
+            format: self.format,
+            sort: self.sort,
+            top_nodes: self.top_nodes,
+            min_node_ms: self.min_node_ms,
+            max_depth: self.max_depth,
+            grep: self.grep.clone(),
+            report: self.report,
+            waits: self.waits,
+            args: self.args,
+            saturation: self.saturation.unwrap_or(default.saturation),
+            yellow_ms: self.yellow_ms.unwrap_or(default.yellow_ms),
+            red_ms: self.red_ms.unwrap_or(default.red_ms),
+            colors,
+            ascii: self.ascii,
+            unit: self.unit,
+            precision: self.precision,
+            width,
+        }
+    }
 }
 
-impl Trace {
-    fn number_as_millis(entry: &json::Value, key: &str) -> anyhow::Result<Duration> {
-        entry[key]
-            .as_u64()
-            .ok_or_else(|| anyhow!("Invalid trace: {key} is not a duration"))
-            .map(Duration::from_millis)
+/// Options that select which entries from the query log to use
+#[derive(Debug, Args)]
+struct FilterOpts {
+    /// The `query_id` to trace
+    #[clap(short, long)]
+    qid: Option<String>,
+    /// Only consider queries that took longer than this many milliseconds
+    #[clap(short, long)]
+    min_time: Option<usize>,
+    /// Only consider queries that took less than this many milliseconds
+    #[clap(long)]
+    max_time: Option<usize>,
+    /// Only consider queries that ran against this block or a later one
+    #[clap(long, value_name = "BLOCK")]
+    block_from: Option<u64>,
+    /// Only consider queries that ran against this block or an earlier one
+    #[clap(long, value_name = "BLOCK")]
+    block_to: Option<u64>,
+    /// Only consider queries logged after this time. Either an RFC3339
+    /// timestamp or a duration like `2h` or `1d` before now
+    #[clap(long, value_parser = parse_time)]
+    since: Option<DateTime<Utc>>,
+    /// Only consider queries logged before this time, in the same format as
+    /// `--since`
+    #[clap(long, value_parser = parse_time)]
+    until: Option<DateTime<Utc>>,
+    /// Only consider queries with this GraphQL operation name
+    #[clap(long, value_name = "NAME", value_parser = parse_name)]
+    operation: Option<String>,
+    /// Only consider queries whose text matches this regular expression
+    #[clap(long = "match", value_name = "REGEX", value_parser = parse_regex)]
+    query_regex: Option<Regex>,
+    /// Write all matching query log entries to this file as newline
+    /// delimited JSON
+    #[clap(long, value_name = "FILE")]
+    export_log: Option<String>,
+    /// Read query log entries from this file instead of querying Loki. The
+    /// file must contain `graph-node` log lines in the format that Loki
+    /// ingests
+    #[clap(long, value_name = "FILE", group = "local_logs")]
+    from_file: Option<String>,
+    /// Read query log entries from the journal of this systemd unit with
+    /// `journalctl` instead of querying Loki
+    #[clap(long, value_name = "UNIT", group = "local_logs")]
+    from_journal: Option<String>,
+    /// Read query log entries from the logs of this container with `docker
+    /// logs` instead of querying Loki
+    #[clap(long, value_name = "CONTAINER", group = "local_logs")]
+    from_docker: Option<String>,
+    /// The format of the logs read with `--from-file`, `--from-journal` or
+    /// `--from-docker`. Defaults to the one of the first Loki cluster
+    #[clap(long, value_enum, value_name = "FORMAT")]
+    log_format: Option<LogFormat>,
+    /// Also use all versions of the subgraphs whose name matches this glob,
+    /// looked up in the network subgraph
+    #[clap(long, value_name = "GLOB")]
+    all_matching: Option<String>,
+    /// The IPFS hashes of the deployments, or the names of subgraphs whose
+    /// current version to use
+    #[clap(required_unless_present = "all_matching")]
+    deployments: Vec<String>,
+}
+
+impl FilterOpts {
+    /// The local logs that `--from-file`, `--from-journal` or
+    /// `--from-docker` ask for, if any
+    fn local_logs(&self) -> Option<LocalLogs> {
+        if let Some(path) = &self.from_file {
+            Some(LocalLogs::File(path.clone()))
+        } else if let Some(unit) = &self.from_journal {
+            Some(LocalLogs::Journal(unit.clone()))
+        } else {
+            self.from_docker.clone().map(LocalLogs::Docker)
+        }
     }
 
-    fn parse(root: &json::Value) -> anyhow::Result<Self> {
-        let mut children = Vec::new();
-        for (key, value) in root
-            .as_object()
-            .ok_or_else(|| anyhow!("Invalid trace: root is not an object"))?
-        {
-            if value.is_object() {
-                children.push(Self::parse_query(key, value)?);
+    /// Whether query log entries are read from the local machine rather
+    /// than from a log aggregator
+    fn reads_locally(&self) -> bool {
+        self.local_logs().is_some()
+    }
+
+    /// The filter for the entries of `deployment`
+    fn query_for(&self, deployment: &str) -> QueryFilter {
+        QueryFilter {
+            qid: self.qid.clone(),
+            min_time: self.min_time,
+            max_time: self.max_time,
+            block_from: self.block_from,
+            block_to: self.block_to,
+            since: self.since,
+            until: self.until,
+            operation: self.operation.clone(),
+            query_regex: self.query_regex.clone(),
+            deployment: deployment.to_string(),
+        }
+    }
+
+    /// Replace subgraph names in `deployments` with deployment hashes, and
+    /// add the deployments of the subgraphs that match `all_matching`.
+    /// Returns the names of the subgraphs that were looked up by
+    /// deployment hash
+    async fn resolve(
+        &mut self,
+        graph_node: &GraphNode,
+        retry: &Retry,
+        out: &mut dyn std::io::Write,
+    ) -> anyhow::Result<HashMap<String, String>> {
+        let mut deployments = Vec::new();
+        let mut names = HashMap::new();
+        for deployment in &self.deployments {
+            let hash = graph_node.resolve(deployment, retry, out).await?;
+            if &hash != deployment {
+                names.insert(hash.clone(), deployment.clone());
             }
+            deployments.push(hash);
         }
-        Ok(Self::Root {
-            query: root["query"].to_string(),
-            variables: root["variables"].to_string(),
-            query_id: root["query_id"].to_string(),
-            block: root["block"]
-                .as_u64()
-                .ok_or_else(|| anyhow!("Invalid trace: block is not a number"))?
-                as usize,
-            elapsed: Self::number_as_millis(root, "elapsed_ms")?,
-            conn_wait: Self::number_as_millis(root, "conn_wait_ms")?,
-            permit_wait: Self::number_as_millis(root, "permit_wait_ms")?,
-            children,
-        })
-    }
-
-    fn parse_query(name: &str, query: &json::Value) -> anyhow::Result<(String, Self)> {
-        let mut children = Vec::new();
-        for (key, value) in query
-            .as_object()
-            .ok_or_else(|| anyhow!("Invalid trace: {name} is not an object"))?
-        {
-            if value.is_object() {
-                children.push(Self::parse_query(key, value)?);
+        if let Some(glob) = &self.all_matching {
+            let matching = graph_node.matching(glob, retry, out).await?;
+            if matching.is_empty() {
+                return Err(anyhow!("No subgraph matches {glob}"));
             }
+            deployments.extend(matching);
         }
-        let elapsed = Self::number_as_millis(query, "elapsed_ms")?;
-        let conn_wait = Self::number_as_millis(query, "conn_wait_ms")?;
-        let permit_wait = Self::number_as_millis(query, "permit_wait_ms")?;
-        let entity_count = query["entity_count"]
-            .as_u64()
-            .ok_or_else(|| anyhow!("Invalid trace: entity count is not a number"))?
-            as usize;
-        let query = Self::Query {
-            query: query.to_string(),
-            elapsed,
-            conn_wait,
-            permit_wait,
-            entity_count,
-            children,
+        let mut seen = HashSet::new();
+        deployments.retain(|deployment| seen.insert(deployment.clone()));
+        self.deployments = deployments;
+        Ok(names)
+    }
+}
+
+/// Changes to the variables and pagination arguments of a query before it
+/// is replayed
+#[derive(Clone, Debug, Args)]
+struct VariableOpts {
+    /// The file with a JSON object of variables that replace the logged
+    /// ones, or with all the variables for a query from a file. Use `-` to
+    /// read it from stdin
+    #[clap(long, value_name = "FILE")]
+    variables_file: Option<String>,
+    /// Set the variable NAME to VALUE, which is used as a string if it is
+    /// not valid JSON. Can be given several times and takes precedence over
+    /// `--variables-file`
+    #[clap(long = "set", value_name = "NAME=VALUE", value_parser = parse_variable)]
+    set: Vec<(String, json::Value)>,
+    /// Change the `first` argument of all fields that have one to N, to see
+    /// how the query time depends on the page size
+    #[clap(long, value_name = "N")]
+    first: Option<u64>,
+    /// Change the `skip` argument of all fields that have one to N
+    #[clap(long, value_name = "N")]
+    skip: Option<u64>,
+    /// Only change `--first` and `--skip` for the fields with this name or
+    /// alias, and add them to these fields if they do not have them yet
+    #[clap(long, value_name = "NAME")]
+    field: Option<String>,
+}
+
+impl VariableOpts {
+    /// The variables from `--variables-file` and `--set`
+    fn load(&self) -> anyhow::Result<json::Map<String, json::Value>> {
+        let mut variables = match &self.variables_file {
+            Some(file) => match json::from_str(&read_input(file)?) {
+                Ok(json::Value::Object(variables)) => variables,
+                Ok(_) => return Err(anyhow!("The variables in {file} are not a JSON object")),
+                Err(e) => return Err(anyhow!("Failed to parse variables from {file}: {e}")),
+            },
+            None => json::Map::new(),
         };
-        Ok((name.to_string(), query))
+        variables.extend(self.set.iter().cloned());
+        Ok(variables)
     }
 
-    fn query_id(&self) -> &str {
-        match self {
-            Self::Root { query_id, .. } => query_id,
-            Self::Query { .. } => "none",
+    /// The changes that `--first`, `--skip` and `--field` ask for
+    fn arguments(&self) -> ArgumentOpts {
+        ArgumentOpts {
+            first: self.first,
+            skip: self.skip,
+            field: self.field.clone(),
         }
     }
-}
 
-#[derive(Deserialize, Debug)]
-struct GraphNode {
-    url: String,
-    #[serde(rename = "trace-token")]
-    trace_token: String,
+    /// Read the query for a replay from `query_file`, with `-` or a missing
+    /// file meaning stdin, and apply the variables and arguments to it
+    fn read_entry(&self, query_file: Option<&str>) -> anyhow::Result<LogEntry> {
+        let query_file = query_file.unwrap_or("-");
+        if query_file == "-" && self.variables_file.as_deref() == Some("-") {
+            return Err(anyhow!(
+                "Only one of the query and the variables can be read from stdin"
+            ));
+        }
+        let query = read_input(query_file)?.trim().to_string();
+        let mut log_entry = LogEntry {
+            timestamp: None,
+            query_id: "none".to_string(),
+            block: None,
+            query_time: 0,
+            query,
+            variables: json::Value::Object(self.load()?),
+            unpinned: None,
+        };
+        log_entry.override_arguments(&self.arguments())?;
+        Ok(log_entry)
+    }
 }
 
-impl GraphNode {
-    fn query_url(&self, deployment: &str) -> anyhow::Result<Url> {
-        let mut url = Url::parse(&self.url)?;
-        url.set_path(&format!("/subgraphs/id/{deployment}"));
-        Ok(url)
+fn parse_name(s: &str) -> anyhow::Result<String> {
+    let mut chars = s.chars();
+    let valid = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        return Err(anyhow!("Invalid name {s}: not a GraphQL name"));
     }
+    Ok(s.to_string())
+}
 
-    fn query(&self, deployment: &str, log_entry: &LogEntry) -> anyhow::Result<json::Value> {
-        let url = self.query_url(deployment)?;
-        let client = reqwest::blocking::Client::new();
-        let body = json! {
-            {
-                "query": log_entry.query,
-                "variables": log_entry.variables,
-            }
-        }
-        .to_string();
+/// Parse a `NAME=VALUE` assignment to a variable
+fn parse_variable(s: &str) -> anyhow::Result<(String, json::Value)> {
+    let (name, value) = s
+        .split_once('=')
+        .ok_or_else(|| anyhow!("Invalid variable {s}: expected NAME=VALUE"))?;
+    let value = json::from_str(value).unwrap_or_else(|_| json::Value::String(value.to_string()));
+    Ok((parse_name(name)?, value))
+}
 
-        let resp = client
-            .post(url)
-            .header("X-GraphTraceQuery", &self.trace_token)
-            .header("Content-Type", "application/json")
-            .body(body)
-            .send()
-            .map_err(|e| anyhow!("Failed to send graph-node query: {}", e))?
-            .text()
-            .map_err(|e| anyhow!("Failed to get graph-node response: {}", e))?;
-        json::from_str(&resp).map_err(|e| anyhow!("Failed to parse graph-node response: {}", e))
+/// Read `file`, or stdin if it is `-`
+fn read_input(file: &str) -> anyhow::Result<String> {
+    if file == "-" {
+        std::io::read_to_string(std::io::stdin()).map_err(|e| anyhow!("Failed to read stdin: {e}"))
+    } else {
+        std::fs::read_to_string(file).map_err(|e| anyhow!("Failed to read {file}: {e}"))
     }
 }
 
-#[derive(Deserialize, Debug)]
-struct Output {
-    trace: Option<String>,
-    data: Option<String>,
-    query: Option<String>,
-    variables: Option<String>,
+fn parse_regex(s: &str) -> anyhow::Result<Regex> {
+    Regex::new(s).map_err(|e| anyhow!("Invalid regular expression {s}: {e}"))
 }
 
-#[derive(Deserialize, Debug)]
-struct Config {
-    loki: Loki,
-    #[serde(rename = "graph-node")]
-    graph_node: GraphNode,
-    output: Option<Output>,
+/// Parse a fraction between 0 and 1, e.g., `0.25`
+fn parse_fraction(s: &str) -> anyhow::Result<f64> {
+    match s.parse::<f64>() {
+        Ok(fraction) if (0.0..=1.0).contains(&fraction) => Ok(fraction),
+        _ => Err(anyhow!(
+            "Invalid fraction {s}: expected a number between 0 and 1"
+        )),
+    }
+}
+
+/// Parse either an RFC3339 timestamp or a duration that is subtracted from
+/// the current time
+fn parse_time(s: &str) -> anyhow::Result<DateTime<Utc>> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(s) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    let ago = humantime::parse_duration(s)
+        .map_err(|_| anyhow!("Invalid time {s}: expected an RFC3339 timestamp or a duration"))?;
+    let ago = chrono::Duration::from_std(ago)?;
+    Ok(Utc::now() - ago)
 }
 
-impl Config {
-    fn load(file: &str) -> anyhow::Result<Config> {
-        let config = std::fs::read_to_string(file)?;
-        let config: Config = toml::from_str(&config)?;
-        Ok(config)
+impl Command {
+    /// Whether queries are replayed against a local graph-node
+    fn replays_locally(&self) -> bool {
+        match self {
+            Command::Fetch(opts) => opts.local,
+            Command::Replay { local, .. } => *local,
+            Command::Bench { bench, .. } => bench.query.local,
+            Command::Load { load, .. } => load.query.local,
+            _ => false,
+        }
     }
 }
 
-fn save_query(config: &Config, log_entry: &LogEntry) -> anyhow::Result<()> {
+fn save_query(config: &Config, log_entry: &LogEntry, suffix: Option<&str>) -> anyhow::Result<()> {
     if let Some(output) = &config.output {
         if let Some(query) = &output.query {
-            let mut f = File::create(query)?;
+            let mut f = File::create(suffixed_path(query, suffix))?;
             writeln!(f, "{}", log_entry.query)?;
         }
         if let Some(vars) = &output.variables {
-            let mut f = File::create(vars)?;
+            let mut f = File::create(suffixed_path(vars, suffix))?;
             writeln!(f, "{}", json::to_string_pretty(&log_entry.variables)?)?;
         }
     }
     Ok(())
 }
 
-fn save_output(opt: &Opts, config: &Config, json_output: &json::Value) -> anyhow::Result<()> {
-    let output = opt.data.as_ref().or(config
+fn save_output(
+    save: &SaveOpts,
+    config: &Config,
+    json_output: &json::Value,
+    suffix: Option<&str>,
+) -> anyhow::Result<()> {
+    let output = save.data.as_ref().or(config
         .output
         .as_ref()
         .and_then(|output| output.data.as_ref()));
 
     if let Some(output) = &output {
-        let mut f = File::create(output)?;
+        let mut f = File::create(suffixed_path(output, suffix))?;
         let json = json::to_string_pretty(&json_output["data"])?;
         writeln!(f, "{}", json)?;
     }
     Ok(())
 }
 
-fn save_trace(opt: &Opts, config: &Config, json_trace: &json::Value) -> anyhow::Result<()> {
-    let trace = opt.trace.as_ref().or(config
+fn save_trace(
+    save: &SaveOpts,
+    config: &Config,
+    json_trace: &json::Value,
+    suffix: Option<&str>,
+) -> anyhow::Result<()> {
+    let trace = save.trace.as_ref().or(config
         .output
         .as_ref()
         .and_then(|output| output.trace.as_ref()));
 
     if let Some(trace) = trace {
-        let mut f = File::create(trace)?;
-        let json = json::to_string_pretty(json_trace)?;
+        let mut json_trace = json_trace.clone();
+        ServerVersion::record(config.graph_node().version.as_ref(), &mut json_trace);
+        record_fingerprint(&mut json_trace);
+        let mut f = File::create(suffixed_path(trace, suffix))?;
+        let json = json::to_string_pretty(&json_trace)?;
         writeln!(f, "{}", json)?;
     }
     Ok(())
 }
 
-fn print_brief_trace(name: &str, trace: &Trace, indent: usize) -> Result<(), anyhow::Error> {
-    use Trace::*;
+/// Print the findings of `trace` after its brief output
+fn print_findings(trace: &Trace, display: &DisplayOpts) {
+    let findings = findings::analyze(trace, display);
+    if !findings.is_empty() {
+        println!("\nFindings:");
+        for finding in &findings {
+            println!("  {}: {}", finding.title, finding.message);
+        }
+    }
+}
+
+/// Print the findings of `trace` to stderr, for output formats that other
+/// tools read and that have no place for them
+fn eprint_findings(trace: &Trace, display: &DisplayOpts) {
+    for finding in findings::analyze(trace, display) {
+        eprintln!("{}: {}", finding.title, finding.message);
+    }
+}
 
-    fn query_time(trace: &Trace) -> Duration {
-        match trace {
-            Root { children, .. } => children.iter().map(|(_, trace)| query_time(trace)).sum(),
-            Query {
-                elapsed, children, ..
-            } => *elapsed + children.iter().map(|(_, trace)| query_time(trace)).sum(),
+/// Print the SQL that graph-node ran for each field of `trace`, with the
+/// path of response keys that leads to the field
+fn print_sql(trace: &Trace) {
+    fn print_children(path: &str, trace: &Trace, printed: &mut bool) {
+        for (name, child) in trace.children() {
+            let path = child_path(path, name);
+            if let Trace::Query {
+                sql: Some(sql),
+                params,
+                ..
+            } = child
+            {
+                println!("\n{path}:\n{}", sql::format(sql));
+                if !params.is_empty() {
+                    println!("params: {}", params.join(", "));
+                }
+                *printed = true;
+            }
+            print_children(&path, child, printed);
         }
     }
 
-    match trace {
-        Root {
-            elapsed, children, ..
-        } => {
-            let qt = query_time(trace);
-            let pt = *elapsed - qt;
+    let mut printed = false;
+    print_children("", trace, &mut printed);
+    if !printed {
+        println!("\nThe trace has no SQL; older versions of graph-node do not include it");
+    }
+}
+
+/// Print the timings of the request next to those of `trace`. What
+/// graph-node did not spend on the query itself was spent on the network or
+/// in proxies and load balancers in between
+fn print_timing(timing: &HttpTiming, trace: &Trace, display: &DisplayOpts) {
+    let phases = [
+        ("dns:", timing.dns),
+        ("connect:", timing.connect),
+        ("tls:", timing.tls),
+    ];
+    println!();
+    for (label, time) in phases {
+        if let Some(time) = time {
+            println!("{label:12}{:>9}", display.time(time));
+        }
+    }
+    let total = timing.first_byte + timing.transfer;
+    println!("{:12}{:>9}", "first byte:", display.time(timing.first_byte));
+    println!("{:12}{:>9}", "transfer:", display.time(timing.transfer));
+    println!(
+        "{:12}{:>9}",
+        "network:",
+        display.time(total.saturating_sub(trace.elapsed()))
+    );
+    println!("{:12}{}", "request id:", timing.request_id);
+}
+
+/// When several queries are traced, derive a separate file name for each of
+/// them by inserting a suffix like the query's rank before the extension
+fn suffixed_path(path: &str, suffix: Option<&str>) -> String {
+    let Some(suffix) = suffix else {
+        return path.to_string();
+    };
+    let path = std::path::Path::new(path);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!("{stem}-{suffix}.{}", ext.to_string_lossy()),
+        None => format!("{stem}-{suffix}"),
+    };
+    path.with_file_name(name).to_string_lossy().to_string()
+}
+
+/// Replay `log_entry` against graph-node, save the results and print a
+/// brief version of the trace. With `cache_compare`, replay it a second
+/// time right away and print the traces of both runs side by side instead
+async fn trace_entry(
+    save: &SaveOpts,
+    config: &Config,
+    deployment: &str,
+    log_entry: &LogEntry,
+    suffix: Option<&str>,
+    cache_compare: bool,
+    out: &mut dyn std::io::Write,
+) -> anyhow::Result<Trace> {
+    save_query(config, log_entry, suffix)?;
+
+    writeln!(out, "Querying graph-node for query trace")?;
+    let graph_node = config.graph_node();
+    let unpinned;
+    let mut log_entry = log_entry;
+    let mut pruned = false;
+    let (output, timing) = &match graph_node
+        .query_timed(deployment, log_entry, &config.retry, out)
+        .await
+    {
+        Err(e) if config.retry.unpin_pruned && GraphQlErrors::is_block_unavailable(&e) => {
+            let Some(entry) = log_entry.unpin() else {
+                return Err(e);
+            };
+            writeln!(out, "{e}\nQuerying graph-node again at the latest block")?;
+            unpinned = entry;
+            log_entry = &unpinned;
+            pruned = true;
+            graph_node
+                .query_timed(deployment, log_entry, &config.retry, out)
+                .await?
+        }
+        result => result?,
+    };
+    save_output(save, config, output, suffix)?;
+
+    let trace = &output["trace"];
+    save_trace(save, config, trace, suffix)?;
+
+    let trace = graph_node.parse_trace(trace)?;
+    if let Some(otlp) = &config.otlp {
+        otlp.export(
+            deployment,
+            log_entry,
+            &trace,
+            &timing.request_id,
+            &config.retry,
+            out,
+        )
+        .await?;
+    }
+    let mut tempo_link = None;
+    if let Some(tempo) = &config.tempo {
+        let link = tempo
+            .export(
+                deployment,
+                log_entry,
+                &trace,
+                &timing.request_id,
+                &config.retry,
+                out,
+            )
+            .await?;
+        writeln!(out, "Pushed the trace to Tempo: {link}")?;
+        tempo_link = Some(link);
+    }
+    if let Some(zipkin) = &config.zipkin {
+        zipkin
+            .export(
+                deployment,
+                log_entry,
+                &trace,
+                &timing.request_id,
+                &config.retry,
+                out,
+            )
+            .await?;
+    }
+    if let Some(path) = &save.zipkin {
+        export::save_zipkin(
+            deployment,
+            log_entry,
+            &trace,
+            &timing.request_id,
+            &suffixed_path(path, suffix),
+        )?;
+    }
+    if let Some(jaeger) = &save.jaeger {
+        export::save_jaeger(
+            deployment,
+            log_entry,
+            &trace,
+            &timing.request_id,
+            &suffixed_path(jaeger, suffix),
+        )?;
+    }
+    if let Some(flamegraph) = &save.flamegraph {
+        save_flamegraph(&trace, &suffixed_path(flamegraph, suffix))?;
+    }
+    match config.display.format {
+        TraceFormat::Brief => {}
+        TraceFormat::Speedscope => {
+            println!("{}", speedscope_profile(&trace));
+            eprint_findings(&trace, &config.display);
+            return Ok(trace);
+        }
+        TraceFormat::Chrome => {
+            println!("{}", chrome_trace(&trace));
+            eprint_findings(&trace, &config.display);
+            return Ok(trace);
+        }
+        TraceFormat::Json => {
+            let mut summary = trace_summary(&trace, &config.display);
+            summary["deployment"] = json!(deployment);
+            println!("{summary}");
+            return Ok(trace);
+        }
+        TraceFormat::Dot => {
+            print!("{}", dot_graph(&trace, &config.display));
+            return Ok(trace);
+        }
+        TraceFormat::Mermaid => {
+            print!("{}", mermaid_flowchart(&trace, &config.display));
+            return Ok(trace);
+        }
+    }
+    if let Some(format) = config.display.report {
+        report::write(
+            format,
+            &trace,
+            Some(deployment),
+            &config.display,
+            &mut std::io::stdout(),
+        )?;
+        return Ok(trace);
+    }
+    println!(
+        "Trace for qid {}\n deployment {}\n",
+        trace.query_id(),
+        deployment
+    );
+    if pruned {
+        if let Some(block) = log_entry.block {
+            println!("Replayed at the latest block since block {block} is no longer available\n");
+        }
+    }
+    if let Some(link) = &tempo_link {
+        println!("Tempo: {link}\n");
+    }
+    if !cache_compare {
+        print!("{}", brief_trace("root", &trace, 0, &config.display));
+        print_findings(&trace, &config.display);
+        print_timing(timing, &trace, &config.display);
+        if config.display.show_sql {
+            print_sql(&trace);
+        }
+        return Ok(trace);
+    }
+
+    // Whatever the first run left in graph-node's and Postgres' caches
+    // makes the second run faster
+    writeln!(out, "Querying graph-node again with warm caches")?;
+    let output = config
+        .graph_node()
+        .query(deployment, log_entry, &config.retry, out)
+        .await?;
+    let second = config.graph_node().parse_trace(&output["trace"])?;
+    let traces = [trace, second];
+    print_comparison(
+        &["first", "second"],
+        &[Some(&traces[0]), Some(&traces[1])],
+        &config.display,
+    );
+    let [trace, second] = traces;
+    if !trace.elapsed().is_zero() {
+        println!(
+            "\nThe second run took {:.0}% of the time of the first",
+            second.elapsed().as_secs_f64() * 100.0 / trace.elapsed().as_secs_f64()
+        );
+    }
+    if let Some(warning) = second.cache_warning() {
+        println!("Note: for the second run, {warning}");
+    }
+    print_timing(timing, &trace, &config.display);
+    if config.display.show_sql {
+        print_sql(&trace);
+    }
+    Ok(trace)
+}
+
+/// Trace each top-level field of `log_entry` on its own and print how long
+/// each of them took, since the trace of the whole query does not always
+/// make clear which of them is slow
+async fn trace_fields(
+    config: &Config,
+    deployment: &str,
+    log_entry: &LogEntry,
+    out: &mut dyn std::io::Write,
+) -> anyhow::Result<()> {
+    let fields = split_fields(&log_entry.query)?;
+    if fields.len() < 2 {
+        println!("\nThe query has only one top-level field");
+        return Ok(());
+    }
+    let mut times = Vec::new();
+    for (name, query) in fields {
+        let field_entry = LogEntry {
+            timestamp: log_entry.timestamp,
+            query_id: log_entry.query_id.clone(),
+            block: log_entry.block,
+            query_time: log_entry.query_time,
+            query,
+            variables: log_entry.variables.clone(),
+            unpinned: None,
+        };
+        writeln!(out, "Querying graph-node for the trace of {name}")?;
+        let output = config
+            .graph_node()
+            .query(deployment, &field_entry, &config.retry, out)
+            .await?;
+        let trace = config
+            .graph_node()
+            .parse_trace(&output["trace"])
+            .map_err(|e| anyhow!("{name}: {e}"))?;
+        times.push((name, trace.query_time(), trace.elapsed()));
+    }
+
+    let total: Duration = times.iter().map(|(_, _, elapsed)| *elapsed).sum();
+    println!(
+        "\n{:40} {:>9} {:>9} {:>6}",
+        "field", "query", "total", "share"
+    );
+    for (name, query, elapsed) in times {
+        let share = match total.as_millis() {
+            0 => 0.0,
+            total => elapsed.as_millis() as f64 * 100.0 / total as f64,
+        };
+        println!(
+            "{:40} {:>9} {:>9} {share:5.0}%",
+            truncate(&name, 40),
+            config.display.time(query),
+            config.display.time(elapsed)
+        );
+    }
+    Ok(())
+}
+
+/// Print the ranking of traced queries. Each entry also has the number of
+/// matching queries with the same shape, which is only shown if `dedup` is
+/// set since it is always 1 otherwise
+fn print_ranking(ranking: &[(&LogEntry, usize, Trace)], dedup: bool, display: &DisplayOpts) {
+    let copies = |copies: &dyn std::fmt::Display| {
+        if dedup {
+            format!(" {copies:>7}")
+        } else {
+            String::new()
+        }
+    };
+    println!(
+        "{:>4}  {:40} {:>9} {:>9}{}",
+        "rank",
+        "query_id",
+        "logged",
+        "replayed",
+        copies(&"copies")
+    );
+    for (rank, (log_entry, count, trace)) in ranking.iter().enumerate() {
+        println!(
+            "{:4}  {:40} {:>9} {:>9}{}",
+            rank + 1,
+            log_entry.query_id,
+            display.time(Duration::from_millis(log_entry.query_time)),
+            display.time(trace.elapsed()),
+            copies(count)
+        );
+    }
+}
+
+/// Shorten `text` to at most `width` characters, collapsing whitespace
+fn truncate(text: &str, width: usize) -> String {
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    if text.chars().count() <= width {
+        return text;
+    }
+    let mut text: String = text.chars().take(width - 3).collect();
+    text.push_str("...");
+    text
+}
+
+fn print_list_header() {
+    println!(
+        "{:19}  {:40} {:>10} {:>9}  query",
+        "timestamp", "query_id", "block", "time"
+    );
+}
+
+fn print_list_entry(entry: &LogEntry) {
+    let timestamp = entry
+        .timestamp
+        .map(|ts| ts.format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_else(|| "-".to_string());
+    let block = entry
+        .block
+        .map(|block| block.to_string())
+        .unwrap_or_else(|| "-".to_string());
+    println!(
+        "{:19}  {:40} {:>10} {:7}ms  {}",
+        timestamp,
+        entry.query_id,
+        block,
+        entry.query_time,
+        truncate(&entry.query, 60)
+    );
+}
+
+fn print_list(log_entries: &[LogEntry]) {
+    print_list_header();
+    for entry in log_entries {
+        print_list_entry(entry);
+    }
+}
+
+/// Print percentiles of the query times of `log_entries` and a histogram
+/// with buckets that grow in steps of 1, 2.5 and 5
+fn print_stats(deployment: &str, log_entries: &[LogEntry]) {
+    const WIDTH: usize = 50;
+
+    let Some(times) = query_times(log_entries) else {
+        return;
+    };
+    println!("deployment {deployment}: {} queries\n", times.count);
+    println!("min:  {:7}ms", times.min);
+    println!("mean: {:7}ms", times.mean);
+    for (p, time) in &times.percentiles {
+        println!("p{p}:  {time:7}ms");
+    }
+    println!("max:  {:7}ms\n", times.max);
+
+    let most = times
+        .histogram
+        .iter()
+        .map(|(_, count)| *count)
+        .max()
+        .unwrap_or(1);
+    println!("{:>10} {:>7}", "below", "count");
+    for (bound, count) in &times.histogram {
+        let bar = "#".repeat((count * WIDTH).div_ceil(most));
+        println!("{bound:>8}ms {count:7} {bar}");
+    }
+}
+
+/// Poll all Loki clusters for new matching entries and print them as they
+/// show up. Log lines can reach Loki a while after they were written, so
+/// every poll looks back a bit and skips entries it has already printed
+async fn tail(
+    config: &Config,
+    filter: &FilterOpts,
+    interval: Duration,
+    out: &mut dyn std::io::Write,
+) -> anyhow::Result<()> {
+    const LOOKBACK: chrono::Duration = chrono::Duration::minutes(1);
+
+    if filter.reads_locally() {
+        return Err(anyhow!("Tailing only works with Loki, not with local logs"));
+    }
+    if config.lokis().is_empty() {
+        return Err(anyhow!("The configuration has no [loki] section"));
+    }
+    let [deployment] = filter.deployments.as_slice() else {
+        return Err(anyhow!("Tailing only works with a single deployment"));
+    };
+
+    let query_filter = filter.query_for(deployment);
+    let queries: Vec<_> = config
+        .lokis()
+        .iter()
+        .map(|loki| (loki, loki.logql(&query_filter)))
+        .collect();
+    let mut export = filter.export_log.as_ref().map(File::create).transpose()?;
+    let mut start = query_filter.since.unwrap_or_else(Utc::now);
+    let mut seen = HashSet::new();
+    print_list_header();
+    loop {
+        let end = Utc::now();
+        let range = TimeRange { start, end };
+        let mut entries = Vec::new();
+        for (loki, query) in &queries {
+            let page = loki
+                .query_page(query, Some(&range), loki.page_size, &config.retry, out)
+                .await?;
+            // Not all filters can be applied in LogQL
+            entries.extend(page.into_iter().filter(|entry| query_filter.matches(entry)));
+        }
+        entries.sort_by_key(|entry| entry.timestamp);
+        for entry in entries {
+            if seen.insert((entry.timestamp, entry.query_id.clone())) {
+                print_list_entry(&entry);
+                if let Some(export) = &mut export {
+                    writeln!(export, "{}", entry.to_json())?;
+                }
+            }
+        }
+        std::io::stdout().flush()?;
+        if let Some(export) = &mut export {
+            export.flush()?;
+        }
+
+        start = end - LOOKBACK;
+        seen.retain(|(timestamp, _)| timestamp.is_some_and(|timestamp| timestamp >= start));
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// The query log entries found for one deployment
+struct Section {
+    deployment: String,
+    entries: anyhow::Result<Vec<LogEntry>>,
+    /// What was printed while searching, which is held back until the
+    /// section is printed so that deployments do not get mixed up
+    log: Vec<u8>,
+}
+
+impl Section {
+    /// Print the heading of the section if there are several, followed by
+    /// what was printed while searching, and return the entries
+    fn begin(self, multiple: bool, out: &mut dyn std::io::Write) -> anyhow::Result<Vec<LogEntry>> {
+        if multiple {
+            println!("== {} ==\n", self.deployment);
+        }
+        out.write_all(&self.log)?;
+        self.entries
+    }
+}
+
+/// Search the query log for up to `limit` entries for each deployment in
+/// `filter`, all at the same time
+async fn find_sections(
+    filter: &FilterOpts,
+    config: &Config,
+    limit: usize,
+) -> anyhow::Result<Vec<Section>> {
+    let searches = filter.deployments.iter().map(|deployment| async move {
+        let query = filter.query_for(deployment);
+        let local = filter.local_logs();
+        let mut log = Vec::new();
+        let entries = find_entries(
+            &query,
+            local.as_ref(),
+            filter.log_format,
+            config,
+            limit,
+            &mut log,
+        )
+        .await;
+        Section {
+            deployment: deployment.clone(),
+            entries,
+            log,
+        }
+    });
+    let mut sections = join_all(searches).await;
+    if let Some(persisted) = &config.persisted_queries {
+        for section in &mut sections {
+            if let Ok(entries) = &mut section.entries {
+                let result = persisted
+                    .resolve(entries, &config.retry, &mut section.log)
+                    .await;
+                if let Err(e) = result {
+                    section.entries = Err(e);
+                }
+            }
+        }
+    }
+    if let Some(path) = &filter.export_log {
+        let mut f = File::create(path)?;
+        for section in &sections {
+            for entry in section.entries.iter().flatten() {
+                writeln!(f, "{}", entry.to_json())?;
+            }
+        }
+    }
+    Ok(sections)
+}
+
+/// Keeps track of the deployments for which a command failed. With several
+/// deployments, an error for one of them is printed and the command goes
+/// on with the next one
+struct Failures {
+    multiple: bool,
+    count: usize,
+    failed: usize,
+}
+
+impl Failures {
+    fn new(count: usize) -> Self {
+        Failures {
+            multiple: count > 1,
+            count,
+            failed: 0,
+        }
+    }
+
+    /// Record the result for one deployment. With several deployments,
+    /// this also ends its section, and a failure leaves nothing to return
+    fn check<T: Default>(&mut self, result: anyhow::Result<T>) -> anyhow::Result<T> {
+        match result {
+            Ok(value) if self.multiple => {
+                println!();
+                Ok(value)
+            }
+            Err(e) if self.multiple => {
+                println!("{e}\n");
+                self.failed += 1;
+                Ok(T::default())
+            }
+            result => result,
+        }
+    }
+
+    fn finish(self) -> anyhow::Result<()> {
+        if self.failed > 0 {
+            return Err(anyhow!(
+                "Failed for {} of {} deployments",
+                self.failed,
+                self.count
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Print the entries found for each deployment
+async fn list(
+    config: &Config,
+    filter: &FilterOpts,
+    limit: usize,
+    out: &mut dyn std::io::Write,
+) -> anyhow::Result<()> {
+    let sections = find_sections(filter, config, limit).await?;
+    let mut failures = Failures::new(sections.len());
+    for section in sections {
+        let result = section
+            .begin(failures.multiple, out)
+            .map(|entries| print_list(&entries));
+        failures.check(result)?;
+    }
+    failures.finish()
+}
+
+/// Print a summary of the query times for each deployment
+async fn stats(
+    config: &Config,
+    filter: &FilterOpts,
+    limit: usize,
+    out: &mut dyn std::io::Write,
+) -> anyhow::Result<()> {
+    let sections = find_sections(filter, config, limit).await?;
+    let mut failures = Failures::new(sections.len());
+    for section in sections {
+        let deployment = section.deployment.clone();
+        let result = section
+            .begin(failures.multiple, out)
+            .map(|entries| print_stats(&deployment, &entries));
+        failures.check(result)?;
+    }
+    failures.finish()
+}
+
+async fn fetch(
+    config: &Config,
+    opts: &FetchOpts,
+    out: &mut dyn std::io::Write,
+) -> anyhow::Result<Vec<Trace>> {
+    // Loki can neither sort by query time nor pick random entries; look at
+    // as many entries as Loki returns by default and choose ourselves
+    let limit = if opts.top.is_some() || opts.sample.is_some() {
+        5000
+    } else {
+        1
+    };
+    let overrides = opts.variables.load()?;
+    let sections = find_sections(&opts.filter, config, limit).await?;
+    let mut failures = Failures::new(sections.len());
+    let mut traces = Vec::new();
+    for section in sections {
+        let deployment = section.deployment.clone();
+        let multiple = failures.multiple;
+        let result = async {
+            let log_entries = section.begin(multiple, out)?;
+            fetch_deployment(
+                config,
+                opts,
+                &deployment,
+                log_entries,
+                &overrides,
+                multiple,
+                out,
+            )
+            .await
+        }
+        .await;
+        traces.extend(failures.check(result)?);
+    }
+    failures.finish()?;
+    Ok(traces)
+}
+
+/// Trace the entries that `fetch` found for `deployment` with the variables
+/// in `overrides` changed, and return the traces. With several
+/// deployments, the deployment is added to the names of the files that
+/// results are saved in
+async fn fetch_deployment(
+    config: &Config,
+    opts: &FetchOpts,
+    deployment: &str,
+    mut log_entries: Vec<LogEntry>,
+    overrides: &json::Map<String, json::Value>,
+    multiple: bool,
+    out: &mut dyn std::io::Write,
+) -> anyhow::Result<Vec<Trace>> {
+    let FetchOpts {
+        top,
+        dedup,
+        sample,
+        pin_block,
+        cache_compare,
+        split_fields,
+        save,
+        ..
+    } = opts;
+    let suffix = |rank: Option<usize>| match (multiple, rank) {
+        (true, Some(rank)) => Some(format!("{deployment}-{rank}")),
+        (true, None) => Some(deployment.to_string()),
+        (false, rank) => rank.map(|rank| rank.to_string()),
+    };
+    // Change the entries that are traced to what should be replayed
+    let prepare = |log_entry: &mut LogEntry| {
+        log_entry.override_variables(overrides);
+        log_entry.override_arguments(&opts.variables.arguments())?;
+        if *pin_block {
+            log_entry.pin_block()?;
+        }
+        Ok::<_, anyhow::Error>(())
+    };
+
+    let count = match (top, sample) {
+        (Some(top), _) => *top,
+        (None, Some(sample)) => {
+            writeln!(
+                out,
+                "Sampling {sample} of {} log entries",
+                log_entries.len()
+            )?;
+            log_entries.shuffle(&mut rand::rng());
+            log_entries.truncate(*sample);
+            *sample
+        }
+        (None, None) => {
+            let suffix = suffix(None);
+            prepare(&mut log_entries[0])?;
+            let trace = trace_entry(
+                save,
+                config,
+                deployment,
+                &log_entries[0],
+                suffix.as_deref(),
+                *cache_compare,
+                out,
+            )
+            .await?;
+            if *split_fields {
+                trace_fields(config, deployment, &log_entries[0], out).await?;
+            }
+            return Ok(vec![trace]);
+        }
+    };
+
+    log_entries.sort_by_key(|entry| std::cmp::Reverse(entry.query_time));
+    // The number of entries with the same shape as each entry
+    let mut counts = vec![1; log_entries.len()];
+    if *dedup {
+        let mut shapes: HashMap<String, usize> = HashMap::new();
+        let mut unique = Vec::new();
+        counts.clear();
+        for entry in log_entries {
+            match shapes.entry(query_shape(&entry.query)) {
+                Entry::Occupied(index) => counts[*index.get()] += 1,
+                Entry::Vacant(index) => {
+                    index.insert(unique.len());
+                    unique.push(entry);
+                    counts.push(1);
+                }
+            }
+        }
+        writeln!(out, "Found {} different query shapes", unique.len())?;
+        log_entries = unique;
+    }
+    log_entries.truncate(count);
+    for log_entry in &mut log_entries {
+        prepare(log_entry)?;
+    }
+    let mut ranking = Vec::new();
+    for (rank, (log_entry, count)) in log_entries.iter().zip(counts).enumerate() {
+        let suffix = suffix(Some(rank + 1));
+        let trace = trace_entry(
+            save,
+            config,
+            deployment,
+            log_entry,
+            suffix.as_deref(),
+            *cache_compare,
+            out,
+        )
+        .await?;
+        if *split_fields {
+            trace_fields(config, deployment, log_entry, out).await?;
+        }
+        println!();
+        ranking.push((log_entry, count, trace));
+    }
+    print_ranking(&ranking, *dedup, &config.display);
+    Ok(ranking.into_iter().map(|(_, _, trace)| trace).collect())
+}
+
+/// Benchmark `log_entry` as `opts` says, print the results and return
+/// them as JSON
+async fn bench_entry(
+    config: &Config,
+    opts: &BenchOpts,
+    deployment: &str,
+    log_entry: &LogEntry,
+    out: &mut dyn std::io::Write,
+) -> anyhow::Result<json::Value> {
+    let result = bench(config, &opts.schedule(), deployment, log_entry, out).await?;
+    let runs = result.times.len() + result.errors;
+    let elapsed = result.elapsed.as_secs_f64();
+    println!(
+        "deployment {deployment}: {} runs, {} errors ({:.1}%) in {elapsed:.1}s, {:.1} runs/s",
+        result.times.len(),
+        result.errors,
+        result.errors as f64 * 100.0 / runs.max(1) as f64,
+        result.times.len() as f64 / elapsed
+    );
+    for (name, time) in result.stats() {
+        println!("{:7} {time:9.1}ms", format!("{name}:"));
+    }
+    Ok(result.to_json())
+}
+
+/// Replay a query for each deployment in `filter` over and over and report
+/// how long it took
+async fn run_bench(
+    config: &Config,
+    opts: &BenchOpts,
+    filter: &FilterOpts,
+    out: &mut dyn std::io::Write,
+) -> anyhow::Result<()> {
+    let mut results = Vec::new();
+    let mut failures = Failures::new(filter.deployments.len());
+    if let Some(query_file) = &opts.query.query_file {
+        let log_entry = opts.query.variables.read_entry(Some(query_file))?;
+        for deployment in &filter.deployments {
+            if failures.multiple {
+                println!("== {deployment} ==\n");
+            }
+            let result = bench_entry(config, opts, deployment, &log_entry, out)
+                .await
+                .map(|result| results.push(result));
+            failures.check(result)?;
+        }
+    } else {
+        let overrides = opts.query.variables.load()?;
+        for section in find_sections(filter, config, 1).await? {
+            let deployment = section.deployment.clone();
+            let multiple = failures.multiple;
+            let result = async {
+                let mut log_entries = section.begin(multiple, out)?;
+                log_entries[0].override_variables(&overrides);
+                log_entries[0].override_arguments(&opts.query.variables.arguments())?;
+                if opts.query.pin_block {
+                    log_entries[0].pin_block()?;
+                }
+                let result = bench_entry(config, opts, &deployment, &log_entries[0], out).await?;
+                results.push(result);
+                Ok(())
+            }
+            .await;
+            failures.check(result)?;
+        }
+    }
+    if let Some(path) = &opts.query.json {
+        let mut f = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| anyhow!("Failed to open {path}: {e}"))?;
+        for result in &results {
+            writeln!(f, "{result}")?;
+        }
+    }
+    failures.finish()
+}
+
+fn analyze(file: &str, flamegraph: Option<&str>, display: &DisplayOpts) -> anyhow::Result<Trace> {
+    let trace = load_trace(file)?;
+    if let Some(flamegraph) = flamegraph {
+        save_flamegraph(&trace, flamegraph)?;
+    }
+    match display.format {
+        TraceFormat::Brief => {}
+        TraceFormat::Speedscope => {
+            println!("{}", speedscope_profile(&trace));
+            eprint_findings(&trace, display);
+            return Ok(trace);
+        }
+        TraceFormat::Chrome => {
+            println!("{}", chrome_trace(&trace));
+            eprint_findings(&trace, display);
+            return Ok(trace);
+        }
+        TraceFormat::Json => {
+            println!("{}", trace_summary(&trace, display));
+            return Ok(trace);
+        }
+        TraceFormat::Dot => {
+            print!("{}", dot_graph(&trace, display));
+            return Ok(trace);
+        }
+        TraceFormat::Mermaid => {
+            print!("{}", mermaid_flowchart(&trace, display));
+            return Ok(trace);
+        }
+    }
+    if let Some(format) = display.report {
+        report::write(format, &trace, None, display, &mut std::io::stdout())?;
+        return Ok(trace);
+    }
+    println!("Trace for qid {}\n", trace.query_id());
+    print!("{}", brief_trace("root", &trace, 0, display));
+    print_findings(&trace, display);
+    if display.show_sql {
+        print_sql(&trace);
+    }
+    Ok(trace)
+}
+
+fn compare(first: &str, second: &str, display: &DisplayOpts) -> anyhow::Result<()> {
+    let row = |label: &str, first: Duration, second: Duration| {
+        println!(
+            "{label:6} {:>9} {:>9} {:>9}",
+            display.time(first),
+            display.time(second),
+            display.time_delta(first, second)
+        );
+    };
+
+    let first_trace = load_trace(first)?;
+    let second_trace = load_trace(second)?;
+    println!("first:  {first} (qid {})", first_trace.query_id());
+    println!("second: {second} (qid {})\n", second_trace.query_id());
+    println!("{:6} {:>9} {:>9} {:>9}", "", "first", "second", "delta");
+    let (first_query, second_query) = (first_trace.query_time(), second_trace.query_time());
+    let (first_total, second_total) = (first_trace.elapsed(), second_trace.elapsed());
+    row("query:", first_query, second_query);
+    row(
+        "other:",
+        first_total.saturating_sub(first_query),
+        second_total.saturating_sub(second_query),
+    );
+    row("total:", first_total, second_total);
+    Ok(())
+}
+
+/// Format one row of a comparison of several traces: the time from the
+/// first trace, followed by the time from each other trace and how much it
+/// differs from the first one. Traces that do not have the row are shown
+/// as `-`
+fn comparison_row(label: &str, times: &[Option<Duration>], display: &DisplayOpts) -> String {
+    let mut row = label.to_string();
+    for (i, time) in times.iter().enumerate() {
+        match time {
+            Some(time) => row.push_str(&format!(" {:>9}", display.time(*time))),
+            None => row.push_str(&format!(" {:>9}", "-")),
+        }
+        if i > 0 {
+            match (times[0], time) {
+                (Some(first), Some(time)) => {
+                    row.push_str(&format!(" {:>9}", display.time_delta(first, *time)));
+                }
+                _ => row.push_str(&format!(" {:>9}", "-")),
+            }
+        }
+    }
+    row
+}
+
+/// Print the tree of the first of `traces` that exists with the time that
+/// each node took in each of the traces next to each other
+fn print_comparison_tree(
+    name: &str,
+    traces: &[Option<&Trace>],
+    indent: usize,
+    display: &DisplayOpts,
+) {
+    let label = format!(
+        "{space:indent$}{name:rest$}",
+        space = "",
+        rest = 48usize.saturating_sub(indent)
+    );
+    let times: Vec<_> = traces
+        .iter()
+        .map(|trace| trace.map(Trace::elapsed))
+        .collect();
+    println!("{}", comparison_row(&label, &times, display));
+    if let Some(first) = traces.iter().flatten().next() {
+        for (child, _) in first.children() {
+            let children: Vec<_> = traces
+                .iter()
+                .map(|trace| trace.and_then(|trace| trace.child(child)))
+                .collect();
+            print_comparison_tree(child, &children, indent + 2, display);
+        }
+    }
+}
+
+/// Print the trees of `traces` side by side under their `labels`, followed
+/// by the totals for each of them. Missing traces are shown as `-`
+fn print_comparison(labels: &[impl AsRef<str>], traces: &[Option<&Trace>], display: &DisplayOpts) {
+    let mut header = format!("{:48}", "");
+    for (i, label) in labels.iter().enumerate() {
+        header.push_str(&format!(" {:>9}", truncate(label.as_ref(), 9)));
+        if i > 0 {
+            header.push_str(&format!(" {:>9}", "delta"));
+        }
+    }
+    println!("{header}");
+    print_comparison_tree("root", traces, 0, display);
+    println!();
+    let query: Vec<_> = traces
+        .iter()
+        .map(|trace| trace.map(Trace::query_time))
+        .collect();
+    let other: Vec<_> = traces
+        .iter()
+        .map(|trace| trace.map(|trace| trace.elapsed().saturating_sub(trace.query_time())))
+        .collect();
+    let total: Vec<_> = traces
+        .iter()
+        .map(|trace| trace.map(Trace::elapsed))
+        .collect();
+    println!(
+        "{}",
+        comparison_row(&format!("{:48}", "query:"), &query, display)
+    );
+    println!(
+        "{}",
+        comparison_row(&format!("{:48}", "other:"), &other, display)
+    );
+    println!(
+        "{}",
+        comparison_row(&format!("{:48}", "total:"), &total, display)
+    );
+}
+
+/// Print the fields of `first` and `second` matched up by their path, with
+/// how much longer or shorter each of them took in `second`, followed by
+/// the fields that only one of them has
+fn print_trace_diff(labels: [&str; 2], first: &Trace, second: &Trace, display: &DisplayOpts) {
+    let row = |path: &str, first: Duration, second: Duration| {
+        let change = if first.is_zero() {
+            "-".to_string()
+        } else {
+            format!("{:+.1}%", percent_of(second, first) - 100.0)
+        };
+        println!(
+            "{} {:>9} {:>9} {:>9} {:>8}",
+            fit(path, 48),
+            display.time(first),
+            display.time(second),
+            display.time_delta(first, second),
+            change
+        );
+    };
+
+    if first.fingerprint() != second.fingerprint() {
+        println!(
+            "Note: the traces are of different queries, with fingerprints {} and {}\n",
+            or_dash(first.fingerprint()),
+            or_dash(second.fingerprint())
+        );
+    }
+    let diff = diff_traces(first, second, display.sort);
+    println!(
+        "{:48} {:>9} {:>9} {:>9} {:>8}",
+        "",
+        truncate(labels[0], 9),
+        truncate(labels[1], 9),
+        "delta",
+        "change"
+    );
+    for (path, first, second) in &diff.both {
+        row(path, *first, *second);
+    }
+    for (label, only) in labels.iter().zip(&diff.only) {
+        if only.is_empty() {
+            continue;
+        }
+        println!("\nOnly in {label}:");
+        for (path, elapsed) in only {
+            println!("  {} {:>9}", fit(path, 46), display.time(*elapsed));
+        }
+    }
+}
+
+/// Print the mean and 95th percentile of the time and the entities of each
+/// field across the traces in each group of `aggregate`
+fn print_aggregate(aggregate: &Aggregate, display: &DisplayOpts) {
+    let row = |path: &str, stats: &FieldStats| {
+        let (mean, p95) = stats.time();
+        let (entities, entities_p95) = match stats.entities() {
+            Some((mean, p95)) => (format!("{mean:.1}"), p95.to_string()),
+            None => ("-".to_string(), "-".to_string()),
+        };
+        println!(
+            "{} {:>6} {:>11} {:>9} {:>10} {:>9}",
+            fit(path, 48),
+            stats.times.len(),
+            display.time(Duration::from_secs_f64(mean / 1e6)),
+            display.time(Duration::from_micros(p95)),
+            entities,
+            entities_p95
+        );
+    };
+
+    let groups = &aggregate.groups;
+    println!(
+        "{} traces of {} different queries\n",
+        aggregate.files,
+        groups.len()
+    );
+    for (i, group) in groups.iter().enumerate() {
+        println!(
+            "Query {} of {} (fingerprint {}): {} traces",
+            i + 1,
+            groups.len(),
+            group.fingerprint,
+            group.traces.len()
+        );
+        for line in group.query.trim().lines() {
+            println!("  {line}");
+        }
+        println!();
+        println!(
+            "{:48} {:>6} {:>11} {:>9} {:>10} {:>9}",
+            "", "traces", "mean", "p95", "entities", "p95"
+        );
+        row("root", &group.root);
+        for (path, stats) in &group.fields {
+            row(path, stats);
+        }
+        println!();
+    }
+}
+
+/// Print the diff of the traces in the files `first` and `second`
+fn diff(first: &str, second: &str, display: &DisplayOpts) -> anyhow::Result<()> {
+    let first_trace = load_trace(first)?;
+    let second_trace = load_trace(second)?;
+    println!("first:  {first} (qid {})", first_trace.query_id());
+    println!("second: {second} (qid {})\n", second_trace.query_id());
+    print_trace_diff(["first", "second"], &first_trace, &second_trace, display);
+    Ok(())
+}
+
+/// Print how the data and errors that each of `targets` returned differ
+/// from what the first one returned. It is an error if any of them differ
+fn report_data_diffs(targets: &[String], outputs: &[json::Value]) -> anyhow::Result<()> {
+    /// The maximum number of differences to print for each target
+    const MAX_DIFFS: usize = 20;
+
+    let mut diverged = Vec::new();
+    for (target, output) in targets.iter().zip(outputs).skip(1) {
+        let diffs = response_diffs(&outputs[0], output);
+        if diffs.is_empty() {
+            println!("{target} returned the same data as {}", targets[0]);
+            continue;
+        }
+        println!("{target} returned different data than {}:", targets[0]);
+        for diff in diffs.iter().take(MAX_DIFFS) {
+            println!("  {diff}");
+        }
+        if diffs.len() > MAX_DIFFS {
+            println!("  and {} more differences", diffs.len() - MAX_DIFFS);
+        }
+        diverged.push(target.as_str());
+    }
+    if !diverged.is_empty() {
+        return Err(anyhow!(
+            "{} returned different data than {}",
+            diverged.join(", "),
+            targets[0]
+        ));
+    }
+    Ok(())
+}
+
+/// Replay the most recent matching query for each deployment against each
+/// of the graph-node endpoints in `targets` and print the traces side by
+/// side, or with `diff` the diff of the traces from the two of them
+async fn compare_targets(
+    config: &Config,
+    targets: &[String],
+    filter: &FilterOpts,
+    diff: bool,
+    out: &mut dyn std::io::Write,
+) -> anyhow::Result<()> {
+    let endpoints = targets
+        .iter()
+        .map(|target| config.target(target))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let sections = find_sections(filter, config, 1).await?;
+    let mut failures = Failures::new(sections.len());
+    for section in sections {
+        let deployment = section.deployment.clone();
+        let multiple = failures.multiple;
+        let result = async {
+            let log_entries = section.begin(multiple, out)?;
+            let mut outputs = Vec::new();
+            let mut traces = Vec::new();
+            let mut requests = Vec::new();
+            for (target, endpoint) in targets.iter().zip(&endpoints) {
+                writeln!(out, "Querying {target} for query trace")?;
+                let replay = endpoint
+                    .replay(&deployment, &log_entries[0], &config.retry, out)
+                    .await
+                    .map_err(|e| anyhow!("{target}: {e}"))?;
+                traces.push(replay.trace);
+                outputs.push(replay.output);
+                requests.push(Some(replay.timing.first_byte + replay.timing.transfer));
+            }
 
             println!(
-                "{space:indent$}{name:rest$} {elapsed:7}ms",
-                space = " ",
-                indent = indent,
-                rest = 48 - indent,
-                name = name,
-                elapsed = elapsed.as_millis(),
+                "Trace for qid {}\n deployment {deployment}\n",
+                log_entries[0].query_id
             );
-            for (name, trace) in children {
-                print_brief_trace(name, trace, indent + 2)?;
+            let traces: Vec<_> = traces.iter().map(Option::as_ref).collect();
+            if diff {
+                let [Some(first), Some(second)] = traces[..] else {
+                    return Err(anyhow!(
+                        "diff needs traces, which the gateway does not return"
+                    ));
+                };
+                print_trace_diff([&targets[0], &targets[1]], first, second, &config.display);
+                println!();
+                return report_data_diffs(targets, &outputs);
             }
-            println!("\nquery:      {:7}ms", qt.as_millis());
-            println!("other:      {:7}ms", pt.as_millis());
-            println!("total:      {:7}ms", elapsed.as_millis())
-        }
-        Query {
-            elapsed,
-            entity_count,
-            children,
-            ..
-        } => {
+            print_comparison(targets, &traces, &config.display);
+            // What the client saw, which is all there is for the gateway
             println!(
-                "{space:indent$}{name:rest$} {elapsed:7}ms [{count:7} entities]",
-                space = " ",
-                indent = indent,
-                rest = 50 - indent,
-                name = name,
-                elapsed = elapsed.as_millis(),
-                count = entity_count
+                "{}",
+                comparison_row(&format!("{:48}", "request:"), &requests, &config.display)
             );
-            for (name, trace) in children {
-                print_brief_trace(name, trace, indent + 2)?;
+            println!();
+            report_data_diffs(targets, &outputs)
+        }
+        .await;
+        failures.check(result)?;
+    }
+    failures.finish()
+}
+
+/// Print the requests that `rerun` would send for the queries in `corpus`
+fn describe_corpus(config: &Config, corpus: &str) -> anyhow::Result<()> {
+    for recording in read_corpus(corpus)? {
+        println!("== {} ==\n", recording.path.display());
+        println!(
+            "Would send\n{}\n",
+            config
+                .graph_node()
+                .describe(&recording.deployment, Some(&recording.log_entry))?
+        );
+    }
+    Ok(())
+}
+
+/// Replay up to `limit` matching queries for each deployment in `filter`
+/// and save them in the `corpus` directory
+async fn record_corpus(
+    config: &Config,
+    filter: &FilterOpts,
+    limit: usize,
+    pin_block: bool,
+    corpus: &str,
+    out: &mut dyn std::io::Write,
+) -> anyhow::Result<()> {
+    std::fs::create_dir_all(corpus).map_err(|e| anyhow!("Failed to create {corpus}: {e}"))?;
+    let sections = find_sections(filter, config, limit).await?;
+    let mut failures = Failures::new(sections.len());
+    for section in sections {
+        let deployment = section.deployment.clone();
+        let multiple = failures.multiple;
+        let result = async {
+            for (rank, mut log_entry) in section.begin(multiple, out)?.into_iter().enumerate() {
+                if pin_block {
+                    log_entry.pin_block()?;
+                }
+                let (path, elapsed) =
+                    record(config, &deployment, &log_entry, rank + 1, corpus, out).await?;
+                println!("{:>9} {}", config.display.time(elapsed), path.display());
+            }
+            Ok(())
+        }
+        .await;
+        failures.check(result)?;
+    }
+    failures.finish()
+}
+
+/// Replay all queries in `corpus` and print how much longer or shorter
+/// they took than when they were recorded. It is an error if any of them
+/// returned different data
+async fn rerun_corpus(
+    config: &Config,
+    corpus: &str,
+    out: &mut dyn std::io::Write,
+) -> anyhow::Result<()> {
+    /// The maximum number of differences to print for each query
+    const MAX_DIFFS: usize = 5;
+
+    let recordings = read_corpus(corpus)?;
+    println!(
+        "{:>9} {:>9} {:>9} {:>7} {:7}  query",
+        "recorded", "now", "delta", "change", "data"
+    );
+    let mut failed = 0;
+    let mut diverged = Vec::new();
+    for recording in &recordings {
+        let name = recording
+            .path
+            .file_stem()
+            .unwrap_or_default()
+            .to_string_lossy();
+        let before = recording.trace.elapsed();
+        let rerun = match rerun(config, recording, out).await {
+            Ok(rerun) => rerun,
+            Err(e) => {
+                println!("{:>9} {:>9}  {name}: {e}", config.display.time(before), "-");
+                failed += 1;
+                continue;
+            }
+        };
+        let now = rerun.trace.elapsed();
+        let change = if before.is_zero() {
+            "-".to_string()
+        } else {
+            format!("{:+.0}%", percent_of(now, before) - 100.0)
+        };
+        println!(
+            "{:>9} {:>9} {:>9} {change:>7} {:7}  {name}",
+            config.display.time(before),
+            config.display.time(now),
+            config.display.time_delta(before, now),
+            if rerun.diffs.is_empty() {
+                "same"
+            } else {
+                "differs"
             }
+        );
+        if !rerun.diffs.is_empty() {
+            diverged.push((name, rerun.diffs));
         }
     }
 
+    for (name, diffs) in &diverged {
+        println!("\n{name} returned different data than when it was recorded:");
+        for diff in diffs.iter().take(MAX_DIFFS) {
+            println!("  {diff}");
+        }
+        if diffs.len() > MAX_DIFFS {
+            println!("  and {} more differences", diffs.len() - MAX_DIFFS);
+        }
+    }
+    match (failed, diverged.len()) {
+        (0, 0) => Ok(()),
+        (failed, diverged) => Err(anyhow!(
+            "Of {} queries, {failed} failed and {diverged} returned different data",
+            recordings.len()
+        )),
+    }
+}
+
+/// Print what `cmd` would do, as far as that is possible without sending
+/// any requests
+fn dry_run(config: &Config, cmd: &Command) -> anyhow::Result<()> {
+    let (filter, log_entry, endpoints) = match cmd {
+        Command::Fetch(opts) => (
+            Some(&opts.filter),
+            None,
+            vec![Target::GraphNode(config.graph_node())],
+        ),
+        Command::List { filter, .. }
+        | Command::Stats { filter, .. }
+        | Command::Tail { filter, .. } => (Some(filter), None, Vec::new()),
+        Command::Record { filter, .. } => (
+            Some(filter),
+            None,
+            vec![Target::GraphNode(config.graph_node())],
+        ),
+        Command::Rerun { corpus } => return describe_corpus(config, corpus),
+        Command::Bench {
+            bench: BenchOpts { query, .. },
+            filter,
+        }
+        | Command::Load {
+            load: LoadOpts { query, .. },
+            filter,
+        } => match &query.query_file {
+            Some(query_file) => {
+                let log_entry = query.variables.read_entry(Some(query_file))?;
+                (
+                    None,
+                    Some(log_entry),
+                    vec![Target::GraphNode(config.graph_node())],
+                )
+            }
+            None => (
+                Some(filter),
+                None,
+                vec![Target::GraphNode(config.graph_node())],
+            ),
+        },
+        Command::Replay {
+            query_file,
+            variables,
+            block,
+            ..
+        } => {
+            let mut log_entry = variables.read_entry(query_file.as_deref())?;
+            if let Some(block) = block {
+                log_entry.block = Some(*block);
+                log_entry.pin_block()?;
+            }
+            (
+                None,
+                Some(log_entry),
+                vec![Target::GraphNode(config.graph_node())],
+            )
+        }
+        Command::Compare { targets, filter } | Command::Diff { targets, filter } => {
+            let endpoints = targets
+                .iter()
+                .map(|target| config.target(target))
+                .collect::<anyhow::Result<_>>()?;
+            (Some(filter), None, endpoints)
+        }
+        Command::Analyze { .. } | Command::Aggregate { .. } => return Ok(()),
+    };
+    let deployments = match (cmd, filter) {
+        (Command::Replay { deployment, .. }, _) => vec![deployment.clone()],
+        (_, Some(filter)) => filter.deployments.clone(),
+        (_, None) => Vec::new(),
+    };
+
+    if let Some(glob) = filter.and_then(|filter| filter.all_matching.as_ref()) {
+        println!("Would add the subgraphs matching {glob} from the network subgraph\n");
+    }
+    if cmd.replays_locally() {
+        println!("Would start a graph-node with Docker and send queries to it instead\n");
+    }
+    let default = Pattern::default();
+    let pattern = config
+        .lokis()
+        .first()
+        .map(|loki| &loki.pattern)
+        .unwrap_or(&default);
+    for deployment in &deployments {
+        println!("== {deployment} ==\n");
+        if let Some(filter) = filter {
+            let query = filter.query_for(deployment);
+            let logs = filter.local_logs();
+            let local = logs
+                .as_ref()
+                .map(|logs| local_source(logs, filter.log_format, pattern));
+            let sources = match &local {
+                Some(local) => vec![local.as_ref()],
+                None => config.sources(),
+            };
+            for source in sources {
+                println!("Would search {}\n", source.describe(&query));
+            }
+        }
+        for endpoint in &endpoints {
+            println!(
+                "Would send\n{}\n",
+                endpoint.describe(deployment, log_entry.as_ref())?
+            );
+        }
+    }
     Ok(())
 }
 
-fn main() -> anyhow::Result<()> {
+#[tokio::main]
+async fn main() -> anyhow::Result<ExitCode> {
     let opt = Opts::parse();
-    let config = Config::load(&opt.config)?;
+    let fail_on_findings = opt.fail_on_findings;
+    let display = opt.display.opts();
+    let traces = run(opt, &display).await?;
+    if fail_on_findings
+        && traces
+            .iter()
+            .any(|trace| !findings::analyze(trace, &display).is_empty())
+    {
+        return Ok(ExitCode::from(3));
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Run the command that `opt` asks for and return the traces that it
+/// printed, which `--fail-on-findings` checks
+async fn run(mut opt: Opts, display: &DisplayOpts) -> anyhow::Result<Vec<Trace>> {
     let mut out: Box<dyn std::io::Write> = if opt.verbose {
         Box::new(std::io::stdout())
     } else {
         Box::new(std::io::sink())
     };
 
-    writeln!(out, "Querying Loki for query log entry")?;
-    let log_entry =
-        config
-            .loki
-            .query(&opt.deployment, opt.qid.as_deref(), opt.min_time, &mut out)?;
-    save_query(&config, &log_entry)?;
-
-    writeln!(out, "Querying graph-node for query trace")?;
-    let output = &config.graph_node.query(&opt.deployment, &log_entry)?;
-    save_output(&opt, &config, output)?;
+    if display.report.is_some() && display.format != TraceFormat::Brief {
+        return Err(anyhow!("--report can only be used with --format brief"));
+    }
 
-    let trace = &output["trace"];
-    save_trace(&opt, &config, trace)?;
+    let mut config = match &opt.cmd {
+        Command::Analyze { file, flamegraph } => {
+            return Ok(vec![analyze(file, flamegraph.as_deref(), display)?]);
+        }
+        Command::Aggregate { traces } => {
+            print_aggregate(&aggregate(traces, display.sort)?, display);
+            return Ok(Vec::new());
+        }
+        Command::Compare { targets, filter } if targets.is_empty() => {
+            match filter.deployments.as_slice() {
+                [first, second] => compare(first, second, display)?,
+                _ => {
+                    return Err(anyhow!(
+                        "compare needs the files with two traces, or --targets and deployments"
+                    ))
+                }
+            }
+            return Ok(Vec::new());
+        }
+        Command::Diff { targets, filter } if targets.is_empty() => {
+            match filter.deployments.as_slice() {
+                [first, second] => diff(first, second, display)?,
+                _ => {
+                    return Err(anyhow!(
+                        "diff needs the files with two traces, or --targets and deployments"
+                    ))
+                }
+            }
+            return Ok(Vec::new());
+        }
+        Command::Diff { targets, .. } if targets.len() != 2 => {
+            return Err(anyhow!("diff needs exactly two --targets"));
+        }
+        _ => Config::load(&opt.config)?,
+    };
+    config.retry.timeout = opt.timeout;
+    config.display = display.clone();
+    if let Command::Fetch(FetchOpts {
+        cache_compare: true,
+        ..
+    })
+    | Command::Replay {
+        cache_compare: true,
+        ..
+    } = &opt.cmd
+    {
+        if display.format != TraceFormat::Brief {
+            return Err(anyhow!(
+                "--cache-compare can only be used with --format brief"
+            ));
+        }
+        if display.report.is_some() {
+            return Err(anyhow!("--cache-compare can not be used with --report"));
+        }
+    }
+    if let Some(target) = &opt.target {
+        config.select(target)?;
+    }
+    if opt.route_by_name {
+        for graph_node in config.graph_nodes.as_mut_slice() {
+            graph_node.routing = Routing::Name;
+        }
+    }
+    if opt.dry_run {
+        dry_run(&config, &opt.cmd)?;
+        return Ok(Vec::new());
+    }
+    // The tunnels stay open until they are dropped at the end. Only the
+    // endpoints that queries are sent to need one
+    let mut tunnels = Vec::new();
+    for (i, graph_node) in config.graph_nodes.as_mut_slice().iter_mut().enumerate() {
+        let used = match &opt.cmd {
+            Command::Compare { targets, .. } | Command::Diff { targets, .. } => graph_node
+                .name
+                .as_ref()
+                .is_some_and(|name| targets.contains(name)),
+            _ => i == 0,
+        };
+        if used {
+            tunnels.extend(tunnel::open(graph_node, &mut out).await?);
+        }
+    }
+    let names = match &mut opt.cmd {
+        Command::Fetch(FetchOpts { filter, .. })
+        | Command::List { filter, .. }
+        | Command::Stats { filter, .. }
+        | Command::Bench { filter, .. }
+        | Command::Load { filter, .. }
+        | Command::Record { filter, .. }
+        | Command::Tail { filter, .. }
+        | Command::Compare { filter, .. }
+        | Command::Diff { filter, .. } => {
+            filter
+                .resolve(config.graph_node(), &config.retry, &mut out)
+                .await?
+        }
+        Command::Replay { deployment, .. } => {
+            let hash = config
+                .graph_node()
+                .resolve(deployment, &config.retry, &mut out)
+                .await?;
+            let name = std::mem::replace(deployment, hash.clone());
+            let mut names = HashMap::new();
+            if name != hash {
+                names.insert(hash, name);
+            }
+            names
+        }
+        Command::Rerun { .. } | Command::Analyze { .. } | Command::Aggregate { .. } => {
+            HashMap::new()
+        }
+    };
+    for graph_node in config.graph_nodes.as_mut_slice() {
+        graph_node.names = names.clone();
+    }
+    // The container keeps running until it is dropped at the end
+    let _container = if opt.cmd.replays_locally() {
+        let local_node = config
+            .local_node
+            .as_ref()
+            .ok_or_else(|| anyhow!("The configuration has no [local-node] section"))?;
+        let (container, graph_node) = local_node.start(&mut out).await?;
+        config.graph_nodes = OneOrMany::One(graph_node);
+        Some(container)
+    } else {
+        None
+    };
+    // The graph-node endpoints that the command queries, whose version
+    // decides how their traces are parsed, and whether to call them by name
+    let (queried, named): (Vec<_>, _) = match &opt.cmd {
+        Command::Fetch(_)
+        | Command::Bench { .. }
+        | Command::Load { .. }
+        | Command::Replay { .. }
+        | Command::Record { .. }
+        | Command::Rerun { .. } => (vec![0], false),
+        Command::Compare { targets, .. } | Command::Diff { targets, .. } => {
+            let queried = config.graph_nodes.as_slice().iter().enumerate();
+            let queried = queried
+                .filter(|(_, graph_node)| {
+                    graph_node
+                        .name
+                        .as_ref()
+                        .is_some_and(|name| targets.contains(name))
+                })
+                .map(|(i, _)| i)
+                .collect();
+            (queried, true)
+        }
+        _ => (Vec::new(), false),
+    };
+    for i in queried {
+        let graph_node = &mut config.graph_nodes.as_mut_slice()[i];
+        let name = match &graph_node.name {
+            Some(name) if named => name.clone(),
+            _ => "graph-node".to_string(),
+        };
+        match graph_node.lookup_version(&config.retry, &mut out).await {
+            Ok(Some(version)) => {
+                writeln!(out, "{name} is version {version}")?;
+                graph_node.version = Some(version);
+            }
+            Ok(None) => {}
+            Err(e) => writeln!(out, "Failed to look up the version of {name}: {e}")?,
+        }
+    }
 
-    let trace = Trace::parse(trace)?;
-    println!(
-        "Trace for qid {}\n deployment {}\n",
-        trace.query_id(),
-        opt.deployment
-    );
-    print_brief_trace("root", &trace, 0)?;
-    Ok(())
+    match &opt.cmd {
+        Command::Fetch(opts) => return fetch(&config, opts, &mut out).await,
+        Command::List { limit, filter } => list(&config, filter, *limit, &mut out).await?,
+        Command::Stats { limit, filter } => stats(&config, filter, *limit, &mut out).await?,
+        Command::Bench { bench, filter } => run_bench(&config, bench, filter, &mut out).await?,
+        Command::Load { load, filter } => {
+            run_bench(&config, &load.bench(), filter, &mut out).await?
+        }
+        Command::Tail { interval, filter } => tail(&config, filter, *interval, &mut out).await?,
+        Command::Replay {
+            query_file,
+            variables,
+            block,
+            cache_compare,
+            split_fields,
+            save,
+            deployment,
+            ..
+        } => {
+            let mut log_entry = variables.read_entry(query_file.as_deref())?;
+            if let Some(block) = block {
+                log_entry.block = Some(*block);
+                log_entry.pin_block()?;
+            }
+            let trace = trace_entry(
+                save,
+                &config,
+                deployment,
+                &log_entry,
+                None,
+                *cache_compare,
+                &mut out,
+            )
+            .await?;
+            if *split_fields {
+                trace_fields(&config, deployment, &log_entry, &mut out).await?;
+            }
+            return Ok(vec![trace]);
+        }
+        Command::Compare { targets, filter } => {
+            compare_targets(&config, targets, filter, false, &mut out).await?
+        }
+        Command::Diff { targets, filter } => {
+            compare_targets(&config, targets, filter, true, &mut out).await?
+        }
+        Command::Record {
+            corpus,
+            limit,
+            pin_block,
+            filter,
+        } => record_corpus(&config, filter, *limit, *pin_block, corpus, &mut out).await?,
+        Command::Rerun { corpus } => rerun_corpus(&config, corpus, &mut out).await?,
+        Command::Analyze { .. } | Command::Aggregate { .. } => unreachable!(),
+    }
+    Ok(Vec::new())
 }
@@ -0,0 +1,118 @@
+//! Persisted queries, which clients like those that use automatic persisted
+//! queries (APQ) send as the hash of the query document instead of the
+//! document itself. Such queries show up in the query log with the hash in
+//! place of the query and need to be resolved before they can be replayed
+
+use std::collections::HashMap;
+
+use anyhow::anyhow;
+use serde_derive::Deserialize;
+use serde_json as json;
+
+use super::{Http, LogEntry, Retry};
+
+/// Where to find the documents for the hashes of persisted queries
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct PersistedQueries {
+    /// A JSON file with an object that maps hashes to query documents
+    file: Option<String>,
+    /// An endpoint that returns the document for a hash, with `{hash}`
+    /// replaced by the hash. It can respond with the document itself or
+    /// with a JSON object that has it in a `query` field
+    url: Option<String>,
+    #[serde(flatten)]
+    http: Http,
+}
+
+/// The hash in `query` if it is the SHA-256 hash of a persisted query,
+/// optionally prefixed with `sha256:`, rather than a query document
+fn persisted_hash(query: &str) -> Option<&str> {
+    let query = query.trim();
+    let hash = query.strip_prefix("sha256:").unwrap_or(query);
+    (hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit())).then_some(hash)
+}
+
+impl PersistedQueries {
+    /// Replace the hashes of persisted queries in `entries` with their
+    /// documents. Entries whose hash can not be resolved are left alone
+    pub async fn resolve(
+        &self,
+        entries: &mut [LogEntry],
+        retry: &Retry,
+        out: &mut dyn std::io::Write,
+    ) -> anyhow::Result<()> {
+        if !entries
+            .iter()
+            .any(|entry| persisted_hash(&entry.query).is_some())
+        {
+            return Ok(());
+        }
+        let mut documents: HashMap<String, Option<String>> = match &self.file {
+            Some(file) => {
+                let text = std::fs::read_to_string(file)
+                    .map_err(|e| anyhow!("Failed to read persisted queries from {file}: {e}"))?;
+                let documents: HashMap<String, String> = json::from_str(&text)
+                    .map_err(|e| anyhow!("Invalid persisted queries in {file}: {e}"))?;
+                documents
+                    .into_iter()
+                    .map(|(hash, document)| {
+                        let hash = hash.strip_prefix("sha256:").unwrap_or(&hash);
+                        (hash.to_lowercase(), Some(document))
+                    })
+                    .collect()
+            }
+            None => HashMap::new(),
+        };
+        for entry in entries {
+            let Some(hash) = persisted_hash(&entry.query).map(str::to_lowercase) else {
+                continue;
+            };
+            if !documents.contains_key(&hash) {
+                let document = match &self.url {
+                    Some(url) => self.fetch(url, &hash, retry, out).await?,
+                    None => None,
+                };
+                documents.insert(hash.clone(), document);
+            }
+            match &documents[&hash] {
+                Some(document) => entry.query = document.clone(),
+                None => writeln!(out, "Could not resolve persisted query {hash}")?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Ask the endpoint at `url` for the document for `hash`
+    async fn fetch(
+        &self,
+        url: &str,
+        hash: &str,
+        retry: &Retry,
+        out: &mut dyn std::io::Write,
+    ) -> anyhow::Result<Option<String>> {
+        let client = self.http.client()?;
+        let url = url.replace("{hash}", hash);
+        let resp = retry
+            .send(|| client.get(&url), None, out)
+            .await
+            .map_err(|e| anyhow!("Failed to look up persisted query: {e}"))?;
+        if resp.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let resp = resp
+            .error_for_status()
+            .map_err(|e| anyhow!("Failed to look up persisted query: {e}"))?
+            .text()
+            .await
+            .map_err(|e| anyhow!("Failed to get persisted query: {e}"))?;
+        let document = match json::from_str::<json::Value>(&resp) {
+            Ok(json::Value::Object(resp)) => resp
+                .get("query")
+                .and_then(|query| query.as_str())
+                .map(str::to_string),
+            _ => Some(resp),
+        };
+        Ok(document.filter(|document| !document.trim().is_empty()))
+    }
+}
@@ -0,0 +1,314 @@
+//! Reports about a trace that can be shared with others, e.g., by pasting
+//! them into a GitHub issue. Unlike the brief output, a report includes
+//! the query and its variables so that it can be understood on its own
+
+use serde_json as json;
+
+use super::{field_paths, findings, or_dash, percent_of, sorted, unquote, DisplayOpts, Trace};
+
+/// The formats that reports can be written in
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportFormat {
+    /// Markdown, as GitHub renders it
+    Md,
+    /// A single HTML page with a collapsible tree of the fields and a table
+    /// of them that can be sorted, which any browser can open
+    Html,
+}
+
+/// Write a report about `trace` in `format` to `out`. The fields are
+/// listed in the order that `display.sort` says, and times are shown like
+/// `display.time` shows them
+pub fn write(
+    format: ReportFormat,
+    trace: &Trace,
+    deployment: Option<&str>,
+    display: &DisplayOpts,
+    out: &mut dyn std::io::Write,
+) -> anyhow::Result<()> {
+    match format {
+        ReportFormat::Md => markdown(trace, deployment, display, out),
+        ReportFormat::Html => html(trace, deployment, display, out),
+    }
+}
+
+/// The variables of a query, pretty-printed if they are valid JSON.
+/// `graph-node` puts them in the trace either as an object or as a string
+/// with the JSON for the object
+fn pretty_variables(variables: &str) -> String {
+    let variables = unquote(variables);
+    json::from_str::<json::Value>(&variables)
+        .and_then(|variables| json::to_string_pretty(&variables))
+        .unwrap_or(variables)
+}
+
+/// What `qtrace` found in `trace`, with the kind of each finding
+fn findings(trace: &Trace, display: &DisplayOpts) -> Vec<(&'static str, String)> {
+    let mut found = Vec::new();
+    if let Some(warning) = trace.cache_warning() {
+        found.push(("Cache", warning.to_string()));
+    }
+    found.extend(
+        findings::analyze(trace, display)
+            .into_iter()
+            .map(|finding| (finding.title, finding.message)),
+    );
+    found
+}
+
+fn markdown(
+    trace: &Trace,
+    deployment: Option<&str>,
+    display: &DisplayOpts,
+    out: &mut dyn std::io::Write,
+) -> anyhow::Result<()> {
+    let elapsed = trace.elapsed();
+    let query_time = trace.query_time();
+
+    writeln!(out, "# Trace for qid `{}`\n", unquote(trace.query_id()))?;
+    if let Some(deployment) = deployment {
+        writeln!(out, "- Deployment: `{deployment}`")?;
+    }
+    if let Some(fingerprint) = trace.fingerprint() {
+        writeln!(out, "- Fingerprint: `{fingerprint}`")?;
+    }
+    if let Trace::Root {
+        query,
+        variables,
+        block,
+        cache_status,
+        ..
+    } = trace
+    {
+        writeln!(out, "- Block: {block}")?;
+        if let Some(status) = cache_status {
+            writeln!(out, "- Cache: `{status}`")?;
+        }
+        writeln!(out)?;
+        writeln!(
+            out,
+            "## Query\n\n```graphql\n{}\n```\n",
+            unquote(query).trim()
+        )?;
+        writeln!(
+            out,
+            "## Variables\n\n```json\n{}\n```\n",
+            pretty_variables(variables)
+        )?;
+    }
+
+    writeln!(out, "## Timing\n")?;
+    writeln!(out, "| | Time |\n|---|---:|")?;
+    writeln!(out, "| query | {} |", display.time(query_time))?;
+    writeln!(
+        out,
+        "| other | {} |",
+        display.time(elapsed.saturating_sub(query_time))
+    )?;
+    writeln!(out, "| total | {} |\n", display.time(elapsed))?;
+
+    writeln!(out, "## Fields\n")?;
+    writeln!(
+        out,
+        "| Field | Self | Total | Share | Entities |\n|---|---:|---:|---:|---:|"
+    )?;
+    for (path, field) in field_paths(trace, display.sort) {
+        writeln!(
+            out,
+            "| `{path}` | {} | {} | {:.1}% | {} |",
+            display.time(field.elapsed()),
+            display.time(field.query_time()),
+            percent_of(field.elapsed(), elapsed),
+            or_dash(field.entity_count()),
+        )?;
+    }
+
+    writeln!(out, "\n## Findings\n")?;
+    let findings = findings(trace, display);
+    for (title, finding) in &findings {
+        writeln!(out, "- {title}: {finding}")?;
+    }
+    if findings.is_empty() {
+        writeln!(out, "Nothing stands out.")?;
+    }
+    Ok(())
+}
+
+/// Escape `text` so that it can be put into HTML
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Styles and the script that sorts the table of fields when a column
+/// header is clicked
+const HTML_HEAD: &str = r#"<meta charset="utf-8">
+<style>
+body { font-family: sans-serif; margin: 2em; }
+pre { background: #f4f4f4; padding: 1em; overflow-x: auto; }
+table { border-collapse: collapse; }
+th, td { padding: 0.2em 0.8em; border-bottom: 1px solid #ddd; }
+th { cursor: pointer; text-align: left; }
+td.num { text-align: right; font-variant-numeric: tabular-nums; }
+details { margin-left: 1.5em; }
+summary { font-family: monospace; white-space: pre; }
+</style>
+<script>
+function sortFields(column) {
+  const table = document.getElementById("fields");
+  const rows = Array.from(table.tBodies[0].rows);
+  const desc = table.dataset.column == column && table.dataset.order != "desc";
+  rows.sort((a, b) => {
+    const x = a.cells[column].dataset.value, y = b.cells[column].dataset.value;
+    const order = isNaN(x) ? x.localeCompare(y) : x - y;
+    return desc ? -order : order;
+  });
+  rows.forEach(row => table.tBodies[0].appendChild(row));
+  table.dataset.column = column;
+  table.dataset.order = desc ? "desc" : "asc";
+}
+</script>
+"#;
+
+fn html(
+    trace: &Trace,
+    deployment: Option<&str>,
+    display: &DisplayOpts,
+    out: &mut dyn std::io::Write,
+) -> anyhow::Result<()> {
+    fn tree(
+        name: &str,
+        trace: &Trace,
+        display: &DisplayOpts,
+        total: std::time::Duration,
+        out: &mut dyn std::io::Write,
+    ) -> anyhow::Result<()> {
+        let summary = format!(
+            "{:<40} {:>9} {:>9} {:>5.1}% [{:>7} entities]",
+            name,
+            display.time(trace.elapsed()),
+            display.time(trace.query_time()),
+            percent_of(trace.elapsed(), total),
+            or_dash(trace.entity_count()),
+        );
+        writeln!(out, "<details open><summary>{}</summary>", escape(&summary))?;
+        for (name, child) in sorted(trace.children(), display.sort) {
+            tree(name, child, display, total, out)?;
+        }
+        writeln!(out, "</details>")?;
+        Ok(())
+    }
+
+    let elapsed = trace.elapsed();
+    let query_time = trace.query_time();
+    let query_id = escape(&unquote(trace.query_id()));
+
+    writeln!(out, "<!DOCTYPE html>\n<html>\n<head>")?;
+    writeln!(
+        out,
+        "<title>Trace for qid {query_id}</title>\n{HTML_HEAD}</head>"
+    )?;
+    writeln!(
+        out,
+        "<body>\n<h1>Trace for qid <code>{query_id}</code></h1>\n<ul>"
+    )?;
+    if let Some(deployment) = deployment {
+        writeln!(
+            out,
+            "<li>Deployment: <code>{}</code></li>",
+            escape(deployment)
+        )?;
+    }
+    if let Trace::Root {
+        block,
+        cache_status,
+        ..
+    } = trace
+    {
+        if let Some(fingerprint) = trace.fingerprint() {
+            writeln!(out, "<li>Fingerprint: <code>{fingerprint}</code></li>")?;
+        }
+        writeln!(out, "<li>Block: {block}</li>")?;
+        if let Some(status) = cache_status {
+            writeln!(out, "<li>Cache: <code>{}</code></li>", escape(status))?;
+        }
+    }
+    writeln!(
+        out,
+        "<li>Query: {}, other: {}, total: {}</li>\n</ul>",
+        display.time(query_time),
+        display.time(elapsed.saturating_sub(query_time)),
+        display.time(elapsed)
+    )?;
+    if let Trace::Root {
+        query, variables, ..
+    } = trace
+    {
+        writeln!(
+            out,
+            "<h2>Query</h2>\n<pre>{}</pre>",
+            escape(unquote(query).trim())
+        )?;
+        writeln!(
+            out,
+            "<h2>Variables</h2>\n<pre>{}</pre>",
+            escape(&pretty_variables(variables))
+        )?;
+    }
+
+    writeln!(out, "<h2>Trace</h2>")?;
+    writeln!(
+        out,
+        "<p>Each field shows the time of its own query, the time including \
+         the fields below it, its share of the request, and its entities</p>"
+    )?;
+    for (name, child) in sorted(trace.children(), display.sort) {
+        tree(name, child, display, elapsed, out)?;
+    }
+
+    writeln!(out, "<h2>Fields</h2>\n<p>Click a column to sort by it</p>")?;
+    writeln!(out, "<table id=\"fields\">\n<thead><tr>")?;
+    for (column, title) in ["Field", "Self", "Total", "Share (%)", "Entities"]
+        .iter()
+        .enumerate()
+    {
+        writeln!(out, "<th onclick=\"sortFields({column})\">{title}</th>")?;
+    }
+    writeln!(out, "</tr></thead>\n<tbody>")?;
+    for (path, field) in field_paths(trace, display.sort) {
+        let share = percent_of(field.elapsed(), elapsed);
+        // The times are sorted by their value in microseconds, whatever
+        // unit they are shown in
+        writeln!(
+            out,
+            "<tr><td data-value=\"{path}\"><code>{path}</code></td>\
+             <td class=\"num\" data-value=\"{self_us}\">{self_time}</td>\
+             <td class=\"num\" data-value=\"{total_us}\">{total_time}</td>\
+             <td class=\"num\" data-value=\"{share:.1}\">{share:.1}</td>\
+             <td class=\"num\" data-value=\"{entities}\">{entities}</td></tr>",
+            path = escape(&path),
+            self_us = field.elapsed().as_micros(),
+            self_time = display.time(field.elapsed()),
+            total_us = field.query_time().as_micros(),
+            total_time = display.time(field.query_time()),
+            entities = or_dash(field.entity_count()),
+        )?;
+    }
+    writeln!(out, "</tbody>\n</table>")?;
+
+    writeln!(out, "<h2>Findings</h2>")?;
+    let findings = findings(trace, display);
+    if findings.is_empty() {
+        writeln!(out, "<p>Nothing stands out.</p>")?;
+    } else {
+        writeln!(out, "<ul>")?;
+        for (title, finding) in &findings {
+            writeln!(out, "<li>{title}: {}</li>", escape(finding))?;
+        }
+        writeln!(out, "</ul>")?;
+    }
+    writeln!(out, "</body>\n</html>")?;
+    Ok(())
+}
@@ -0,0 +1,1659 @@
+//! The places that query log entries can be read from
+
+use std::time::Duration;
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde_derive::Deserialize;
+use serde_json::{self as json, json};
+use url::Url;
+
+use super::{Http, LogEntry, OneOrMany, QueryFilter, RateLimit, Retry};
+
+/// A place that query log entries can be read from, like a log aggregator
+/// or a file
+#[async_trait(?Send)]
+pub trait LogSource {
+    /// How to refer to the source in messages
+    fn name(&self) -> String;
+
+    /// Describe how entries matching `filter` would be searched for, for
+    /// `--dry-run`
+    fn describe(&self, _filter: &QueryFilter) -> String {
+        self.name()
+    }
+
+    /// Find up to `limit` entries matching `filter`, most recent first. It
+    /// is an error if there are none
+    async fn find(
+        &self,
+        filter: &QueryFilter,
+        limit: usize,
+        retry: &Retry,
+        out: &mut dyn std::io::Write,
+    ) -> anyhow::Result<Vec<LogEntry>>;
+}
+
+/// The message of the log lines that `graph-node` writes for each query
+const QUERY_TIMING: &str = "Query timing (GraphQL)";
+
+/// How query log lines are written
+#[derive(Deserialize, clap::ValueEnum, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Plain text, as `graph-node` writes it by default
+    #[default]
+    Text,
+    /// One JSON object per line, either with the fields of the query log
+    /// entry as keys, or wrapping the original line like Vector or Fluentd
+    /// do
+    Json,
+}
+
+/// Quote `s` as a LogQL string
+fn logql_string(s: &str) -> String {
+    format!(r#""{}""#, s.replace('\\', r"\\").replace('"', r#"\""#))
+}
+
+/// The time window in which to look for log entries. Without it, Loki
+/// performs an instant query over its default window
+#[derive(Debug, Clone, Copy)]
+pub struct TimeRange {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+}
+
+impl TimeRange {
+    fn new(since: Option<DateTime<Utc>>, until: Option<DateTime<Utc>>) -> Option<Self> {
+        if since.is_none() && until.is_none() {
+            return None;
+        }
+        let end = until.unwrap_or_else(Utc::now);
+        // Use the same default window length as Loki
+        let start = since.unwrap_or(end - chrono::Duration::hours(1));
+        Some(TimeRange { start, end })
+    }
+
+    fn nanos(time: &DateTime<Utc>) -> String {
+        time.timestamp_nanos_opt()
+            .map(|nanos| nanos.to_string())
+            .unwrap_or_else(|| time.to_rfc3339())
+    }
+}
+
+/// How query log lines are found and taken apart. These settings only need
+/// to be changed if the query log format or the labels of the log streams
+/// differ from what `graph-node` on the hosted service uses
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct Pattern {
+    /// The LogQL stream selector; `{cluster}` and `{deployment}` are
+    /// replaced with the actual values. It replaces the one built from
+    /// `app`, `container` and `matchers`
+    selector: Option<String>,
+    /// The regular expression that the `app` label must match; an empty
+    /// string leaves out the matcher
+    app: Option<String>,
+    /// The value of the `container` label; an empty string leaves out the
+    /// matcher
+    container: Option<String>,
+    /// Additional label matchers like `namespace="graph"` for the stream
+    /// selector
+    #[serde(default)]
+    matchers: Vec<String>,
+    /// The LogQL `pattern` expression that extracts the `block`,
+    /// `query_time`, `variables`, `query` and `query_id` fields. Without
+    /// it, the fields are found by their names, which works for all
+    /// formats that `graph-node` has used so far
+    pattern: Option<String>,
+    /// The format of the log lines. For JSON, `pattern` is used for text
+    /// lines that are wrapped in JSON
+    #[serde(default)]
+    format: LogFormat,
+}
+
+impl Pattern {
+    const APP: &'static str = "query-node.*";
+    const CONTAINER: &'static str = "query-node";
+
+    fn selector(&self, cluster: &str, deployment: &str) -> String {
+        if let Some(selector) = &self.selector {
+            return selector
+                .replace("{cluster}", cluster)
+                .replace("{deployment}", deployment);
+        }
+        let mut matchers = vec![format!("cluster={}", logql_string(cluster))];
+        let app = self.app.as_deref().unwrap_or(Self::APP);
+        if !app.is_empty() {
+            matchers.push(format!("app=~{}", logql_string(app)));
+        }
+        matchers.push(format!("deployment={}", logql_string(deployment)));
+        let container = self.container.as_deref().unwrap_or(Self::CONTAINER);
+        if !container.is_empty() {
+            matchers.push(format!("container={}", logql_string(container)));
+        }
+        matchers.extend(self.matchers.iter().cloned());
+        format!("{{{}}}", matchers.join(","))
+    }
+
+    /// The LogQL stages that extract the fields of a query log line. With a
+    /// `pattern`, that extracts all of them. Otherwise, only the fields
+    /// that filters need are extracted, each with its own `regexp` so that
+    /// their order does not matter, or with `json` for JSON lines, and the
+    /// query and variables are taken from the log line later
+    fn expr(&self) -> String {
+        match (&self.pattern, self.format) {
+            (_, LogFormat::Json) => {
+                r#"json block="block", query_time="query_time_ms", query_id="query_id""#.to_string()
+            }
+            (Some(pattern), LogFormat::Text) => format!("pattern {}", logql_string(pattern)),
+            (None, LogFormat::Text) => [
+                r", block: (?P<block>[0-9]+)",
+                r", query_time_ms: (?P<query_time>[0-9]+)",
+                r", query_id: (?P<query_id>[^,\s]+)",
+            ]
+            .iter()
+            .map(|regex| format!("regexp {}", logql_string(regex)))
+            .collect::<Vec<_>>()
+            .join(" | "),
+        }
+    }
+
+    /// Whether `expr` extracts the query text as a label
+    fn extracts_query(&self) -> bool {
+        self.pattern.is_some() && self.format == LogFormat::Text
+    }
+
+    /// The parser for log lines in `format`, or in the configured format
+    fn line_parser(&self, format: Option<LogFormat>) -> LineParser {
+        LineParser::new(self.pattern.as_deref(), format.unwrap_or(self.format))
+    }
+}
+
+/// Takes query log lines apart, either with a pattern or by detecting the
+/// format of the line
+enum LineParser {
+    Pattern(LinePattern),
+    Detect,
+    /// JSON lines; text lines wrapped in them are taken apart with the
+    /// inner parser
+    Json(Box<LineParser>),
+}
+
+impl LineParser {
+    fn new(pattern: Option<&str>, format: LogFormat) -> Self {
+        let text = match pattern {
+            Some(pattern) => LineParser::Pattern(LinePattern::new(pattern)),
+            None => LineParser::Detect,
+        };
+        match format {
+            LogFormat::Text => text,
+            LogFormat::Json => LineParser::Json(Box::new(text)),
+        }
+    }
+
+    fn parse(&self, line: &str) -> Option<json::Map<String, json::Value>> {
+        match self {
+            LineParser::Pattern(pattern) => pattern.captures(line),
+            LineParser::Detect => detect_fields(line),
+            LineParser::Json(text) => {
+                // Lines from `docker logs` or `journalctl` have a prefix
+                let start = line.find('{')?;
+                let value = json::from_str(line[start..].trim_end()).ok()?;
+                json_fields(&value, text)
+            }
+        }
+    }
+}
+
+/// Take a query log entry in JSON format apart. `graph-node` logs the
+/// fields of the entry as keys next to the message. Log shippers wrap the
+/// original line, which can be JSON itself, in a field like `log` or
+/// `message`; wrapped text lines are taken apart with `text`. Fields are
+/// returned under the same names as the labels that the LogQL pattern
+/// extracts, plus the `timestamp` if there is one
+fn json_fields(value: &json::Value, text: &LineParser) -> Option<json::Map<String, json::Value>> {
+    const MESSAGES: [&str; 3] = ["msg", "message", "log"];
+    const FIELDS: [(&str, &str); 6] = [
+        ("block", "block"),
+        ("query_time_ms", "query_time"),
+        ("query_time", "query_time"),
+        ("query_id", "query_id"),
+        ("query", "query"),
+        ("variables", "variables"),
+    ];
+
+    let object = value.as_object()?;
+    let timestamp = ["ts", "time", "timestamp", "@timestamp"]
+        .iter()
+        .find_map(|key| object.get(*key));
+    let is_entry = MESSAGES.iter().any(|key| {
+        object
+            .get(*key)
+            .and_then(|msg| msg.as_str())
+            .is_some_and(|msg| msg.trim() == QUERY_TIMING)
+    });
+    if is_entry && object.contains_key("query") {
+        let mut fields = json::Map::new();
+        for (key, name) in FIELDS {
+            let value = match object.get(key) {
+                Some(json::Value::String(s)) => s.clone(),
+                Some(json::Value::Null) | None => continue,
+                Some(value) => value.to_string(),
+            };
+            fields.insert(name.to_string(), json::Value::String(value));
+        }
+        if let Some(timestamp) = timestamp {
+            fields.insert("timestamp".to_string(), timestamp.clone());
+        }
+        return Some(fields);
+    }
+    let mut fields = MESSAGES.iter().find_map(|key| match object.get(*key)? {
+        inner @ json::Value::Object(_) => json_fields(inner, text),
+        json::Value::String(line) => match json::from_str(line.trim()) {
+            Ok(inner @ json::Value::Object(_)) => json_fields(&inner, text),
+            _ => text.parse(line),
+        },
+        _ => None,
+    })?;
+    // The wrapper knows when the wrapped line was logged
+    if let Some(timestamp) = timestamp {
+        fields
+            .entry("timestamp")
+            .or_insert_with(|| timestamp.clone());
+    }
+    Some(fields)
+}
+
+/// Take a `Query timing (GraphQL)` log line apart without knowing the
+/// exact format. Depending on the version, `graph-node` logs the fields in
+/// different orders and leaves some of them out, but it always logs them as
+/// `key: value` pairs separated by `, `. Since the query and the variables
+/// can contain anything, all other fields are taken off the front and the
+/// back of the line first; they never contain whitespace or brackets. What
+/// remains are the variables, which must be valid JSON, and the query.
+/// Fields are returned under the same names as the labels that the LogQL
+/// pattern extracts
+fn detect_fields(line: &str) -> Option<json::Map<String, json::Value>> {
+    fn scalar(pair: &str) -> Option<(&str, &str)> {
+        let (key, value) = pair.split_once(": ")?;
+        let is_key = !key.is_empty() && key.chars().all(|c| c.is_ascii_lowercase() || c == '_');
+        let is_value = !value.is_empty()
+            && !value
+                .chars()
+                .any(|c| c.is_whitespace() || "{}()[]".contains(c));
+        (is_key && is_value && key != "query" && key != "variables").then_some((key, value))
+    }
+
+    fn is_json(text: &str) -> bool {
+        json::from_str::<json::Value>(text).is_ok()
+    }
+
+    let start = line.find(QUERY_TIMING)? + QUERY_TIMING.len();
+    let mut rest = line[start..].trim_end().trim_end_matches(',');
+    let mut fields = json::Map::new();
+    let mut insert = |key: &str, value: &str| {
+        let key = if key == "query_time_ms" {
+            "query_time"
+        } else {
+            key
+        };
+        fields.insert(
+            key.to_string(),
+            json::Value::String(value.trim().to_string()),
+        );
+    };
+
+    while let Some(field) = rest.strip_prefix(", ") {
+        let end = field.find(", ").unwrap_or(field.len());
+        let Some((key, value)) = scalar(&field[..end]) else {
+            break;
+        };
+        insert(key, value);
+        rest = &field[end..];
+    }
+    while let Some(pos) = rest.rfind(", ") {
+        let Some((key, value)) = scalar(&rest[pos + 2..]) else {
+            break;
+        };
+        insert(key, value);
+        rest = &rest[..pos];
+    }
+
+    let rest = rest.strip_prefix(", ")?;
+    if let Some(rest) = rest.strip_prefix("variables: ") {
+        // The variables come first; they end at the first `, query: ` that
+        // leaves valid JSON in front of it
+        let split = rest
+            .match_indices(", query: ")
+            .map(|(pos, _)| pos)
+            .find(|pos| is_json(&rest[..*pos]))?;
+        insert("variables", &rest[..split]);
+        insert("query", &rest[split + ", query: ".len()..]);
+    } else {
+        let rest = rest.strip_prefix("query: ")?;
+        let split = rest
+            .rmatch_indices(", variables: ")
+            .map(|(pos, _)| pos)
+            .find(|pos| is_json(&rest[pos + ", variables: ".len()..]));
+        match split {
+            Some(split) => {
+                insert("query", &rest[..split]);
+                insert("variables", &rest[split + ", variables: ".len()..]);
+            }
+            None => insert("query", rest),
+        }
+    }
+    Some(fields)
+}
+
+enum PatternPart {
+    Literal(String),
+    Capture(String),
+}
+
+/// A LogQL `pattern` expression, so that log lines that do not come from
+/// Loki can be taken apart the same way Loki does it
+struct LinePattern {
+    parts: Vec<PatternPart>,
+}
+
+impl LinePattern {
+    fn new(pattern: &str) -> Self {
+        fn is_name(name: &str) -> bool {
+            !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || c == '_')
+        }
+
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut rest = pattern;
+        while let Some(start) = rest.find('<') {
+            let name = rest[start + 1..]
+                .find('>')
+                .map(|end| &rest[start + 1..start + 1 + end])
+                .filter(|name| is_name(name));
+            literal.push_str(&rest[..start]);
+            match name {
+                Some(name) => {
+                    if !literal.is_empty() {
+                        parts.push(PatternPart::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(PatternPart::Capture(name.to_string()));
+                    rest = &rest[start + name.len() + 2..];
+                }
+                None => {
+                    literal.push('<');
+                    rest = &rest[start + 1..];
+                }
+            }
+        }
+        literal.push_str(rest);
+        if !literal.is_empty() {
+            parts.push(PatternPart::Literal(literal));
+        }
+        LinePattern { parts }
+    }
+
+    /// Match `line` against the pattern and return the captured fields in
+    /// the same form as the labels of a Loki stream. Like in Loki, a capture
+    /// extends to the next occurrence of the literal that follows it, and
+    /// anything after the end of the pattern is ignored
+    fn captures(&self, line: &str) -> Option<json::Map<String, json::Value>> {
+        let mut fields = json::Map::new();
+        let mut rest = line;
+        let mut parts = self.parts.iter().peekable();
+        while let Some(part) = parts.next() {
+            match part {
+                PatternPart::Literal(literal) => {
+                    rest = rest.strip_prefix(literal.as_str())?;
+                }
+                PatternPart::Capture(name) => {
+                    let end = match parts.peek() {
+                        Some(PatternPart::Literal(literal)) => rest.find(literal.as_str())?,
+                        _ => rest.len(),
+                    };
+                    if name != "_" {
+                        fields.insert(name.to_string(), json::Value::String(rest[..end].into()));
+                    }
+                    rest = &rest[end..];
+                }
+            }
+        }
+        Some(fields)
+    }
+}
+
+/// The time at the start of a log line, as added by `docker logs
+/// --timestamps` or `journalctl -o short-iso-precise`
+fn line_timestamp(line: &str) -> Option<DateTime<Utc>> {
+    let first = line.split_whitespace().next()?;
+    DateTime::parse_from_rfc3339(first)
+        .or_else(|_| DateTime::parse_from_str(first, "%Y-%m-%dT%H:%M:%S%.f%z"))
+        .ok()
+        .map(|time| time.with_timezone(&Utc))
+}
+
+/// Turns the fields of query log lines into log entries. Lines that can
+/// not be turned into one, e.g., because their variables are not valid
+/// JSON, are skipped so that one odd line does not fail a search over
+/// thousands of them
+#[derive(Default)]
+struct EntryParser {
+    /// The number of lines that were turned into entries
+    parsed: usize,
+    /// Why the first line that was skipped could not be used
+    error: Option<anyhow::Error>,
+}
+
+impl EntryParser {
+    /// The log entry for `fields`, or nothing if the line is skipped, which
+    /// is written to `out`
+    fn entry(
+        &mut self,
+        fields: &json::Map<String, json::Value>,
+        timestamp: Option<DateTime<Utc>>,
+        out: &mut dyn std::io::Write,
+    ) -> anyhow::Result<Option<LogEntry>> {
+        match LogEntry::from_stream(fields, timestamp) {
+            Ok(entry) => {
+                self.parsed += 1;
+                Ok(Some(entry))
+            }
+            Err(e) => {
+                writeln!(out, "Skipping a query log line: {e}")?;
+                self.error.get_or_insert(e);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Fail with the reason for skipping the first line if no line could
+    /// be used
+    fn finish(self) -> anyhow::Result<()> {
+        match self.error {
+            Some(e) if self.parsed == 0 => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Extract up to `limit` query log entries for `deployment` from raw
+/// `graph-node` log lines, which must be ordered most recent first
+fn parse_log_lines<'a>(
+    lines: impl Iterator<Item = &'a str>,
+    parser: &LineParser,
+    filter: &QueryFilter,
+    limit: usize,
+    out: &mut dyn std::io::Write,
+) -> anyhow::Result<Vec<LogEntry>> {
+    let mut entry_parser = EntryParser::default();
+    let mut entries = Vec::new();
+    for line in lines {
+        if entries.len() >= limit {
+            break;
+        }
+        if !line.contains(&filter.deployment) {
+            continue;
+        }
+        let Some(fields) = parser.parse(line) else {
+            continue;
+        };
+        // JSON lines carry their own timestamp
+        let timestamp = line_timestamp(line).or_else(|| {
+            fields
+                .get("timestamp")
+                .and_then(|ts| ts.as_str())
+                .and_then(|ts| DateTime::parse_from_rfc3339(ts).ok())
+                .map(|ts| ts.with_timezone(&Utc))
+        });
+        let Some(entry) = entry_parser.entry(&fields, timestamp, out)? else {
+            continue;
+        };
+        if filter.matches(&entry) {
+            entries.push(entry);
+        }
+    }
+    entry_parser.finish()?;
+    Ok(entries)
+}
+
+/// Extract up to `limit` query log entries for `deployment` from several
+/// logs whose lines all start with a timestamp, most recent first
+fn parse_merged_logs(
+    logs: &[&str],
+    parser: &LineParser,
+    filter: &QueryFilter,
+    limit: usize,
+    out: &mut dyn std::io::Write,
+) -> anyhow::Result<Vec<LogEntry>> {
+    let mut lines: Vec<_> = logs.iter().flat_map(|log| log.lines()).collect();
+    lines.sort_by_cached_key(|line| line_timestamp(line));
+    parse_log_lines(lines.into_iter().rev(), parser, filter, limit, out)
+}
+
+/// Run `program` and extract query log entries for `deployment` from
+/// what it prints
+fn read_command_logs(
+    program: &str,
+    args: &[String],
+    parser: &LineParser,
+    filter: &QueryFilter,
+    limit: usize,
+    out: &mut dyn std::io::Write,
+) -> anyhow::Result<Vec<LogEntry>> {
+    let output = std::process::Command::new(program)
+        .args(args)
+        .output()
+        .map_err(|e| anyhow!("Failed to run {program}: {e}"))?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "{program} failed with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    // `docker logs` replays what the container wrote to stderr on stderr;
+    // merge both streams back together by the timestamp of each line
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let entries = parse_merged_logs(&[&stdout, &stderr], parser, filter, limit, out)?;
+    if entries.is_empty() {
+        return Err(anyhow!(
+            "No matching query log entries in the output of {program}"
+        ));
+    }
+    Ok(entries)
+}
+
+/// The `--since` and `--until` arguments for commands that print logs, so
+/// that they do not print more than necessary. `journalctl` wants epoch
+/// seconds with an `@` in front, `docker logs` without
+fn window_args(filter: &QueryFilter, prefix: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    if let Some(since) = filter.since {
+        args.extend([
+            "--since".to_string(),
+            format!("{prefix}{}", since.timestamp()),
+        ]);
+    }
+    if let Some(until) = filter.until {
+        args.extend([
+            "--until".to_string(),
+            format!("{prefix}{}", until.timestamp()),
+        ]);
+    }
+    args
+}
+
+/// A file with raw `graph-node` logs. Since the log lines do not have a
+/// full timestamp, the entries are returned most recent, i.e., last in the
+/// file, first
+pub struct LogFile<'a> {
+    pub(crate) path: &'a str,
+    pub(crate) pattern: &'a Pattern,
+    /// The format of the log lines, if not the one from `pattern`
+    pub(crate) format: Option<LogFormat>,
+}
+
+#[async_trait(?Send)]
+impl LogSource for LogFile<'_> {
+    fn name(&self) -> String {
+        self.path.to_string()
+    }
+
+    async fn find(
+        &self,
+        filter: &QueryFilter,
+        limit: usize,
+        _retry: &Retry,
+        out: &mut dyn std::io::Write,
+    ) -> anyhow::Result<Vec<LogEntry>> {
+        let path = self.path;
+        let logs =
+            std::fs::read_to_string(path).map_err(|e| anyhow!("Failed to read {path}: {e}"))?;
+        let parser = self.pattern.line_parser(self.format);
+        let entries = parse_log_lines(logs.lines().rev(), &parser, filter, limit, out)?;
+        if entries.is_empty() {
+            return Err(anyhow!("No matching query log entries in {path}"));
+        }
+        Ok(entries)
+    }
+}
+
+/// The journal of a systemd unit that runs `graph-node` on this machine
+pub struct Journal<'a> {
+    pub(crate) unit: &'a str,
+    pub(crate) pattern: &'a Pattern,
+    pub(crate) format: Option<LogFormat>,
+}
+
+#[async_trait(?Send)]
+impl LogSource for Journal<'_> {
+    fn name(&self) -> String {
+        format!("the journal of {}", self.unit)
+    }
+
+    async fn find(
+        &self,
+        filter: &QueryFilter,
+        limit: usize,
+        _retry: &Retry,
+        out: &mut dyn std::io::Write,
+    ) -> anyhow::Result<Vec<LogEntry>> {
+        let mut args = vec![
+            "--unit".to_string(),
+            self.unit.to_string(),
+            "--output".to_string(),
+            "short-iso-precise".to_string(),
+            "--no-pager".to_string(),
+        ];
+        args.extend(window_args(filter, "@"));
+        let parser = self.pattern.line_parser(self.format);
+        read_command_logs("journalctl", &args, &parser, filter, limit, out)
+    }
+}
+
+/// The logs of a docker container that runs `graph-node` on this machine
+pub struct DockerLogs<'a> {
+    pub(crate) container: &'a str,
+    pub(crate) pattern: &'a Pattern,
+    pub(crate) format: Option<LogFormat>,
+}
+
+#[async_trait(?Send)]
+impl LogSource for DockerLogs<'_> {
+    fn name(&self) -> String {
+        format!("container {}", self.container)
+    }
+
+    async fn find(
+        &self,
+        filter: &QueryFilter,
+        limit: usize,
+        _retry: &Retry,
+        out: &mut dyn std::io::Write,
+    ) -> anyhow::Result<Vec<LogEntry>> {
+        let mut args = vec!["logs".to_string(), "--timestamps".to_string()];
+        args.extend(window_args(filter, ""));
+        args.push(self.container.to_string());
+        let parser = self.pattern.line_parser(self.format);
+        read_command_logs("docker", &args, &parser, filter, limit, out)
+    }
+}
+
+/// How qtrace authenticates with Loki
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum Auth {
+    /// Put `username` and `password` into the URL
+    #[default]
+    Basic,
+    /// Send `token` in an `Authorization: Bearer` header
+    Bearer,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Loki {
+    cluster: String,
+    url: String,
+    #[serde(default)]
+    auth: Auth,
+    username: Option<String>,
+    password: Option<String>,
+    token: Option<String>,
+    #[serde(default)]
+    pub pattern: Pattern,
+    /// The maximum number of entries that Loki returns for one request
+    #[serde(default = "Loki::default_page_size", rename = "page-size")]
+    pub page_size: usize,
+    /// The maximum number of requests per second to send to this cluster
+    #[serde(default, rename = "rate-limit")]
+    rate_limit: RateLimit,
+    #[serde(flatten)]
+    http: Http,
+}
+
+impl Loki {
+    /// Loki's default for `max_entries_limit_per_query`
+    fn default_page_size() -> usize {
+        5000
+    }
+
+    fn query_url(&self, range: bool) -> anyhow::Result<Url> {
+        let mut url = Url::parse(&self.url)?;
+        if self.auth == Auth::Basic {
+            if let Some(username) = &self.username {
+                url.set_username(username)
+                    .map_err(|_| anyhow!("Failed to set Loki username"))?;
+            }
+            if let Some(password) = &self.password {
+                url.set_password(Some(password))
+                    .map_err(|_| anyhow!("Failed to set Loki password"))?;
+            }
+        }
+        if range {
+            url.set_path("/loki/api/v1/query_range");
+        } else {
+            url.set_path("/loki/api/v1/query");
+        }
+        Ok(url)
+    }
+
+    /// The LogQL query that finds the log entries matching `filter`
+    pub fn logql(&self, filter: &QueryFilter) -> String {
+        let mut query = self.pattern.selector(&self.cluster, &filter.deployment);
+        // Line filters are cheaper than the pattern, so apply them first
+        if !self.pattern.extracts_query() {
+            query.push_str(&format!(" |= {}", logql_string(QUERY_TIMING)));
+        }
+        if let Some(operation) = &filter.operation {
+            let regex = format!(r"\b(query|mutation|subscription)\s+{operation}\b");
+            query.push_str(&format!(" |~ {}", logql_string(&regex)));
+        }
+        // The line filter also sees the rest of the log line; the label
+        // filter after the pattern makes sure only the query text matches
+        let query_regex = filter.query_regex.as_ref().map(|regex| regex.as_str());
+        if let Some(regex) = query_regex {
+            query.push_str(&format!(" |~ {}", logql_string(regex)));
+        }
+        query.push_str(&format!(" | {}", self.pattern.expr()));
+        // Without a pattern, or for JSON lines, there is no `query` label;
+        // `find` checks the query text itself
+        if let Some(regex) = query_regex.filter(|_| self.pattern.extracts_query()) {
+            let regex = format!(".*(?:{regex}).*");
+            query.push_str(&format!(" | query=~{}", logql_string(&regex)));
+        }
+        if let Some(qid) = &filter.qid {
+            query.push_str(&format!(r#" | query_id="{qid}""#));
+        }
+        if let Some(min_time) = filter.min_time {
+            query.push_str(&format!(r#" | query_time > {min_time}"#));
+        }
+        if let Some(max_time) = filter.max_time {
+            query.push_str(&format!(r#" | query_time < {max_time}"#));
+        }
+        if let Some(block_from) = filter.block_from {
+            query.push_str(&format!(r#" | block >= {block_from}"#));
+        }
+        if let Some(block_to) = filter.block_to {
+            query.push_str(&format!(r#" | block <= {block_to}"#));
+        }
+        // Lines that lack a field that a filter needs make the filter fail
+        // rather than drop the line
+        query.push_str(r#" | __error__="""#);
+        query
+    }
+
+    /// Run `query` once and return up to `limit` entries, most recent first
+    pub async fn query_page(
+        &self,
+        query: &str,
+        range: Option<&TimeRange>,
+        limit: usize,
+        retry: &Retry,
+        out: &mut dyn std::io::Write,
+    ) -> anyhow::Result<Vec<LogEntry>> {
+        let url = self.query_url(range.is_some())?;
+        let mut params = vec![("query", query.to_string()), ("limit", limit.to_string())];
+        if let Some(range) = range {
+            params.push(("start", TimeRange::nanos(&range.start)));
+            params.push(("end", TimeRange::nanos(&range.end)));
+        }
+        let token = match (self.auth, &self.token) {
+            (Auth::Basic, _) => None,
+            (Auth::Bearer, Some(token)) => Some(token),
+            (Auth::Bearer, None) => {
+                return Err(anyhow!(
+                    "Loki cluster {} uses bearer auth but has no token",
+                    self.cluster
+                ))
+            }
+        };
+        let client = self.http.client()?;
+        let resp = retry
+            .send(
+                || {
+                    let request = client.get(url.clone()).query(&params);
+                    match token {
+                        Some(token) => request.bearer_auth(token),
+                        None => request,
+                    }
+                },
+                Some(&self.rate_limit),
+                out,
+            )
+            .await
+            .map_err(|e| anyhow!("Failed to send Loki query: {}", e))?
+            .text()
+            .await
+            .map_err(|e| anyhow!("Failed to get Loki response: {}", e))?;
+        let resp: json::Value =
+            json::from_str(&resp).map_err(|e| anyhow!("Failed to parse Loki response: {}", e))?;
+        let results = match &resp["data"]["result"] {
+            json::Value::Array(results) if !results.is_empty() => results,
+            _ => {
+                writeln!(out, "Loki response status: {}", resp["status"])?;
+                return Ok(Vec::new());
+            }
+        };
+        let parser = self.pattern.line_parser(None);
+        let mut entry_parser = EntryParser::default();
+        let mut entries = Vec::new();
+        for result in results {
+            let stream = match &result["stream"] {
+                json::Value::Object(stream) => stream,
+                _ => return Err(anyhow!("Invalid Loki response: result has no stream")),
+            };
+            // Each value is one log line; lines with identical labels are
+            // grouped into the same stream
+            let empty = Vec::new();
+            let values = result["values"].as_array().unwrap_or(&empty);
+            for value in values {
+                let timestamp = value[0]
+                    .as_str()
+                    .and_then(|nanos| nanos.parse::<i64>().ok())
+                    .map(DateTime::from_timestamp_nanos);
+                // Unless the pattern extracted them, the query and the
+                // variables have to be taken from the log line
+                let mut fields = stream.clone();
+                if !fields.contains_key("query") {
+                    let Some(detected) = value[1].as_str().and_then(|line| parser.parse(line))
+                    else {
+                        continue;
+                    };
+                    for (key, value) in detected {
+                        fields.entry(key).or_insert(value);
+                    }
+                }
+                if let Some(entry) = entry_parser.entry(&fields, timestamp, out)? {
+                    entries.push(entry);
+                }
+            }
+        }
+        entry_parser.finish()?;
+        entries.sort_by_key(|entry| std::cmp::Reverse(entry.timestamp));
+        entries.truncate(limit);
+        Ok(entries)
+    }
+}
+
+#[async_trait(?Send)]
+impl LogSource for Loki {
+    fn name(&self) -> String {
+        format!("Loki cluster {}", self.cluster)
+    }
+
+    fn describe(&self, filter: &QueryFilter) -> String {
+        // Not `query_url`, which adds the credentials
+        let url = Url::parse(&self.url)
+            .map(|mut url| {
+                url.set_path("/loki/api/v1/query_range");
+                url.to_string()
+            })
+            .unwrap_or_else(|_| self.url.clone());
+        format!("{} at {url} with\n    {}", self.name(), self.logql(filter))
+    }
+
+    async fn find(
+        &self,
+        filter: &QueryFilter,
+        limit: usize,
+        retry: &Retry,
+        out: &mut dyn std::io::Write,
+    ) -> anyhow::Result<Vec<LogEntry>> {
+        let query = self.logql(filter);
+        let mut range = TimeRange::new(filter.since, filter.until);
+        if range.is_none() && limit > self.page_size {
+            // Only range queries can be paged; use Loki's default window
+            range = TimeRange::new(None, Some(Utc::now()));
+        }
+        let mut entries: Vec<LogEntry> = Vec::new();
+        // Loki returns at most `page_size` entries per request. For range
+        // queries, ask for older entries by moving the end of the range to
+        // the oldest entry seen so far until we have enough. Since the end
+        // of the range is exclusive, it is set just past that entry so that
+        // no entries with the same timestamp get lost, and pages overlap by
+        // at least one entry
+        loop {
+            let page_limit = (limit - entries.len() + 1).min(self.page_size);
+            let page = self
+                .query_page(&query, range.as_ref(), page_limit, retry, out)
+                .await?;
+            let page_len = page.len();
+            // Of the whole page, so that a page in which no entry passes
+            // the filter does not end the search
+            let oldest = page.iter().filter_map(|entry| entry.timestamp).min();
+            let page: Vec<_> = page.into_iter().filter(|e| filter.matches(e)).collect();
+            for entry in page {
+                // Entries at the boundary between pages are returned twice
+                let seen = entries.iter().any(|seen| {
+                    seen.timestamp == entry.timestamp && seen.query_id == entry.query_id
+                });
+                if !seen {
+                    entries.push(entry);
+                }
+            }
+            let (Some(current), Some(oldest)) = (range.as_mut(), oldest) else {
+                break;
+            };
+            if page_len < page_limit || entries.len() >= limit {
+                break;
+            }
+            // When the whole page has the same timestamp as the end of the
+            // range, skip past it since asking again would return it again
+            let end = oldest + chrono::Duration::nanoseconds(1);
+            current.end = if end < current.end { end } else { oldest };
+            if current.end <= current.start {
+                break;
+            }
+            writeln!(out, "Fetched {} entries, asking for more", entries.len())?;
+        }
+        entries.truncate(limit);
+        if entries.is_empty() {
+            writeln!(out, "Loki query: {query}")?;
+            return Err(anyhow!("Invalid Loki response: no result"));
+        }
+        Ok(entries)
+    }
+}
+
+/// An Elasticsearch or OpenSearch cluster that `graph-node` logs are
+/// shipped to. Each document holds one raw log line
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct Elasticsearch {
+    url: String,
+    /// The index or index pattern to search
+    #[serde(default = "Elasticsearch::default_index")]
+    index: String,
+    username: Option<String>,
+    password: Option<String>,
+    /// An API key to send instead of `username` and `password`
+    api_key: Option<String>,
+    /// The field that holds the log line
+    #[serde(default = "Elasticsearch::default_message_field")]
+    message_field: String,
+    /// The field that holds the time at which the line was logged
+    #[serde(default = "Elasticsearch::default_timestamp_field")]
+    timestamp_field: String,
+    /// A field that holds the deployment hash. Without it, the deployment
+    /// is searched for in the log line
+    deployment_field: Option<String>,
+    /// The pattern that extracts the fields from a query log line, with
+    /// the same syntax as a LogQL `pattern`
+    pattern: Option<String>,
+    /// The format of the log lines
+    #[serde(default)]
+    format: LogFormat,
+    /// The number of documents to ask for with each request
+    #[serde(default = "Elasticsearch::default_page_size")]
+    page_size: usize,
+    /// The maximum number of requests per second to send to the cluster
+    #[serde(default)]
+    rate_limit: RateLimit,
+    #[serde(flatten)]
+    http: Http,
+}
+
+impl Elasticsearch {
+    fn default_index() -> String {
+        "*".to_string()
+    }
+
+    fn default_message_field() -> String {
+        "message".to_string()
+    }
+
+    fn default_timestamp_field() -> String {
+        "@timestamp".to_string()
+    }
+
+    /// The default for `index.max_result_window` is 10000, stay well below
+    fn default_page_size() -> usize {
+        1000
+    }
+
+    /// The search query that finds candidates for log entries matching
+    /// `filter`. Only the query log marker, the deployment, the query id,
+    /// and the time range are searched for in Elasticsearch; all other
+    /// filters are applied after the log lines have been taken apart
+    fn search(&self, filter: &QueryFilter) -> json::Value {
+        let phrase = |text: &str| json!({ "match_phrase": { &self.message_field: text } });
+        let mut filters = vec![phrase(QUERY_TIMING)];
+        match &self.deployment_field {
+            Some(field) => filters.push(json!({ "term": { field: filter.deployment } })),
+            None => filters.push(phrase(&filter.deployment)),
+        }
+        if let Some(qid) = &filter.qid {
+            filters.push(phrase(qid));
+        }
+        let mut range = json::Map::new();
+        if let Some(since) = filter.since {
+            range.insert("gte".to_string(), json!(since.to_rfc3339()));
+        }
+        if let Some(until) = filter.until {
+            range.insert("lte".to_string(), json!(until.to_rfc3339()));
+        }
+        if !range.is_empty() {
+            filters.push(json!({ "range": { &self.timestamp_field: range } }));
+        }
+        json!({ "bool": { "filter": filters } })
+    }
+}
+
+#[async_trait(?Send)]
+impl LogSource for Elasticsearch {
+    fn name(&self) -> String {
+        format!("Elasticsearch at {}", self.url)
+    }
+
+    async fn find(
+        &self,
+        filter: &QueryFilter,
+        limit: usize,
+        retry: &Retry,
+        out: &mut dyn std::io::Write,
+    ) -> anyhow::Result<Vec<LogEntry>> {
+        let mut url = Url::parse(&self.url)?;
+        url.path_segments_mut()
+            .map_err(|_| anyhow!("Invalid Elasticsearch url {}", self.url))?
+            .pop_if_empty()
+            .push(&self.index)
+            .push("_search");
+        let parser = LineParser::new(self.pattern.as_deref(), self.format);
+        let client = self.http.client()?;
+        let query = self.search(filter);
+        let mut entry_parser = EntryParser::default();
+        let mut entries = Vec::new();
+        let mut search_after = None;
+        // Since most filters can only be checked here, keep asking for
+        // older documents with `search_after` until there are enough
+        // matching ones
+        while entries.len() < limit {
+            let mut body = json!({
+                "size": self.page_size,
+                "query": query,
+                "sort": [{ &self.timestamp_field: "desc" }, { "_doc": "desc" }],
+                "_source": [&self.message_field, &self.timestamp_field],
+            });
+            if let Some(after) = search_after.take() {
+                body["search_after"] = after;
+            }
+            let body = body.to_string();
+            let resp = retry
+                .send(
+                    || {
+                        let request = client
+                            .post(url.clone())
+                            .header("Content-Type", "application/json")
+                            .body(body.clone());
+                        match (&self.api_key, &self.username) {
+                            (Some(key), _) => {
+                                request.header("Authorization", format!("ApiKey {key}"))
+                            }
+                            (None, Some(username)) => {
+                                request.basic_auth(username, self.password.as_ref())
+                            }
+                            (None, None) => request,
+                        }
+                    },
+                    Some(&self.rate_limit),
+                    out,
+                )
+                .await
+                .map_err(|e| anyhow!("Failed to send Elasticsearch query: {}", e))?
+                .text()
+                .await
+                .map_err(|e| anyhow!("Failed to get Elasticsearch response: {}", e))?;
+            let resp: json::Value = json::from_str(&resp)
+                .map_err(|e| anyhow!("Failed to parse Elasticsearch response: {}", e))?;
+            if let Some(error) = resp.get("error") {
+                return Err(anyhow!("Elasticsearch query failed: {error}"));
+            }
+            let hits = match &resp["hits"]["hits"] {
+                json::Value::Array(hits) => hits,
+                _ => return Err(anyhow!("Invalid Elasticsearch response: no hits")),
+            };
+            for hit in hits {
+                let source = &hit["_source"];
+                let Some(fields) = source[&self.message_field]
+                    .as_str()
+                    .and_then(|line| parser.parse(line))
+                else {
+                    continue;
+                };
+                let timestamp = source[&self.timestamp_field]
+                    .as_str()
+                    .and_then(|ts| DateTime::parse_from_rfc3339(ts).ok())
+                    .map(|ts| ts.with_timezone(&Utc));
+                let Some(entry) = entry_parser.entry(&fields, timestamp, out)? else {
+                    continue;
+                };
+                if filter.matches(&entry) && entries.len() < limit {
+                    entries.push(entry);
+                }
+            }
+            match hits.last() {
+                Some(last) if hits.len() >= self.page_size && entries.len() < limit => {
+                    search_after = Some(last["sort"].clone());
+                    writeln!(out, "Fetched {} entries, asking for more", entries.len())?;
+                }
+                _ => break,
+            }
+        }
+        entry_parser.finish()?;
+        if entries.is_empty() {
+            writeln!(out, "Elasticsearch query: {query}")?;
+            return Err(anyhow!("No matching query log entries in Elasticsearch"));
+        }
+        Ok(entries)
+    }
+}
+
+/// CloudWatch Logs, e.g., for `graph-node` running on EKS. Query log lines
+/// are found with Logs Insights. Credentials come from the usual AWS
+/// chain: environment variables, the shared config and credentials files,
+/// and the instance or task role
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct CloudWatch {
+    /// The log group or log groups to search
+    log_group: OneOrMany<String>,
+    /// The AWS region; defaults to the one from the AWS config
+    region: Option<String>,
+    /// The AWS profile to use instead of the default one
+    profile: Option<String>,
+    /// Talk to this endpoint instead of the regular one for the region
+    endpoint_url: Option<String>,
+    /// The pattern that extracts the fields from a query log line, with
+    /// the same syntax as a LogQL `pattern`
+    pattern: Option<String>,
+    /// The format of the log lines
+    #[serde(default)]
+    format: LogFormat,
+}
+
+impl CloudWatch {
+    /// Logs Insights returns at most this many results for a query
+    const MAX_LIMIT: usize = 10_000;
+
+    /// The Logs Insights query that finds candidates for log entries
+    /// matching `filter`. Like with Elasticsearch, the filters on fields of
+    /// the query log entry are applied after the log lines have been taken
+    /// apart
+    fn insights(&self, filter: &QueryFilter, limit: usize) -> String {
+        fn like(text: &str) -> String {
+            format!("| filter @message like {}\n", logql_string(text))
+        }
+        fn regex(regex: &str) -> String {
+            format!("| filter @message like /{}/\n", regex.replace('/', r"\/"))
+        }
+
+        let mut query = "fields @timestamp, @message\n".to_string();
+        query.push_str(&like(QUERY_TIMING));
+        query.push_str(&like(&filter.deployment));
+        if let Some(qid) = &filter.qid {
+            query.push_str(&like(qid));
+        }
+        if let Some(operation) = &filter.operation {
+            query.push_str(&regex(&format!(
+                r"\b(query|mutation|subscription)\s+{operation}\b"
+            )));
+        }
+        if let Some(query_regex) = &filter.query_regex {
+            query.push_str(&regex(query_regex.as_str()));
+        }
+        query.push_str(&format!("| sort @timestamp desc\n| limit {limit}"));
+        query
+    }
+}
+
+#[async_trait(?Send)]
+impl LogSource for CloudWatch {
+    fn name(&self) -> String {
+        "CloudWatch".to_string()
+    }
+
+    async fn find(
+        &self,
+        filter: &QueryFilter,
+        limit: usize,
+        retry: &Retry,
+        out: &mut dyn std::io::Write,
+    ) -> anyhow::Result<Vec<LogEntry>> {
+        use aws_sdk_cloudwatchlogs::types::QueryStatus;
+
+        let mut loader = aws_config::defaults(aws_config::BehaviorVersion::latest()).retry_config(
+            aws_config::retry::RetryConfig::standard().with_max_attempts(retry.attempts as u32),
+        );
+        if let Some(region) = &self.region {
+            loader = loader.region(aws_config::Region::new(region.clone()));
+        }
+        if let Some(profile) = &self.profile {
+            loader = loader.profile_name(profile);
+        }
+        if let Some(endpoint_url) = &self.endpoint_url {
+            loader = loader.endpoint_url(endpoint_url);
+        }
+        let client = aws_sdk_cloudwatchlogs::Client::new(&loader.load().await);
+
+        // Logs Insights needs a time range
+        let range = TimeRange::new(filter.since, filter.until)
+            .or_else(|| TimeRange::new(None, Some(Utc::now())))
+            .unwrap();
+        // Ask for as many lines as possible since some filters can only be
+        // checked here
+        let query = self.insights(filter, Self::MAX_LIMIT);
+        writeln!(out, "Logs Insights query: {query}")?;
+        let mut start = client
+            .start_query()
+            .start_time(range.start.timestamp())
+            .end_time(range.end.timestamp())
+            .query_string(&query)
+            .limit(Self::MAX_LIMIT as i32);
+        for log_group in self.log_group.as_slice() {
+            start = start.log_group_names(log_group);
+        }
+        let query_id = start
+            .send()
+            .await
+            .map_err(|e| anyhow!("Failed to start Logs Insights query: {}", e))?
+            .query_id
+            .ok_or_else(|| anyhow!("Invalid CloudWatch response: no query id"))?;
+
+        let results = loop {
+            let resp = client
+                .get_query_results()
+                .query_id(&query_id)
+                .send()
+                .await
+                .map_err(|e| anyhow!("Failed to get Logs Insights results: {}", e))?;
+            match resp.status() {
+                Some(QueryStatus::Complete) => break resp.results,
+                Some(QueryStatus::Scheduled | QueryStatus::Running) | None => {
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+                Some(status) => {
+                    return Err(anyhow!(
+                        "Logs Insights query {query_id} ended with {status}"
+                    ))
+                }
+            }
+        };
+        let results = results.unwrap_or_default();
+
+        let parser = LineParser::new(self.pattern.as_deref(), self.format);
+        let mut entry_parser = EntryParser::default();
+        let mut entries = Vec::new();
+        for result in results {
+            let field = |name: &str| {
+                result
+                    .iter()
+                    .find(|field| field.field() == Some(name))
+                    .and_then(|field| field.value())
+            };
+            let Some(fields) = field("@message").and_then(|line| parser.parse(line)) else {
+                continue;
+            };
+            let timestamp = field("@timestamp")
+                .and_then(|ts| {
+                    chrono::NaiveDateTime::parse_from_str(ts, "%Y-%m-%d %H:%M:%S%.f").ok()
+                })
+                .map(|ts| ts.and_utc());
+            let Some(entry) = entry_parser.entry(&fields, timestamp, out)? else {
+                continue;
+            };
+            if filter.matches(&entry) {
+                entries.push(entry);
+            }
+            if entries.len() >= limit {
+                break;
+            }
+        }
+        entry_parser.finish()?;
+        if entries.is_empty() {
+            return Err(anyhow!("No matching query log entries in CloudWatch"));
+        }
+        Ok(entries)
+    }
+}
+
+/// Google Cloud Logging, e.g., for `graph-node` running on GKE. Tokens are
+/// obtained with application default credentials: a service account key in
+/// `GOOGLE_APPLICATION_CREDENTIALS`, the credentials from `gcloud auth
+/// application-default login`, or the metadata server
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct Gcp {
+    /// The project whose logs to search
+    project: String,
+    /// Only search this log, e.g., `stdout` for container logs on GKE
+    log_name: Option<String>,
+    /// An additional Logging query filter, e.g., to select the containers
+    /// that run `graph-node`
+    filter: Option<String>,
+    /// The Cloud Logging API endpoint
+    #[serde(default = "Gcp::default_url")]
+    url: String,
+    /// The pattern that extracts the fields from a query log line, with
+    /// the same syntax as a LogQL `pattern`
+    pattern: Option<String>,
+    /// The format of the log lines
+    #[serde(default)]
+    format: LogFormat,
+    /// The number of log entries to ask for with each request
+    #[serde(default = "Gcp::default_page_size")]
+    page_size: usize,
+    /// The maximum number of requests per second to send to the API
+    #[serde(default)]
+    rate_limit: RateLimit,
+    #[serde(flatten)]
+    http: Http,
+}
+
+impl Gcp {
+    const SCOPE: &'static str = "https://www.googleapis.com/auth/logging.read";
+
+    fn default_url() -> String {
+        "https://logging.googleapis.com".to_string()
+    }
+
+    /// The largest page size that the API allows
+    fn default_page_size() -> usize {
+        1000
+    }
+
+    /// The Logging query that finds candidates for log entries matching
+    /// `filter`. The filters on fields of the query log entry are applied
+    /// after the log lines have been taken apart
+    fn logging_filter(&self, filter: &QueryFilter) -> String {
+        let mut terms = Vec::new();
+        if let Some(log_name) = &self.log_name {
+            let log_name = format!("projects/{}/logs/{log_name}", self.project);
+            terms.push(format!("logName={}", logql_string(&log_name)));
+        }
+        if let Some(extra) = &self.filter {
+            terms.push(format!("({extra})"));
+        }
+        terms.push(format!("textPayload:{}", logql_string(QUERY_TIMING)));
+        terms.push(format!("textPayload:{}", logql_string(&filter.deployment)));
+        if let Some(qid) = &filter.qid {
+            terms.push(format!("textPayload:{}", logql_string(qid)));
+        }
+        if let Some(operation) = &filter.operation {
+            let regex = format!(r"\b(query|mutation|subscription)\s+{operation}\b");
+            terms.push(format!("textPayload=~{}", logql_string(&regex)));
+        }
+        if let Some(regex) = &filter.query_regex {
+            terms.push(format!("textPayload=~{}", logql_string(regex.as_str())));
+        }
+        if let Some(since) = filter.since {
+            terms.push(format!(r#"timestamp>="{}""#, since.to_rfc3339()));
+        }
+        if let Some(until) = filter.until {
+            terms.push(format!(r#"timestamp<="{}""#, until.to_rfc3339()));
+        }
+        terms.join(" AND ")
+    }
+}
+
+#[async_trait(?Send)]
+impl LogSource for Gcp {
+    fn name(&self) -> String {
+        "Google Cloud Logging".to_string()
+    }
+
+    async fn find(
+        &self,
+        filter: &QueryFilter,
+        limit: usize,
+        retry: &Retry,
+        out: &mut dyn std::io::Write,
+    ) -> anyhow::Result<Vec<LogEntry>> {
+        let url = Url::parse(&self.url)?.join("/v2/entries:list")?;
+        let token = gcp_auth::provider()
+            .await
+            .map_err(|e| anyhow!("Failed to find Google Cloud credentials: {}", e))?
+            .token(&[Self::SCOPE])
+            .await
+            .map_err(|e| anyhow!("Failed to get a Google Cloud access token: {}", e))?;
+        let parser = LineParser::new(self.pattern.as_deref(), self.format);
+        let client = self.http.client()?;
+        let logging_filter = self.logging_filter(filter);
+        let mut entry_parser = EntryParser::default();
+        let mut entries = Vec::new();
+        let mut page_token = None;
+        loop {
+            let mut body = json!({
+                "resourceNames": [format!("projects/{}", self.project)],
+                "filter": logging_filter,
+                "orderBy": "timestamp desc",
+                "pageSize": self.page_size,
+            });
+            if let Some(page_token) = page_token.take() {
+                body["pageToken"] = json::Value::String(page_token);
+            }
+            let body = body.to_string();
+            let resp = retry
+                .send(
+                    || {
+                        client
+                            .post(url.clone())
+                            .bearer_auth(token.as_str())
+                            .header("Content-Type", "application/json")
+                            .body(body.clone())
+                    },
+                    Some(&self.rate_limit),
+                    out,
+                )
+                .await
+                .map_err(|e| anyhow!("Failed to send Cloud Logging query: {}", e))?
+                .text()
+                .await
+                .map_err(|e| anyhow!("Failed to get Cloud Logging response: {}", e))?;
+            let resp: json::Value = json::from_str(&resp)
+                .map_err(|e| anyhow!("Failed to parse Cloud Logging response: {}", e))?;
+            if let Some(error) = resp.get("error") {
+                return Err(anyhow!("Cloud Logging query failed: {}", error["message"]));
+            }
+            // Cloud Logging leaves out `entries` if there are none
+            let page = resp["entries"].as_array().map(Vec::as_slice);
+            for entry in page.unwrap_or_default() {
+                let Some(fields) = entry["textPayload"]
+                    .as_str()
+                    .and_then(|line| parser.parse(line))
+                else {
+                    continue;
+                };
+                let timestamp = entry["timestamp"]
+                    .as_str()
+                    .and_then(|ts| DateTime::parse_from_rfc3339(ts).ok())
+                    .map(|ts| ts.with_timezone(&Utc));
+                let Some(entry) = entry_parser.entry(&fields, timestamp, out)? else {
+                    continue;
+                };
+                if filter.matches(&entry) && entries.len() < limit {
+                    entries.push(entry);
+                }
+            }
+            match resp["nextPageToken"].as_str() {
+                Some(next) if !next.is_empty() && entries.len() < limit => {
+                    page_token = Some(next.to_string());
+                    writeln!(out, "Fetched {} entries, asking for more", entries.len())?;
+                }
+                _ => break,
+            }
+        }
+        entry_parser.finish()?;
+        if entries.is_empty() {
+            writeln!(out, "Cloud Logging filter: {logging_filter}")?;
+            return Err(anyhow!("No matching query log entries in Cloud Logging"));
+        }
+        Ok(entries)
+    }
+}
+
+/// The logs of the `graph-node` pods in a Kubernetes cluster, read through
+/// the Kubernetes API. Only what the kubelet still keeps of the logs of the
+/// running pods is searched, which makes it possible to trace queries live
+/// in clusters that do not keep logs for long
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct Kubernetes {
+    /// The API server; defaults to the one for pods in the cluster
+    #[serde(default = "Kubernetes::default_url")]
+    url: String,
+    /// The namespace of the query node pods
+    namespace: String,
+    /// The label selector that picks out the query node pods
+    label_selector: String,
+    /// The container that runs `graph-node`, for pods with several
+    /// containers
+    container: Option<String>,
+    /// The bearer token to authenticate with. Without it, the token is read
+    /// from `token-file`
+    token: Option<String>,
+    /// The file with the bearer token; defaults to the token of the service
+    /// account for pods in the cluster
+    #[serde(default = "Kubernetes::default_token_file")]
+    token_file: String,
+    /// The pattern that extracts the fields from a query log line, with
+    /// the same syntax as a LogQL `pattern`
+    pattern: Option<String>,
+    /// The format of the log lines
+    #[serde(default)]
+    format: LogFormat,
+    /// The maximum number of requests per second to send to the API server
+    #[serde(default)]
+    rate_limit: RateLimit,
+    #[serde(flatten)]
+    http: Http,
+}
+
+impl Kubernetes {
+    fn default_url() -> String {
+        "https://kubernetes.default.svc".to_string()
+    }
+
+    fn default_token_file() -> String {
+        "/var/run/secrets/kubernetes.io/serviceaccount/token".to_string()
+    }
+
+    fn token(&self) -> anyhow::Result<String> {
+        if let Some(token) = &self.token {
+            return Ok(token.clone());
+        }
+        let file = &self.token_file;
+        let token = std::fs::read_to_string(file)
+            .map_err(|e| anyhow!("Failed to read Kubernetes token from {file}: {e}"))?;
+        Ok(token.trim().to_string())
+    }
+
+    /// Send a `GET` request for `path` with `params` to the API server and
+    /// return the body of the response
+    async fn get(
+        &self,
+        client: &reqwest::Client,
+        token: &str,
+        path: &str,
+        params: &[(&str, String)],
+        retry: &Retry,
+        out: &mut dyn std::io::Write,
+    ) -> anyhow::Result<String> {
+        let url = Url::parse(&self.url)?.join(path)?;
+        let resp = retry
+            .send(
+                || client.get(url.clone()).query(params).bearer_auth(token),
+                Some(&self.rate_limit),
+                out,
+            )
+            .await
+            .map_err(|e| anyhow!("Failed to send Kubernetes API request: {}", e))?;
+        let status = resp.status();
+        let body = resp
+            .text()
+            .await
+            .map_err(|e| anyhow!("Failed to get Kubernetes API response: {}", e))?;
+        if !status.is_success() {
+            // Errors come as a `Status` object with a readable message
+            let message = json::from_str::<json::Value>(&body)
+                .ok()
+                .and_then(|status| status["message"].as_str().map(str::to_string))
+                .unwrap_or(body);
+            return Err(anyhow!(
+                "Kubernetes API request for {path} failed with {status}: {message}"
+            ));
+        }
+        Ok(body)
+    }
+}
+
+#[async_trait(?Send)]
+impl LogSource for Kubernetes {
+    fn name(&self) -> String {
+        format!("the pods in namespace {}", self.namespace)
+    }
+
+    async fn find(
+        &self,
+        filter: &QueryFilter,
+        limit: usize,
+        retry: &Retry,
+        out: &mut dyn std::io::Write,
+    ) -> anyhow::Result<Vec<LogEntry>> {
+        let client = self.http.client()?;
+        let token = self.token()?;
+        let pods_path = format!("/api/v1/namespaces/{}/pods", self.namespace);
+        let params = [
+            ("labelSelector", self.label_selector.clone()),
+            ("fieldSelector", "status.phase=Running".to_string()),
+        ];
+        let pods = self
+            .get(&client, &token, &pods_path, &params, retry, out)
+            .await?;
+        let pods: json::Value = json::from_str(&pods)
+            .map_err(|e| anyhow!("Failed to parse Kubernetes pod list: {}", e))?;
+        let pods: Vec<_> = pods["items"]
+            .as_array()
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|pod| pod["metadata"]["name"].as_str())
+            .collect();
+        if pods.is_empty() {
+            return Err(anyhow!(
+                "No running pods match {} in namespace {}",
+                self.label_selector,
+                self.namespace
+            ));
+        }
+
+        let mut params = vec![("timestamps", "true".to_string())];
+        if let Some(container) = &self.container {
+            params.push(("container", container.clone()));
+        }
+        if let Some(since) = filter.since {
+            params.push(("sinceTime", since.to_rfc3339()));
+        }
+        let mut logs = Vec::new();
+        for pod in pods {
+            writeln!(out, "Reading the logs of pod {pod}")?;
+            let path = format!("{pods_path}/{pod}/log");
+            logs.push(
+                self.get(&client, &token, &path, &params, retry, out)
+                    .await?,
+            );
+        }
+        let logs: Vec<_> = logs.iter().map(String::as_str).collect();
+        let parser = LineParser::new(self.pattern.as_deref(), self.format);
+        let entries = parse_merged_logs(&logs, &parser, filter, limit, out)?;
+        if entries.is_empty() {
+            return Err(anyhow!(
+                "No matching query log entries in the logs of the pods in namespace {}",
+                self.namespace
+            ));
+        }
+        Ok(entries)
+    }
+}
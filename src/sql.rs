@@ -0,0 +1,174 @@
+//! Formatting of the SQL in traces. `graph-node` generates each statement
+//! as one long line, which is hard to read; this puts each clause on its
+//! own line, indents subqueries and uppercases keywords. Everything else,
+//! including identifiers, literals and spacing within a clause, is kept
+//! as it is
+
+/// Words that are uppercased
+const KEYWORDS: &[&str] = &[
+    "ALL", "AND", "ANY", "AS", "ASC", "BETWEEN", "BY", "CASE", "CROSS", "DESC", "DISTINCT", "ELSE",
+    "END", "EXISTS", "FROM", "FULL", "GROUP", "HAVING", "ILIKE", "IN", "INNER", "IS", "JOIN",
+    "LATERAL", "LEFT", "LIKE", "LIMIT", "NOT", "NULL", "NULLS", "OFFSET", "ON", "OR", "ORDER",
+    "OUTER", "RIGHT", "SELECT", "THEN", "UNION", "USING", "WHEN", "WHERE", "WITH",
+];
+
+/// Keywords that start a clause on a new line
+const CLAUSES: &[&str] = &[
+    "CROSS", "FROM", "FULL", "GROUP", "HAVING", "INNER", "JOIN", "LEFT", "LIMIT", "OFFSET",
+    "ORDER", "RIGHT", "SELECT", "UNION", "WHERE", "WITH",
+];
+
+/// Keywords that make a following `JOIN` part of the same clause
+const JOIN_KINDS: &[&str] = &[
+    "CROSS", "FULL", "INNER", "LEFT", "OUTER", "RIGHT", "LATERAL",
+];
+
+/// A piece of a statement and whether it was preceded by whitespace
+struct Token<'a> {
+    text: &'a str,
+    spaced: bool,
+}
+
+/// Split `sql` into words, quoted strings and identifiers, and single
+/// characters of punctuation
+fn tokenize(sql: &str) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    let mut chars = sql.char_indices().peekable();
+    let mut spaced = false;
+    while let Some((start, c)) = chars.next() {
+        if c.is_whitespace() {
+            spaced = true;
+            continue;
+        }
+        let mut end = start + c.len_utf8();
+        if c == '\'' || c == '"' {
+            // A doubled quote is an escaped quote inside the string
+            while let Some((i, next)) = chars.next() {
+                end = i + next.len_utf8();
+                if next == c {
+                    match chars.peek() {
+                        Some((_, after)) if *after == c => {
+                            chars.next();
+                        }
+                        _ => break,
+                    }
+                }
+            }
+        } else if c.is_alphanumeric() || c == '_' || c == '$' {
+            while let Some((i, next)) = chars.peek() {
+                if !(next.is_alphanumeric() || *next == '_' || *next == '$') {
+                    break;
+                }
+                end = i + next.len_utf8();
+                chars.next();
+            }
+        }
+        tokens.push(Token {
+            text: &sql[start..end],
+            spaced,
+        });
+        spaced = false;
+    }
+    tokens
+}
+
+/// Format `sql` so that it can be read
+pub fn format(sql: &str) -> String {
+    let tokens = tokenize(sql);
+    let mut out = String::new();
+    // The indentation of clauses, and of the current line
+    let mut level = 0;
+    let mut indent = 0;
+    // For each open parenthesis that starts a subquery, the indentation
+    // of clauses and of the line outside it
+    let mut parens: Vec<Option<(usize, usize)>> = Vec::new();
+    let mut between = false;
+    let mut previous = String::new();
+    for (i, token) in tokens.iter().enumerate() {
+        let upper = token.text.to_uppercase();
+        let keyword = KEYWORDS.contains(&upper.as_str()) && !previous.ends_with('.');
+        let text = if keyword { upper.as_str() } else { token.text };
+        // Clauses inside the arguments of a function, e.g., `extract(epoch
+        // from x)`, stay on the same line
+        let in_statement = parens.last().is_none_or(Option::is_some);
+        let newline = keyword
+            && in_statement
+            && match text {
+                "JOIN" | "LEFT" | "RIGHT" | "FULL" | "INNER" | "CROSS" => {
+                    !JOIN_KINDS.contains(&previous.as_str())
+                }
+                // The `AND` in `BETWEEN x AND y` does not start a condition
+                "AND" if between => false,
+                "AND" | "OR" => true,
+                clause => CLAUSES.contains(&clause),
+            };
+        match text {
+            "BETWEEN" => between = true,
+            "AND" => between = false,
+            _ => {}
+        }
+        match text {
+            ")" => {
+                if let Some(Some((outer, line))) = parens.pop() {
+                    level = outer;
+                    indent = line;
+                    newline_at(&mut out, indent);
+                } else if token.spaced {
+                    out.push(' ');
+                }
+                out.push(')');
+            }
+            _ if newline && !out.is_empty() => {
+                indent = match text {
+                    "AND" | "OR" => level + 1,
+                    _ => level,
+                };
+                newline_at(&mut out, indent);
+                out.push_str(text);
+            }
+            _ => {
+                if token.spaced && !out.is_empty() {
+                    out.push(' ');
+                }
+                out.push_str(text);
+            }
+        }
+        if text == "(" {
+            let subquery = tokens
+                .get(i + 1)
+                .is_some_and(|next| matches!(next.text.to_uppercase().as_str(), "SELECT" | "WITH"));
+            if subquery {
+                parens.push(Some((level, indent)));
+                level = indent + 1;
+            } else {
+                parens.push(None);
+            }
+        }
+        previous = text.to_string();
+    }
+    out
+}
+
+fn newline_at(out: &mut String, level: usize) {
+    out.push('\n');
+    out.push_str(&"  ".repeat(level));
+}
+
+/// The shape of `sql`, i.e., the statement with its literals and bind
+/// parameters replaced by `?`, and lists of them collapsed into one, so
+/// that statements that only differ in their values have the same shape
+pub(crate) fn shape(sql: &str) -> String {
+    let mut words: Vec<&str> = Vec::new();
+    for token in tokenize(sql) {
+        let literal = token.text.starts_with('\'')
+            || token.text.starts_with(|c: char| c.is_ascii_digit())
+            || (token.text.starts_with('$') && token.text[1..].chars().all(|c| c.is_ascii_digit()));
+        let word = if literal { "?" } else { token.text };
+        if word == "?" && words.ends_with(&["?", ","]) {
+            words.pop();
+            continue;
+        }
+        words.push(word);
+    }
+    words.join(" ").to_lowercase()
+}
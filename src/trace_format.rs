@@ -0,0 +1,167 @@
+//! The shapes of the traces that different releases of `graph-node`
+//! produce. Before a trace is parsed, it is converted into the shape that
+//! `Trace::parse` understands, which is the one that older releases use
+
+use std::borrow::Cow;
+
+use anyhow::anyhow;
+use serde_json as json;
+
+/// The shapes of traces that `qtrace` knows
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TraceShape {
+    /// The block and the fields are at the root of the trace, and the
+    /// fields below a field are in the field itself
+    Flat,
+    /// The root has a `blocks` list with a trace for each block that the
+    /// query was run against, together with its cache status. The fields
+    /// can be in a `children` object instead of the node itself
+    Blocks,
+}
+
+impl TraceShape {
+    fn detect(root: &json::Map<String, json::Value>) -> Option<Self> {
+        if root.get("blocks").is_some_and(json::Value::is_array) {
+            Some(Self::Blocks)
+        } else if root.get("block").is_some_and(json::Value::is_u64) {
+            Some(Self::Flat)
+        } else {
+            None
+        }
+    }
+}
+
+/// Convert `root` into the flat shape of traces. `version` is the version
+/// of `graph-node` that produced the trace if it is known, and is only used
+/// to say where an unrecognized trace came from
+pub(crate) fn normalize<'a>(
+    root: &'a json::Value,
+    version: Option<&str>,
+) -> anyhow::Result<Cow<'a, json::Value>> {
+    let object = root
+        .as_object()
+        .ok_or_else(|| anyhow!("Invalid trace: root is not an object"))?;
+    match TraceShape::detect(object) {
+        Some(TraceShape::Flat) if !has_children_objects(root) => Ok(Cow::Borrowed(root)),
+        Some(TraceShape::Flat) => Ok(Cow::Owned(flatten_children(root))),
+        Some(TraceShape::Blocks) => from_blocks(object).map(Cow::Owned),
+        None => {
+            let from = match version {
+                Some(version) => format!("graph-node {version}"),
+                None => "an unknown version of graph-node".to_string(),
+            };
+            let mut keys: Vec<_> = object.keys().map(String::as_str).collect();
+            keys.sort();
+            Err(anyhow!(
+                "Unrecognized trace format from {from}: expected `block` or `blocks` \
+                 at the root, but it has {}",
+                match keys.as_slice() {
+                    [] => "no keys".to_string(),
+                    keys => keys.join(", "),
+                }
+            ))
+        }
+    }
+}
+
+/// Whether `value` is an object that holds the fields below a node rather
+/// than a field that happens to be called `children`, which has times
+fn is_children_object(key: &str, value: &json::Value) -> bool {
+    key == "children" && value.is_object() && value.get("elapsed_ms").is_none()
+}
+
+/// Whether any node of `trace` keeps its fields in a `children` object
+fn has_children_objects(trace: &json::Value) -> bool {
+    trace.as_object().is_some_and(|node| {
+        node.iter().any(|(key, value)| {
+            is_children_object(key, value) || (value.is_object() && has_children_objects(value))
+        })
+    })
+}
+
+/// `node` with the fields in its `children` object, and in those of all
+/// fields below it, moved into the nodes themselves
+fn flatten_children(node: &json::Value) -> json::Value {
+    let Some(object) = node.as_object() else {
+        return node.clone();
+    };
+    let mut flat = json::Map::new();
+    for (key, value) in object {
+        match value {
+            json::Value::Object(children) if is_children_object(key, value) => {
+                for (name, child) in children {
+                    flat.insert(name.clone(), flatten_children(child));
+                }
+            }
+            json::Value::Object(_) => {
+                flat.insert(key.clone(), flatten_children(value));
+            }
+            _ => {
+                flat.insert(key.clone(), value.clone());
+            }
+        }
+    }
+    json::Value::Object(flat)
+}
+
+/// Turn a trace with a `blocks` list into a flat one. The fields of all
+/// blocks become fields of the root, which gets the first block and the
+/// cache status of the first block; the waits of all blocks are added up
+fn from_blocks(root: &json::Map<String, json::Value>) -> anyhow::Result<json::Value> {
+    let blocks = root["blocks"]
+        .as_array()
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+    let mut flat = json::Map::new();
+    for (key, value) in root {
+        if key != "blocks" && !value.is_object() {
+            flat.insert(key.clone(), value.clone());
+        }
+    }
+    let mut conn_wait = 0.0;
+    let mut permit_wait = 0.0;
+    for (i, block) in blocks.iter().enumerate() {
+        // The trace of a block is either wrapped together with its cache
+        // status or the entry itself
+        let trace = block.get("trace").unwrap_or(block);
+        let trace = flatten_children(trace);
+        let trace = trace
+            .as_object()
+            .ok_or_else(|| anyhow!("Invalid trace: block {i} is not an object"))?;
+        if i == 0 {
+            // The block is either its number or an object with its number
+            // and hash
+            let number = trace
+                .get("block")
+                .and_then(|block| block.get("number").unwrap_or(block).as_u64())
+                .ok_or_else(|| anyhow!("Invalid trace: block {i} has no block number"))?;
+            flat.insert("block".to_string(), number.into());
+            let cache = block
+                .get("cache")
+                .or_else(|| block.get("cache_status"))
+                .filter(|cache| cache.is_string());
+            if let Some(cache) = cache {
+                flat.insert("cache_status".to_string(), cache.clone());
+            }
+        }
+        conn_wait += trace
+            .get("conn_wait_ms")
+            .and_then(json::Value::as_f64)
+            .unwrap_or(0.0);
+        permit_wait += trace
+            .get("permit_wait_ms")
+            .and_then(json::Value::as_f64)
+            .unwrap_or(0.0);
+        for (key, value) in trace {
+            if value.is_object() && key != "block" {
+                flat.insert(key.clone(), value.clone());
+            }
+        }
+    }
+    if blocks.is_empty() {
+        return Err(anyhow!("Invalid trace: the list of blocks is empty"));
+    }
+    flat.entry("conn_wait_ms").or_insert(conn_wait.into());
+    flat.entry("permit_wait_ms").or_insert(permit_wait.into());
+    Ok(json::Value::Object(flat))
+}
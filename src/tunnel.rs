@@ -0,0 +1,113 @@
+//! Ways to reach a `graph-node` whose query port is not exposed, either
+//! through a unix socket or through a port forward like `kubectl
+//! port-forward`
+
+use std::process::{Child, Stdio};
+use std::time::{Duration, Instant};
+
+use anyhow::anyhow;
+use tokio::net::{TcpListener, TcpStream, UnixStream};
+use tokio::task::JoinHandle;
+use url::Url;
+
+use super::GraphNode;
+
+/// A connection to `graph-node` that is closed when this is dropped
+pub enum Tunnel {
+    /// Passes connections to a local port on to the socket
+    Socket(JoinHandle<()>),
+    /// The command that forwards the port
+    PortForward(Child),
+}
+
+impl Drop for Tunnel {
+    fn drop(&mut self) {
+        match self {
+            Tunnel::Socket(task) => task.abort(),
+            Tunnel::PortForward(child) => {
+                let _ = child.kill();
+                let _ = child.wait();
+            }
+        }
+    }
+}
+
+/// How long to wait for a port forward to accept connections
+const PORT_FORWARD_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Set up the socket or port forward for `graph_node` if it has one. For a
+/// socket, its url is changed to a local port that leads to the socket
+pub async fn open(
+    graph_node: &mut GraphNode,
+    out: &mut dyn std::io::Write,
+) -> anyhow::Result<Option<Tunnel>> {
+    match (&graph_node.socket, &graph_node.port_forward) {
+        (None, None) => Ok(None),
+        (Some(_), Some(_)) => Err(anyhow!(
+            "graph-node at {} can not use both a socket and a port forward",
+            graph_node.url
+        )),
+        (Some(socket), None) => {
+            let socket = socket.clone();
+            // Fail right away if nothing listens on the socket
+            UnixStream::connect(&socket)
+                .await
+                .map_err(|e| anyhow!("Failed to connect to {socket}: {e}"))?;
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            let port = listener.local_addr()?.port();
+            writeln!(out, "Sending requests for graph-node to {socket}")?;
+            let task = tokio::spawn(async move {
+                while let Ok((mut tcp, _)) = listener.accept().await {
+                    let socket = socket.clone();
+                    tokio::spawn(async move {
+                        if let Ok(mut unix) = UnixStream::connect(&socket).await {
+                            let _ = tokio::io::copy_bidirectional(&mut tcp, &mut unix).await;
+                        }
+                    });
+                }
+            });
+            graph_node.url = format!("http://127.0.0.1:{port}/");
+            Ok(Some(Tunnel::Socket(task)))
+        }
+        (None, Some(command)) => {
+            let url = Url::parse(&graph_node.url)
+                .map_err(|e| anyhow!("Invalid graph-node url {}: {e}", graph_node.url))?;
+            let host = url.host_str().unwrap_or("localhost").to_string();
+            let port = url.port_or_known_default().unwrap_or(80);
+            let (program, args) = command
+                .split_first()
+                .ok_or_else(|| anyhow!("The port-forward command is empty"))?;
+            writeln!(out, "Starting {}", command.join(" "))?;
+            let mut child = std::process::Command::new(program)
+                .args(args)
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .stderr(Stdio::piped())
+                .spawn()
+                .map_err(|e| anyhow!("Failed to run {program}: {e}"))?;
+            let deadline = Instant::now() + PORT_FORWARD_TIMEOUT;
+            while TcpStream::connect((host.as_str(), port)).await.is_err() {
+                if let Some(status) = child.try_wait()? {
+                    let mut stderr = String::new();
+                    if let Some(mut pipe) = child.stderr.take() {
+                        let _ = std::io::Read::read_to_string(&mut pipe, &mut stderr);
+                    }
+                    return Err(match stderr.trim() {
+                        "" => anyhow!("{program} exited with {status}"),
+                        stderr => anyhow!("{program} exited with {status}: {stderr}"),
+                    });
+                }
+                if Instant::now() > deadline {
+                    let _ = child.kill();
+                    return Err(anyhow!(
+                        "{program} did not forward {host}:{port} within {}s",
+                        PORT_FORWARD_TIMEOUT.as_secs()
+                    ));
+                }
+                tokio::time::sleep(Duration::from_millis(200)).await;
+            }
+            writeln!(out, "graph-node is reachable at {host}:{port}")?;
+            Ok(Some(Tunnel::PortForward(child)))
+        }
+    }
+}